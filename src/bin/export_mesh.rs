@@ -0,0 +1,63 @@
+//! Generates the terrain around a point without opening a window and exports
+//! its meshes.
+//!
+//! Usage: `export_mesh <x> <z> <radius in chunks> <output.glb|output.obj>`
+
+use std::{env, path::PathBuf, process::ExitCode};
+
+use bevy::prelude::Vec3;
+use mc_clone::{
+    plugins::terrain::{
+        block_names::{BlockNames, BLOCK_NAMES_PATH},
+        mesh_export::{self, MergedMesh},
+        pipeline::{self, ChunkStatus, LoadedChunks},
+        region::{PersistenceSettings, PERSISTENCE_PATH},
+        terrain::ChunkGenerator,
+        worlds::OpenWorld,
+    },
+    utils::config::load_ron_or_default,
+};
+
+const USAGE: &str = "usage: export_mesh <x> <z> <radius in chunks> <output.glb|output.obj>";
+
+fn main() -> ExitCode {
+    let args = env::args().skip(1).collect::<Vec<_>>();
+    let parsed = match args.as_slice() {
+        [x, z, radius, output] => x
+            .parse::<f32>()
+            .ok()
+            .zip(z.parse::<f32>().ok())
+            .zip(radius.parse::<i32>().ok().filter(|radius| *radius >= 0))
+            .map(|((x, z), radius)| (Vec3::new(x, 0.0, z), radius, PathBuf::from(output))),
+        _ => None,
+    };
+    let Some((center, radius, output)) = parsed else {
+        eprintln!("{}", USAGE);
+        return ExitCode::from(2);
+    };
+
+    let block_names = load_ron_or_default::<BlockNames>(BLOCK_NAMES_PATH);
+    let world = OpenWorld::open(&load_ron_or_default::<PersistenceSettings>(
+        PERSISTENCE_PATH,
+    ));
+    let generator = ChunkGenerator::load(&block_names, &world);
+    let mut loaded_chunks = LoadedChunks::default();
+    for (x, z) in mesh_export::region_chunks(center, radius) {
+        loaded_chunks.request(x, z, ChunkStatus::Meshed);
+    }
+    pipeline::generate_blocking(&mut loaded_chunks, &generator);
+
+    let mesh = MergedMesh::from_region(&loaded_chunks, center, radius);
+    match mesh_export::export_mesh(&mesh, &output) {
+        Ok(paths) => {
+            for path in paths {
+                println!("{}", path.display());
+            }
+            ExitCode::SUCCESS
+        }
+        Err(e) => {
+            eprintln!("Failed to export {}: {}", output.display(), e);
+            ExitCode::FAILURE
+        }
+    }
+}
@@ -0,0 +1,74 @@
+//! Generates the terrain of a box without opening a window and exports its
+//! blocks as a MagicaVoxel model.
+//!
+//! Usage: `export_vox <x> <y> <z> <width> <height> <length> <output.vox>`,
+//! with `x`, `y` and `z` the box's lowest corner.
+
+use std::{env, path::PathBuf, process::ExitCode};
+
+use bevy::prelude::IVec3;
+use mc_clone::{
+    plugins::terrain::{
+        block_names::{BlockNames, BLOCK_NAMES_PATH},
+        pipeline::{self, ChunkStatus, LoadedChunks},
+        region::{PersistenceSettings, PERSISTENCE_PATH},
+        schematic::Schematic,
+        terrain::{ChunkGenerator, CHUNK_SIZE},
+        vox,
+        worlds::OpenWorld,
+    },
+    utils::config::load_ron_or_default,
+};
+
+const USAGE: &str = "usage: export_vox <x> <y> <z> <width> <height> <length> <output.vox>";
+
+fn main() -> ExitCode {
+    let args = env::args().skip(1).collect::<Vec<_>>();
+    let parsed = match args.as_slice() {
+        [x, y, z, width, height, length, output] => [x, y, z, width, height, length]
+            .map(|arg| arg.parse::<i32>().ok())
+            .into_iter()
+            .collect::<Option<Vec<_>>>()
+            .map(|numbers| {
+                (
+                    IVec3::new(numbers[0], numbers[1], numbers[2]),
+                    IVec3::new(numbers[3], numbers[4], numbers[5]),
+                    PathBuf::from(output),
+                )
+            })
+            .filter(|(_, size, _)| size.cmpgt(IVec3::ZERO).all()),
+        _ => None,
+    };
+    let Some((min, size, output)) = parsed else {
+        eprintln!("{}", USAGE);
+        return ExitCode::from(2);
+    };
+
+    let block_names = load_ron_or_default::<BlockNames>(BLOCK_NAMES_PATH);
+    let world = OpenWorld::open(&load_ron_or_default::<PersistenceSettings>(
+        PERSISTENCE_PATH,
+    ));
+    let generator = ChunkGenerator::load(&block_names, &world);
+    let chunk_size = CHUNK_SIZE as i32;
+    let max = min + size - IVec3::ONE;
+    let mut loaded_chunks = LoadedChunks::default();
+    for x in min.x.div_euclid(chunk_size)..=max.x.div_euclid(chunk_size) {
+        for z in min.z.div_euclid(chunk_size)..=max.z.div_euclid(chunk_size) {
+            loaded_chunks.request(x * chunk_size, z * chunk_size, ChunkStatus::Features);
+        }
+    }
+    pipeline::generate_blocking(&mut loaded_chunks, &generator);
+
+    match Schematic::capture(&loaded_chunks, min, size)
+        .and_then(|schematic| vox::export_vox(&schematic, &output))
+    {
+        Ok(()) => {
+            println!("{}", output.display());
+            ExitCode::SUCCESS
+        }
+        Err(e) => {
+            eprintln!("Failed to export {}: {}", output.display(), e);
+            ExitCode::FAILURE
+        }
+    }
+}
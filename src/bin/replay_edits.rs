@@ -0,0 +1,133 @@
+//! Replays journals of block edits onto a new world with the seed and preset
+//! of the journaled one, to reproduce what happened in its sessions.
+//!
+//! Usage: `replay_edits <world folder | edits folder | journal> <new world name>`.
+//! Every journal of a folder is replayed, oldest session first.
+
+use std::{env, io, path::Path, process::ExitCode};
+
+use bevy::prelude::IVec3;
+use mc_clone::{
+    plugins::terrain::{
+        block_names::{BlockNames, BLOCK_NAMES_PATH},
+        edits::{self, EditHistory, JournalEntry},
+        pipeline::{self, ChunkStatus, LoadedChunks},
+        region::{PersistenceSettings, PERSISTENCE_PATH},
+        terrain::{ChunkGenerator, CHUNK_SIZE},
+        worlds::{OpenWorld, Worlds},
+    },
+    utils::config::load_ron_or_default,
+};
+
+const USAGE: &str = "usage: replay_edits <world folder | edits folder | journal> <new world name>";
+
+fn main() -> ExitCode {
+    let args = env::args().skip(1).collect::<Vec<_>>();
+    let [source, name] = args.as_slice() else {
+        eprintln!("{}", USAGE);
+        return ExitCode::from(2);
+    };
+
+    let journals = match edits::journal_paths(Path::new(source)).and_then(|paths| {
+        paths
+            .into_iter()
+            .map(|path| Ok((edits::read_journal(&path)?, path)))
+            .collect::<io::Result<Vec<_>>>()
+    }) {
+        Ok(journals) if !journals.is_empty() => journals,
+        Ok(_) => {
+            eprintln!("{} has no journals", source);
+            return ExitCode::FAILURE;
+        }
+        Err(e) => {
+            eprintln!("Failed to read {}: {}", source, e);
+            return ExitCode::FAILURE;
+        }
+    };
+    let mut recorded_in = None;
+    for (entries, path) in &journals {
+        let Some(JournalEntry::World { seed, preset }) = entries.first() else {
+            eprintln!(
+                "{} doesn't start with the world it was recorded in",
+                path.display()
+            );
+            return ExitCode::FAILURE;
+        };
+        if recorded_in.get_or_insert((seed, preset)) != &(seed, preset) {
+            eprintln!(
+                "{} was recorded in a world with another seed or preset",
+                path.display()
+            );
+            return ExitCode::FAILURE;
+        }
+    }
+    let (seed, preset) = recorded_in.unwrap();
+
+    let settings = load_ron_or_default::<PersistenceSettings>(PERSISTENCE_PATH);
+    let worlds = Worlds::new(&settings.saves);
+    let created = match worlds.create(name, *seed, preset) {
+        Ok(created) => created,
+        Err(e) => {
+            eprintln!("Failed to create world {}: {}", name, e);
+            return ExitCode::FAILURE;
+        }
+    };
+    let world = OpenWorld::saved(worlds.path(&created.dir), created.level, &settings);
+    let Some(storage) = world.storage.0.clone() else {
+        eprintln!("Failed to open world {}", created.dir);
+        return ExitCode::FAILURE;
+    };
+    let block_names = load_ron_or_default::<BlockNames>(BLOCK_NAMES_PATH);
+    let generator = ChunkGenerator::load(&block_names, &world);
+
+    let chunk_size = CHUNK_SIZE as i32;
+    let mut loaded_chunks = LoadedChunks::default();
+    let mut replayed = 0;
+    let mut failed = 0;
+    for (entries, _) in &journals {
+        // Every session starts with an empty history, undo can't reach the
+        // edits of earlier sessions.
+        let mut history = EditHistory::new(usize::MAX);
+        for entry in entries {
+            // Undo and redo only touch chunks an earlier edit already generated.
+            if let JournalEntry::Edit(transaction) = entry {
+                for op in &transaction.ops {
+                    let pos = IVec3::from_array(op.pos);
+                    loaded_chunks.request(
+                        pos.x.div_euclid(chunk_size) * chunk_size,
+                        pos.z.div_euclid(chunk_size) * chunk_size,
+                        ChunkStatus::Features,
+                    );
+                }
+                pipeline::generate_blocking(&mut loaded_chunks, &generator);
+            }
+            if !history.replay(&mut loaded_chunks, entry) {
+                failed += 1;
+            }
+            replayed += 1;
+        }
+    }
+
+    let chunks = loaded_chunks
+        .chunks
+        .values()
+        .filter(|entry| entry.dirty)
+        .filter_map(|entry| entry.chunk.as_ref())
+        .map(|chunk| storage.encode_chunk(chunk))
+        .collect::<Vec<_>>();
+    if let Err(e) = storage.save(&chunks, &world.level) {
+        eprintln!("Failed to save world {}: {}", created.dir, e);
+        return ExitCode::FAILURE;
+    }
+    println!(
+        "Replayed {} entries of {} sessions into {}",
+        replayed,
+        journals.len(),
+        worlds.path(&created.dir).display()
+    );
+    if failed > 0 {
+        eprintln!("{} entries couldn't be applied", failed);
+        return ExitCode::FAILURE;
+    }
+    ExitCode::SUCCESS
+}
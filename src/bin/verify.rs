@@ -0,0 +1,56 @@
+//! Reads every saved chunk of a world and reports the ones that are corrupt,
+//! without changing anything.
+//!
+//! Usage: `verify [world folder]`, the world configured in the persistence
+//! settings by default.
+
+use std::{env, path::PathBuf, process::ExitCode};
+
+use mc_clone::{
+    plugins::terrain::{
+        region::{PersistenceSettings, RegionStorage, PERSISTENCE_PATH},
+        worlds::Worlds,
+    },
+    utils::config::load_ron_or_default,
+};
+
+const USAGE: &str = "usage: verify [world folder]";
+
+fn main() -> ExitCode {
+    let args = env::args().skip(1).collect::<Vec<_>>();
+    let world_dir = match args.as_slice() {
+        [] => {
+            let settings = load_ron_or_default::<PersistenceSettings>(PERSISTENCE_PATH);
+            Worlds::new(&settings.saves).path(&settings.world)
+        }
+        [world] => PathBuf::from(world),
+        _ => {
+            eprintln!("{}", USAGE);
+            return ExitCode::from(2);
+        }
+    };
+    if !world_dir.is_dir() {
+        eprintln!("{} is not a world", world_dir.display());
+        return ExitCode::FAILURE;
+    }
+
+    let problems =
+        RegionStorage::open_read_only(world_dir.clone()).and_then(|storage| storage.verify());
+    match problems {
+        Ok(problems) if problems.is_empty() => {
+            println!("{}: no problems found", world_dir.display());
+            ExitCode::SUCCESS
+        }
+        Ok(problems) => {
+            for problem in &problems {
+                println!("{}", problem);
+            }
+            println!("{} problems found", problems.len());
+            ExitCode::FAILURE
+        }
+        Err(e) => {
+            eprintln!("Failed to verify {}: {}", world_dir.display(), e);
+            ExitCode::FAILURE
+        }
+    }
+}
@@ -5,7 +5,11 @@ use bevy::{
 };
 use mc_clone::plugins::{
     camera::camera::{CameraHandlerPlugin, FlyCamera},
-    terrain::terrain::TerrainPlugin,
+    terrain::{
+        level::LevelMetadata,
+        terrain::{TerrainPlugin, RENDER_DISTANCE},
+        tickets::ChunkLoader,
+    },
 };
 
 fn main() {
@@ -13,7 +17,7 @@ fn main() {
         .add_systems(Startup, setup)
         .add_plugins(DefaultPlugins)
         .add_plugins(CameraHandlerPlugin)
-        .add_plugins(FrameTimeDiagnosticsPlugin::default())
+        .add_plugins(FrameTimeDiagnosticsPlugin)
         .add_systems(Update, log_fps)
         .add_systems(Startup, setup_window)
         .add_plugins(TerrainPlugin)
@@ -23,10 +27,8 @@ fn main() {
 fn log_fps(diagnostics: Res<DiagnosticsStore>) {
     diagnostics.iter().for_each(|d| {
         let value = d.value();
-        if value.is_some() {
-            if d.name.eq("fps") {
-                //dbg!(d.name.clone(), value);
-            }
+        if value.is_some() && d.name.eq("fps") {
+            //dbg!(d.name.clone(), value);
         }
     })
 }
@@ -37,11 +39,7 @@ fn setup_window(mut query: Query<&mut Window>) {
     })
 }
 
-fn setup(
-    mut commands: Commands,
-    mut meshes: ResMut<Assets<Mesh>>,
-    mut materials: ResMut<Assets<StandardMaterial>>,
-) {
+fn setup(mut commands: Commands, level: Res<LevelMetadata>) {
     // light
     commands.spawn(PointLightBundle {
         point_light: PointLight {
@@ -52,11 +50,17 @@ fn setup(
         transform: Transform::from_xyz(4.0, 8.0, 4.0),
         ..default()
     });
-    // camera
+    // camera, where it was when the world was last saved
+    let camera = FlyCamera {
+        yaw: level.camera.yaw,
+        pitch: level.camera.pitch,
+        ..default()
+    };
     commands
         .spawn((
             Camera3dBundle {
-                transform: Transform::from_xyz(-2.0, 120.0, 5.0).looking_at(Vec3::ZERO, Vec3::Y),
+                transform: Transform::from_translation(Vec3::from_array(level.camera.position))
+                    .with_rotation(camera.rotation()),
                 camera: Camera {
                     hdr: true,
                     ..default()
@@ -68,5 +72,10 @@ fn setup(
                 ..default()
             },
         ))
-        .insert(FlyCamera::default());
+        .insert((
+            camera,
+            ChunkLoader {
+                radius: RENDER_DISTANCE,
+            },
+        ));
 }
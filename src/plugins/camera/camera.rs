@@ -26,6 +26,13 @@ impl Default for FlyCamera {
     }
 }
 
+impl FlyCamera {
+    pub fn rotation(&self) -> Quat {
+        Quat::from_axis_angle(Vec3::Y, self.yaw.to_radians())
+            * Quat::from_axis_angle(-Vec3::X, self.pitch.to_radians())
+    }
+}
+
 pub struct CameraHandlerPlugin;
 
 impl Plugin for CameraHandlerPlugin {
@@ -68,6 +75,5 @@ fn handle_mouse_input(
     camera.yaw -= delta.x * camera.sensitivy * time.delta_seconds();
     camera.pitch += (delta.y * camera.sensitivy * time.delta_seconds()).clamp(-89.0, 89.9);
 
-    transform.rotation = Quat::from_axis_angle(Vec3::Y, camera.yaw.to_radians())
-        * Quat::from_axis_angle(-Vec3::X, camera.pitch.to_radians());
+    transform.rotation = camera.rotation();
 }
@@ -0,0 +1,344 @@
+use std::{
+    fs::File,
+    io::{self, Read, Seek, SeekFrom},
+    path::PathBuf,
+    sync::Arc,
+};
+
+use bevy::prelude::{warn, IVec3, Resource};
+use serde::Deserialize;
+
+use crate::utils::nbt::{self, invalid_data, Tag};
+
+use super::{
+    block_names::BlockNames,
+    terrain::{BlockType, ChunkGrid, CHUNK_SIZE},
+};
+
+pub const ANVIL_PATH: &str = "assets/terrain/anvil.ron";
+
+const SECTOR_SIZE: u64 = 4096;
+
+/// Chunks per side of a region file.
+const REGION_SIZE: i32 = 32;
+
+/// First data version that doesn't let packed block states span two longs (1.16).
+const NON_SPANNING_VERSION: i64 = 2527;
+
+#[derive(Clone, Debug, Deserialize)]
+pub struct AnvilSettings {
+    pub enabled: bool,
+    /// Folder of a Minecraft Java world, the one containing `level.dat`.
+    pub world: String,
+    /// Minecraft height that becomes the bottom of our world.
+    pub min_y: i32,
+}
+
+impl Default for AnvilSettings {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            world: "saves/world".to_string(),
+            min_y: 0,
+        }
+    }
+}
+
+/// An existing Minecraft world that chunks are read from instead of being
+/// generated. The world files are never written to.
+pub struct AnvilWorld {
+    region_dir: PathBuf,
+    min_y: i32,
+    names: BlockNames,
+}
+
+#[derive(Resource, Clone)]
+pub struct ImportedWorld(pub Option<Arc<AnvilWorld>>);
+
+impl ImportedWorld {
+    pub fn new(settings: AnvilSettings, names: &BlockNames) -> Self {
+        if !settings.enabled {
+            return Self(None);
+        }
+        let region_dir = PathBuf::from(&settings.world).join("region");
+        if !region_dir.is_dir() {
+            warn!("{} has no region folder", settings.world);
+            return Self(None);
+        }
+        Self(Some(Arc::new(AnvilWorld {
+            region_dir,
+            min_y: settings.min_y,
+            names: names.clone(),
+        })))
+    }
+}
+
+impl AnvilWorld {
+    /// Fills `grid` with the blocks of the Minecraft chunk at the same
+    /// position. Chunks missing from the world stay empty.
+    pub fn load_chunk(&self, grid: &mut ChunkGrid) {
+        let chunk_x = grid.origin.x.div_euclid(CHUNK_SIZE as i32);
+        let chunk_z = grid.origin.z.div_euclid(CHUNK_SIZE as i32);
+        let result = self
+            .read_chunk(chunk_x, chunk_z)
+            .and_then(|root| match root {
+                Some(root) => self.copy_sections(&root, grid),
+                None => Ok(()),
+            });
+        if let Err(e) = result {
+            warn!("Failed to import chunk {} {}: {}", chunk_x, chunk_z, e);
+        }
+    }
+
+    /// Reads the NBT of a chunk from its region file, `None` when the chunk
+    /// was never generated.
+    fn read_chunk(&self, chunk_x: i32, chunk_z: i32) -> io::Result<Option<Tag>> {
+        let region_x = chunk_x.div_euclid(REGION_SIZE);
+        let region_z = chunk_z.div_euclid(REGION_SIZE);
+        let path = self
+            .region_dir
+            .join(format!("r.{}.{}.mca", region_x, region_z));
+        let mut file = match File::open(&path) {
+            Ok(file) => file,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(None),
+            Err(e) => return Err(e),
+        };
+
+        let index = chunk_x.rem_euclid(REGION_SIZE) + chunk_z.rem_euclid(REGION_SIZE) * REGION_SIZE;
+        let mut location = [0u8; 4];
+        file.seek(SeekFrom::Start(index as u64 * 4))?;
+        file.read_exact(&mut location)?;
+        let sector = u32::from_be_bytes([0, location[0], location[1], location[2]]) as u64;
+        let sectors = location[3] as u64;
+        if sector == 0 || sectors == 0 {
+            return Ok(None);
+        }
+
+        let mut header = [0u8; 5];
+        file.seek(SeekFrom::Start(sector * SECTOR_SIZE))?;
+        file.read_exact(&mut header)?;
+        let length = u32::from_be_bytes([header[0], header[1], header[2], header[3]]) as u64;
+        if length == 0 || length > sectors * SECTOR_SIZE {
+            return Err(invalid_data(format!("chunk has invalid length {}", length)));
+        }
+        let compression = header[4];
+        if compression & 0x80 != 0 {
+            return Err(invalid_data(
+                "chunks stored in external files are not supported",
+            ));
+        }
+
+        let mut data = vec![0u8; length as usize - 1];
+        file.read_exact(&mut data)?;
+        let (_, root) = match compression {
+            // `nbt::read` recognizes gzip and zlib by their headers.
+            1 | 2 => nbt::read(&data)?,
+            3 => nbt::read_uncompressed(&data)?,
+            other => {
+                return Err(invalid_data(format!(
+                    "unsupported chunk compression {}",
+                    other
+                )))
+            }
+        };
+        Ok(Some(root))
+    }
+
+    fn copy_sections(&self, root: &Tag, grid: &mut ChunkGrid) -> io::Result<()> {
+        let data_version = root.get("DataVersion").and_then(Tag::as_i64).unwrap_or(0);
+        // Before 1.18 everything was wrapped in a `Level` compound.
+        let (sections, palette_key, data_key) = match root.get("sections") {
+            Some(sections) => (sections, "palette", "data"),
+            None => (
+                root.get("Level")
+                    .and_then(|level| level.get("Sections"))
+                    .ok_or_else(|| invalid_data("chunk has no sections"))?,
+                "Palette",
+                "BlockStates",
+            ),
+        };
+        let sections = sections.as_list().unwrap_or_default();
+
+        for section in sections {
+            let Some(section_y) = section.get("Y").and_then(Tag::as_i32) else {
+                continue;
+            };
+            // 1.18 moved the palette and data into `block_states`.
+            let states = section.get("block_states").unwrap_or(section);
+            // Chunks saved before 1.13 store numeric block ids instead of a
+            // palette.
+            if states.get("Blocks").is_some() {
+                return Err(io::Error::new(
+                    io::ErrorKind::Unsupported,
+                    "chunks saved before Minecraft 1.13 are not supported",
+                ));
+            }
+            let Some(palette) = states
+                .get(palette_key)
+                .and_then(Tag::as_list)
+                .filter(|palette| !palette.is_empty())
+            else {
+                continue;
+            };
+            let palette = palette
+                .iter()
+                .map(|state| {
+                    let name = state.get("Name").and_then(Tag::as_str).unwrap_or_default();
+                    self.names.lookup(name)
+                })
+                .collect::<Vec<_>>();
+            let data = states.get(data_key).and_then(Tag::as_long_array);
+            let indices = unpack_indices(
+                data.unwrap_or_default(),
+                palette.len(),
+                data_version >= NON_SPANNING_VERSION,
+            )?;
+
+            for (i, palette_index) in indices.into_iter().enumerate() {
+                let block = *palette
+                    .get(palette_index)
+                    .ok_or_else(|| invalid_data("block state is not in the palette"))?;
+                if block == BlockType::Air {
+                    continue;
+                }
+                let i = i as i32;
+                let pos = IVec3::new(
+                    grid.origin.x + (i & 15),
+                    section_y * 16 + (i >> 8) - self.min_y,
+                    grid.origin.z + ((i >> 4) & 15),
+                );
+                grid.set(pos, block);
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Palette index of each of the 4096 blocks of a section, in YZX order.
+fn unpack_indices(data: &[i64], palette_len: usize, non_spanning: bool) -> io::Result<Vec<usize>> {
+    const BLOCKS: usize = 4096;
+    if palette_len <= 1 || data.is_empty() {
+        return Ok(vec![0; BLOCKS]);
+    }
+
+    let bits = (usize::BITS - (palette_len - 1).leading_zeros()).max(4) as usize;
+    let mask = (1u64 << bits) - 1;
+    let mut indices = Vec::with_capacity(BLOCKS);
+
+    if non_spanning {
+        let per_long = 64 / bits;
+        if data.len() < BLOCKS.div_ceil(per_long) {
+            return Err(invalid_data("section block data is too short"));
+        }
+        for i in 0..BLOCKS {
+            let long = data[i / per_long] as u64;
+            indices.push(((long >> ((i % per_long) * bits)) & mask) as usize);
+        }
+    } else {
+        if data.len() * 64 < BLOCKS * bits {
+            return Err(invalid_data("section block data is too short"));
+        }
+        for i in 0..BLOCKS {
+            let bit = i * bits;
+            let (long, offset) = (bit / 64, bit % 64);
+            let mut value = (data[long] as u64) >> offset;
+            if offset + bits > 64 {
+                value |= (data[long + 1] as u64) << (64 - offset);
+            }
+            indices.push((value & mask) as usize);
+        }
+    }
+    Ok(indices)
+}
+
+#[cfg(test)]
+mod tests {
+    use bevy::utils::HashMap;
+
+    use super::*;
+
+    fn compound<const N: usize>(children: [(&str, Tag); N]) -> Tag {
+        Tag::Compound(HashMap::from_iter(
+            children.map(|(key, tag)| (key.to_string(), tag)),
+        ))
+    }
+
+    fn world(min_y: i32) -> AnvilWorld {
+        AnvilWorld {
+            region_dir: PathBuf::new(),
+            min_y,
+            names: BlockNames::default(),
+        }
+    }
+
+    /// Five bit indices where the top four bits of the first long and the
+    /// lowest bit of the second are set: the 13th index spans both longs
+    /// before 1.16 and starts the second long since.
+    #[test]
+    fn unpacks_spanning_and_non_spanning_indices() {
+        let mut data = vec![0i64; 4096usize.div_ceil(12)];
+        data[0] = (0xf_u64 << 60) as i64;
+        data[1] = 17;
+
+        let spanning = unpack_indices(&data, 32, false).unwrap();
+        assert_eq!(spanning[12], 0b11111);
+        assert!(spanning[..12].iter().all(|index| *index == 0));
+
+        let non_spanning = unpack_indices(&data, 32, true).unwrap();
+        assert_eq!(non_spanning[12], 17);
+        assert!(non_spanning[..12].iter().all(|index| *index == 0));
+        assert!(non_spanning[13..].iter().all(|index| *index == 0));
+
+        assert!(unpack_indices(&data[..300], 32, true).is_err());
+        assert!(unpack_indices(&data[..300], 32, false).is_err());
+        assert_eq!(unpack_indices(&[], 1, true).unwrap(), vec![0; 4096]);
+    }
+
+    #[test]
+    fn copies_sections_to_the_grid() {
+        // Four bit indices, 16 per long: the block at 1, 2, 3 of the section
+        // is index 2 * 256 + 3 * 16 + 1 = 561, in the second nibble of long 35.
+        let mut data = vec![0i64; 256];
+        data[35] = 1 << 4;
+        let section = compound([
+            ("Y", Tag::Byte(-4)),
+            (
+                "block_states",
+                compound([
+                    (
+                        "palette",
+                        Tag::List(vec![
+                            compound([("Name", Tag::String("minecraft:air".to_string()))]),
+                            compound([("Name", Tag::String("minecraft:stone".to_string()))]),
+                        ]),
+                    ),
+                    ("data", Tag::LongArray(data)),
+                ]),
+            ),
+        ]);
+        let root = compound([
+            ("DataVersion", Tag::Int(3465)),
+            ("sections", Tag::List(vec![section])),
+        ]);
+
+        let origin = IVec3::new(32, 0, -16);
+        let mut grid = ChunkGrid::new(origin, IVec3::new(16, 16, 16));
+        world(-64).copy_sections(&root, &mut grid).unwrap();
+        assert_eq!(grid.get(origin + IVec3::new(1, 2, 3)), BlockType::Stone);
+        assert_eq!(grid.get(origin + IVec3::new(3, 2, 1)), BlockType::Air);
+    }
+
+    #[test]
+    fn rejects_chunks_without_palettes() {
+        let section = compound([
+            ("Y", Tag::Byte(0)),
+            ("Blocks", Tag::ByteArray(vec![1; 4096])),
+            ("Data", Tag::ByteArray(vec![0; 2048])),
+        ]);
+        let root = compound([("Level", compound([("Sections", Tag::List(vec![section]))]))]);
+
+        let mut grid = ChunkGrid::new(IVec3::ZERO, IVec3::new(16, 16, 16));
+        let error = world(0).copy_sections(&root, &mut grid).unwrap_err();
+        assert_eq!(error.kind(), io::ErrorKind::Unsupported);
+    }
+}
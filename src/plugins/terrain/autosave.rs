@@ -0,0 +1,198 @@
+use std::{io, sync::Arc};
+
+use bevy::{
+    app::AppExit,
+    prelude::*,
+    tasks::{IoTaskPool, Task},
+};
+use futures_lite::future;
+
+use crate::plugins::camera::camera::FlyCamera;
+
+use super::{
+    level::{LevelMetadata, TimeOfDay},
+    pipeline::{ChunkStatus, LoadedChunks},
+    region::{RegionStorage, SavedChunk, WorldStorage},
+};
+
+/// Chunk being saved, with the entry and change count it was encoded from.
+struct SavingChunk {
+    id: String,
+    generation: u64,
+    changes: u64,
+}
+
+/// Save running in the background, with the chunks it is saving so they can
+/// be marked clean once it succeeds.
+struct RunningSave {
+    task: Task<io::Result<()>>,
+    chunks: Vec<SavingChunk>,
+}
+
+#[derive(Resource)]
+pub struct Autosave {
+    timer: Timer,
+    running: Option<RunningSave>,
+}
+
+impl Autosave {
+    pub fn new(interval: f32) -> Self {
+        Self {
+            timer: Timer::from_seconds(interval.max(1.0), TimerMode::Repeating),
+            running: None,
+        }
+    }
+}
+
+/// Encodes the dirty chunks. They stay dirty, and so loaded, until
+/// [`finish_save`] sees that the save succeeded.
+fn encode_dirty_chunks(
+    loaded_chunks: &LoadedChunks,
+    storage: &RegionStorage,
+) -> (Vec<SavedChunk>, Vec<SavingChunk>) {
+    let mut chunks = Vec::new();
+    let mut saving = Vec::new();
+    for (id, entry) in loaded_chunks.chunks.iter() {
+        if !entry.dirty || entry.in_flight || entry.status < ChunkStatus::Features {
+            continue;
+        }
+        let Some(chunk) = &entry.chunk else {
+            continue;
+        };
+        chunks.push(storage.encode_chunk(chunk));
+        saving.push(SavingChunk {
+            id: id.clone(),
+            generation: entry.generation,
+            changes: entry.changes,
+        });
+    }
+    (chunks, saving)
+}
+
+/// Marks the saved chunks clean, unless they changed again while the save
+/// was running. After a failed save they stay dirty and are saved again.
+fn finish_save(
+    result: io::Result<()>,
+    chunks: Vec<SavingChunk>,
+    loaded_chunks: &mut LoadedChunks,
+) -> bool {
+    if let Err(e) = result {
+        warn!("Failed to save the world: {}", e);
+        return false;
+    }
+    for saved in chunks {
+        if let Some(entry) = loaded_chunks.chunks.get_mut(&saved.id) {
+            if entry.generation == saved.generation && entry.changes == saved.changes {
+                entry.dirty = false;
+            }
+        }
+    }
+    true
+}
+
+/// Saves the dirty chunks and the world's metadata in the background every
+/// [`PersistenceSettings::autosave_interval`](super::region::PersistenceSettings)
+/// seconds.
+pub fn autosave(
+    time: Res<Time>,
+    mut autosave: ResMut<Autosave>,
+    storage: Res<WorldStorage>,
+    mut loaded_chunks: ResMut<LoadedChunks>,
+    camera: Query<(&Transform, &FlyCamera)>,
+    time_of_day: Res<TimeOfDay>,
+    level: Res<LevelMetadata>,
+) {
+    let Some(storage) = &storage.0 else {
+        return;
+    };
+    if let Some(running) = &mut autosave.running {
+        let Some(result) = future::block_on(future::poll_once(&mut running.task)) else {
+            return;
+        };
+        let running = autosave.running.take().unwrap();
+        finish_save(result, running.chunks, &mut loaded_chunks);
+    }
+    if !autosave.timer.tick(time.delta()).just_finished() {
+        return;
+    }
+
+    let (chunks, saving) = encode_dirty_chunks(&loaded_chunks, storage);
+    let level = level.capture(camera.get_single().ok(), *time_of_day);
+    let storage = Arc::clone(storage);
+    let task = IoTaskPool::get().spawn(async move { storage.save(&chunks, &level) });
+    autosave.running = Some(RunningSave {
+        task,
+        chunks: saving,
+    });
+}
+
+/// Saves everything once the app is about to exit, after waiting for the
+/// autosave that may still be running.
+pub fn save_on_exit(
+    mut exit: EventReader<AppExit>,
+    mut autosave: ResMut<Autosave>,
+    storage: Res<WorldStorage>,
+    mut loaded_chunks: ResMut<LoadedChunks>,
+    camera: Query<(&Transform, &FlyCamera)>,
+    time_of_day: Res<TimeOfDay>,
+    level: Res<LevelMetadata>,
+) {
+    if exit.iter().last().is_none() {
+        return;
+    }
+    let Some(storage) = &storage.0 else {
+        return;
+    };
+    if let Some(running) = autosave.running.take() {
+        let result = future::block_on(running.task);
+        finish_save(result, running.chunks, &mut loaded_chunks);
+    }
+
+    let (chunks, saving) = encode_dirty_chunks(&loaded_chunks, storage);
+    let level = level.capture(camera.get_single().ok(), *time_of_day);
+    if finish_save(storage.save(&chunks, &level), saving, &mut loaded_chunks) {
+        info!("Saved {} chunks", chunks.len());
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::plugins::terrain::pipeline::chunk_id;
+
+    fn saving(loaded_chunks: &LoadedChunks, x: i32, z: i32) -> SavingChunk {
+        let entry = &loaded_chunks.chunks[&chunk_id(x, z)];
+        SavingChunk {
+            id: chunk_id(x, z),
+            generation: entry.generation,
+            changes: entry.changes,
+        }
+    }
+
+    #[test]
+    fn keeps_chunks_dirty_until_saved() {
+        let mut loaded_chunks = LoadedChunks::default();
+        for x in [0, 16] {
+            loaded_chunks.request(x, 0, ChunkStatus::Features);
+            let entry = loaded_chunks.chunks.get_mut(&chunk_id(x, 0)).unwrap();
+            entry.dirty = true;
+            entry.changes = 1;
+        }
+
+        let chunks = vec![saving(&loaded_chunks, 0, 0), saving(&loaded_chunks, 16, 0)];
+        let failed = Err(io::Error::other("disk full"));
+        assert!(!finish_save(failed, chunks, &mut loaded_chunks));
+        assert!(loaded_chunks.chunks.values().all(|entry| entry.dirty));
+
+        let chunks = vec![saving(&loaded_chunks, 0, 0), saving(&loaded_chunks, 16, 0)];
+        // Edited while the save was running.
+        loaded_chunks
+            .chunks
+            .get_mut(&chunk_id(16, 0))
+            .unwrap()
+            .changes += 1;
+        assert!(finish_save(Ok(()), chunks, &mut loaded_chunks));
+        assert!(!loaded_chunks.chunks[&chunk_id(0, 0)].dirty);
+        assert!(loaded_chunks.chunks[&chunk_id(16, 0)].dirty);
+    }
+}
@@ -0,0 +1,86 @@
+use std::collections::HashMap;
+
+use bevy::prelude::Resource;
+use serde::Deserialize;
+
+use super::terrain::BlockType;
+
+pub const BLOCK_NAMES_PATH: &str = "assets/terrain/block_names.ron";
+
+/// Maps Minecraft block names to our blocks, for importing builds and maps.
+/// Loaded from [`BLOCK_NAMES_PATH`], falling back to the built-in names.
+#[derive(Resource, Clone, Debug, Deserialize)]
+pub struct BlockNames {
+    /// Full names like `minecraft:stone`.
+    pub names: HashMap<String, BlockType>,
+    /// Used for every name that isn't listed.
+    pub fallback: BlockType,
+}
+
+impl Default for BlockNames {
+    fn default() -> Self {
+        let names = [
+            ("air", BlockType::Air),
+            ("cave_air", BlockType::Air),
+            ("void_air", BlockType::Air),
+            ("grass_block", BlockType::Grass),
+            ("dirt", BlockType::Dirt),
+            ("coarse_dirt", BlockType::Dirt),
+            ("stone", BlockType::Stone),
+            ("andesite", BlockType::Stone),
+            ("diorite", BlockType::Stone),
+            ("granite", BlockType::Stone),
+            ("deepslate", BlockType::Deepslate),
+            ("coal_ore", BlockType::Coal),
+            ("iron_ore", BlockType::Iron),
+            ("gold_ore", BlockType::Gold),
+            ("bedrock", BlockType::Bedrock),
+            ("cobblestone", BlockType::Cobblestone),
+            ("mossy_cobblestone", BlockType::Cobblestone),
+            ("stone_bricks", BlockType::Cobblestone),
+            ("oak_log", BlockType::Log),
+            ("oak_leaves", BlockType::Leaves),
+            ("birch_log", BlockType::BirchLog),
+            ("birch_leaves", BlockType::BirchLeaves),
+            ("spruce_log", BlockType::SpruceLog),
+            ("spruce_leaves", BlockType::SpruceLeaves),
+            ("jungle_log", BlockType::JungleLog),
+            ("jungle_leaves", BlockType::JungleLeaves),
+            ("oak_planks", BlockType::Planks),
+            ("birch_planks", BlockType::Planks),
+            ("spruce_planks", BlockType::Planks),
+            ("jungle_planks", BlockType::Planks),
+            ("poppy", BlockType::Flower),
+            ("dandelion", BlockType::Flower),
+            ("grass", BlockType::TallGrass),
+            ("short_grass", BlockType::TallGrass),
+            ("tall_grass", BlockType::TallGrass),
+            ("vine", BlockType::Vines),
+            ("snow", BlockType::Snow),
+            ("snow_block", BlockType::Snow),
+            ("gravel", BlockType::Gravel),
+            ("sand", BlockType::Sand),
+            ("water", BlockType::Water),
+        ];
+        Self {
+            names: names
+                .into_iter()
+                .map(|(name, block)| (format!("minecraft:{}", name), block))
+                .collect(),
+            fallback: BlockType::Stone,
+        }
+    }
+}
+
+impl BlockNames {
+    /// Block for a name like `minecraft:oak_log[axis=y]`. Block states are ignored.
+    pub fn lookup(&self, name: &str) -> BlockType {
+        let name = name.split('[').next().unwrap_or(name);
+        let block = if name.contains(':') {
+            self.names.get(name)
+        } else {
+            self.names.get(&format!("minecraft:{}", name))
+        };
+        block.copied().unwrap_or(self.fallback)
+    }
+}
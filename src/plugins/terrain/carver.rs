@@ -0,0 +1,276 @@
+use std::f32::consts::PI;
+
+use bevy::prelude::{IVec3, Resource, Vec3};
+use noise::{NoiseFn, Perlin};
+use serde::Deserialize;
+
+use crate::utils::random::{chunk_seed, ChunkRng};
+
+use super::terrain::{BlockType, ChunkGrid, CHUNK_SIZE};
+
+pub const CARVER_PATH: &str = "assets/terrain/carver.ron";
+
+const CHEESE_SALT: u32 = 1;
+const WORM_SALT: u32 = 2;
+const RAVINE_SALT: u32 = 3;
+
+#[derive(Resource, Clone, Deserialize)]
+pub struct CarverSettings {
+    /// Combined with the world's seed by [`stage_seed`](crate::utils::random::stage_seed).
+    pub seed: u32,
+    /// Blocks closer than this to the surface are never carved, so caves don't
+    /// punch holes into the terrain above them.
+    pub surface_depth: i32,
+    /// Lowest layer a carver is allowed to remove.
+    pub min_height: i32,
+    pub cheese_frequency: f64,
+    /// Noise value above which a block becomes part of a cheese cave.
+    pub cheese_threshold: f64,
+    /// Probability that a chunk is the origin of a worm tunnel.
+    pub worm_chance: f32,
+    pub worm_length: u32,
+    pub worm_radius: f32,
+    pub worm_max_start_height: i32,
+    /// Probability that a chunk is the origin of a ravine.
+    pub ravine_chance: f32,
+    pub ravine_length: u32,
+    pub ravine_width: f32,
+    pub ravine_height: f32,
+}
+
+impl Default for CarverSettings {
+    fn default() -> Self {
+        Self {
+            seed: 10,
+            surface_depth: 6,
+            min_height: 1,
+            cheese_frequency: 0.04,
+            cheese_threshold: 0.55,
+            worm_chance: 0.15,
+            worm_length: 80,
+            worm_radius: 2.5,
+            worm_max_start_height: 50,
+            ravine_chance: 0.02,
+            ravine_length: 60,
+            ravine_width: 2.5,
+            ravine_height: 12.0,
+        }
+    }
+}
+
+impl CarverSettings {
+    /// How many chunks away a tunnel can start and still reach into a chunk.
+    fn reach_in_chunks(&self) -> i32 {
+        let reach = (self.worm_length as f32 + self.worm_radius)
+            .max(self.ravine_length as f32 + self.ravine_width);
+        (reach / CHUNK_SIZE as f32).ceil() as i32
+    }
+}
+
+/// Removes blocks from `grid` with cheese caves, worm tunnels and ravines.
+/// `surface` returns the terrain height of a column and is used to keep the
+/// top `surface_depth` blocks intact.
+pub fn carve(grid: &mut ChunkGrid, settings: &CarverSettings, surface: impl Fn(i32, i32) -> i32) {
+    let heights = ColumnHeights::new(grid, &surface);
+
+    carve_cheese(grid, settings, &heights);
+
+    let min_chunk_x = grid.origin.x.div_euclid(CHUNK_SIZE as i32);
+    let max_chunk_x = (grid.origin.x + grid.size.x - 1).div_euclid(CHUNK_SIZE as i32);
+    let min_chunk_z = grid.origin.z.div_euclid(CHUNK_SIZE as i32);
+    let max_chunk_z = (grid.origin.z + grid.size.z - 1).div_euclid(CHUNK_SIZE as i32);
+    let reach = settings.reach_in_chunks();
+
+    // Tunnels are seeded by the chunk they start in, so every chunk they pass
+    // through traces the exact same path no matter which one is loaded first.
+    for chunk_x in (min_chunk_x - reach)..=(max_chunk_x + reach) {
+        for chunk_z in (min_chunk_z - reach)..=(max_chunk_z + reach) {
+            carve_worm(grid, settings, &heights, chunk_x, chunk_z);
+            carve_ravine(grid, settings, &heights, chunk_x, chunk_z);
+        }
+    }
+}
+
+struct ColumnHeights {
+    origin: IVec3,
+    size: IVec3,
+    heights: Vec<i32>,
+}
+
+impl ColumnHeights {
+    fn new(grid: &ChunkGrid, surface: &impl Fn(i32, i32) -> i32) -> Self {
+        let mut heights = Vec::with_capacity((grid.size.x * grid.size.z) as usize);
+        for x in 0..grid.size.x {
+            for z in 0..grid.size.z {
+                heights.push(surface(grid.origin.x + x, grid.origin.z + z));
+            }
+        }
+        Self {
+            origin: grid.origin,
+            size: grid.size,
+            heights,
+        }
+    }
+
+    fn get(&self, x: i32, z: i32) -> i32 {
+        let local_x = x - self.origin.x;
+        let local_z = z - self.origin.z;
+        self.heights[(local_x * self.size.z + local_z) as usize]
+    }
+}
+
+fn can_carve(
+    grid: &ChunkGrid,
+    pos: IVec3,
+    settings: &CarverSettings,
+    heights: &ColumnHeights,
+) -> bool {
+    pos.y >= settings.min_height
+        && pos.y < heights.get(pos.x, pos.z) - settings.surface_depth
+        && grid.get(pos) != BlockType::Bedrock
+}
+
+fn carve_cheese(grid: &mut ChunkGrid, settings: &CarverSettings, heights: &ColumnHeights) {
+    let perlin = Perlin::new(settings.seed.wrapping_add(CHEESE_SALT));
+
+    for x in grid.origin.x..grid.origin.x + grid.size.x {
+        for z in grid.origin.z..grid.origin.z + grid.size.z {
+            let top = heights.get(x, z) - settings.surface_depth;
+            for y in settings.min_height..top.min(grid.origin.y + grid.size.y) {
+                let value = perlin.get([
+                    x as f64 * settings.cheese_frequency,
+                    // Squash vertically so caves are wider than they are tall.
+                    y as f64 * settings.cheese_frequency * 2.0,
+                    z as f64 * settings.cheese_frequency,
+                ]);
+                let pos = IVec3::new(x, y, z);
+                if value > settings.cheese_threshold && grid.get(pos) != BlockType::Bedrock {
+                    grid.set(pos, BlockType::Air);
+                }
+            }
+        }
+    }
+}
+
+fn carve_worm(
+    grid: &mut ChunkGrid,
+    settings: &CarverSettings,
+    heights: &ColumnHeights,
+    chunk_x: i32,
+    chunk_z: i32,
+) {
+    let mut rng = ChunkRng::new(chunk_seed(settings.seed, WORM_SALT, chunk_x, chunk_z));
+    if !rng.chance(settings.worm_chance) {
+        return;
+    }
+
+    let mut position = Vec3::new(
+        (chunk_x * CHUNK_SIZE as i32) as f32 + rng.range_f32(0.0, CHUNK_SIZE as f32),
+        rng.range_f32(
+            settings.min_height as f32 + settings.worm_radius,
+            settings.worm_max_start_height as f32,
+        ),
+        (chunk_z * CHUNK_SIZE as i32) as f32 + rng.range_f32(0.0, CHUNK_SIZE as f32),
+    );
+    let mut yaw = rng.range_f32(0.0, 2.0 * PI);
+    let mut pitch = rng.range_f32(-0.3, 0.3);
+    let mut yaw_change = 0.0;
+    let mut pitch_change = 0.0;
+
+    for step in 0..settings.worm_length {
+        let progress = step as f32 / settings.worm_length as f32;
+        // Taper both ends so tunnels don't start or stop with a flat wall.
+        let radius = settings.worm_radius * (0.5 + 0.5 * (progress * PI).sin());
+        carve_ellipsoid(grid, settings, heights, position, radius, radius);
+
+        position += Vec3::new(
+            yaw.cos() * pitch.cos(),
+            pitch.sin(),
+            yaw.sin() * pitch.cos(),
+        );
+        yaw += yaw_change * 0.1;
+        pitch = (pitch + pitch_change * 0.1).clamp(-0.6, 0.6);
+        yaw_change = yaw_change * 0.75 + rng.range_f32(-2.0, 2.0);
+        pitch_change = pitch_change * 0.75 + rng.range_f32(-1.0, 1.0);
+    }
+}
+
+fn carve_ravine(
+    grid: &mut ChunkGrid,
+    settings: &CarverSettings,
+    heights: &ColumnHeights,
+    chunk_x: i32,
+    chunk_z: i32,
+) {
+    let mut rng = ChunkRng::new(chunk_seed(settings.seed, RAVINE_SALT, chunk_x, chunk_z));
+    if !rng.chance(settings.ravine_chance) {
+        return;
+    }
+
+    let mut position = Vec3::new(
+        (chunk_x * CHUNK_SIZE as i32) as f32 + rng.range_f32(0.0, CHUNK_SIZE as f32),
+        rng.range_f32(
+            settings.min_height as f32 + settings.ravine_height,
+            settings.worm_max_start_height as f32,
+        ),
+        (chunk_z * CHUNK_SIZE as i32) as f32 + rng.range_f32(0.0, CHUNK_SIZE as f32),
+    );
+    let mut yaw = rng.range_f32(0.0, 2.0 * PI);
+    let pitch = rng.range_f32(-0.1, 0.1);
+
+    for step in 0..settings.ravine_length {
+        let progress = step as f32 / settings.ravine_length as f32;
+        let taper = (progress * PI).sin();
+        carve_ellipsoid(
+            grid,
+            settings,
+            heights,
+            position,
+            settings.ravine_width * (0.3 + 0.7 * taper),
+            settings.ravine_height * taper,
+        );
+
+        position += Vec3::new(yaw.cos(), pitch.sin(), yaw.sin());
+        yaw += rng.range_f32(-0.05, 0.05);
+    }
+}
+
+fn carve_ellipsoid(
+    grid: &mut ChunkGrid,
+    settings: &CarverSettings,
+    heights: &ColumnHeights,
+    center: Vec3,
+    horizontal_radius: f32,
+    vertical_radius: f32,
+) {
+    if horizontal_radius < 0.5 || vertical_radius < 0.5 {
+        return;
+    }
+
+    let min = (center - Vec3::new(horizontal_radius, vertical_radius, horizontal_radius))
+        .floor()
+        .as_ivec3()
+        .max(grid.origin);
+    let max = (center + Vec3::new(horizontal_radius, vertical_radius, horizontal_radius))
+        .ceil()
+        .as_ivec3()
+        .min(grid.origin + grid.size - IVec3::ONE);
+    if min.cmpgt(max).any() {
+        return;
+    }
+
+    for x in min.x..=max.x {
+        for z in min.z..=max.z {
+            for y in min.y..=max.y {
+                let offset = IVec3::new(x, y, z).as_vec3() - center;
+                let distance = (offset.x * offset.x + offset.z * offset.z)
+                    / (horizontal_radius * horizontal_radius)
+                    + (offset.y * offset.y) / (vertical_radius * vertical_radius);
+                let pos = IVec3::new(x, y, z);
+                if distance <= 1.0 && can_carve(grid, pos, settings, heights) {
+                    grid.set(pos, BlockType::Air);
+                }
+            }
+        }
+    }
+}
@@ -0,0 +1,469 @@
+use std::{fs, io, path::Path};
+
+use bevy::prelude::{warn, IVec3};
+use serde::{Deserialize, Serialize};
+
+use crate::utils::nbt::invalid_data;
+
+use super::{
+    region::write_atomic,
+    terrain::{BlockType, ChunkGrid},
+};
+
+/// Version written in front of every saved chunk. Bump it whenever the layout
+/// changes and keep a decoder for every older version.
+pub const FORMAT_VERSION: u16 = 3;
+
+/// Starts every chunk saved with a version header. Chunks without it were
+/// saved before versions existed and are version 1.
+const MAGIC: &[u8; 4] = b"CHNK";
+
+/// Blocks in the order of their ids in version 1, which used the position in
+/// [`BlockType::ALL`] at the time instead of a registry.
+const V1_BLOCKS: [&str; 26] = [
+    "air",
+    "grass",
+    "dirt",
+    "stone",
+    "deepslate",
+    "coal",
+    "iron",
+    "gold",
+    "bedrock",
+    "cobblestone",
+    "log",
+    "leaves",
+    "flower",
+    "tall_grass",
+    "birch_log",
+    "birch_leaves",
+    "spruce_log",
+    "spruce_leaves",
+    "jungle_log",
+    "jungle_leaves",
+    "vines",
+    "snow",
+    "gravel",
+    "sand",
+    "water",
+    "planks",
+];
+
+/// Height and width of chunks saved in version 1.
+const V1_SIZE: IVec3 = IVec3::new(16, 100, 16);
+
+/// Block names that were renamed, from the old to the new name.
+const RENAMED_BLOCKS: &[(&str, &str)] = &[];
+
+/// Block that replaces blocks that no longer exist.
+const MISSING_BLOCK: BlockType = BlockType::Stone;
+
+/// Ids blocks are saved under, stored next to the region files. New blocks are
+/// appended, so ids stay the same when block types are added or reordered.
+#[derive(Clone, Debug, Default, Deserialize, Serialize)]
+pub struct BlockRegistry {
+    /// Block names, indexed by id.
+    pub blocks: Vec<String>,
+}
+
+impl BlockRegistry {
+    /// Reads the registry at `path` and adds the blocks it doesn't know yet,
+    /// writing it back if anything was added.
+    pub fn open(path: &Path) -> io::Result<Self> {
+        let mut registry = Self::read(path)?;
+
+        let known = registry.blocks.len();
+        for block in BlockType::ALL {
+            if !registry.blocks.iter().any(|name| name == block.name()) {
+                registry.blocks.push(block.name().to_string());
+            }
+        }
+        if registry.blocks.len() != known {
+            if let Some(parent) = path.parent() {
+                fs::create_dir_all(parent)?;
+            }
+            let content = ron::ser::to_string_pretty(&registry, Default::default())
+                .map_err(|e| invalid_data(e.to_string()))?;
+            // A half written registry would keep every saved chunk from loading.
+            write_atomic(path, content.as_bytes())?;
+        }
+        Ok(registry)
+    }
+
+    /// Reads the registry at `path` as it is, empty when there is none.
+    pub fn read(path: &Path) -> io::Result<Self> {
+        match fs::read_to_string(path) {
+            Ok(content) => ron::from_str::<BlockRegistry>(&content)
+                .map_err(|e| invalid_data(format!("invalid block registry: {}", e))),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(Self::default()),
+            Err(e) => Err(e),
+        }
+    }
+
+    fn id(&self, block: BlockType) -> u16 {
+        // `open` registers every block type.
+        self.blocks
+            .iter()
+            .position(|name| name == block.name())
+            .unwrap() as u16
+    }
+
+    fn block(&self, id: u16) -> io::Result<BlockType> {
+        let name = self.blocks.get(id as usize).ok_or_else(|| {
+            // The chunk is fine but its registry went missing or was replaced,
+            // so the chunk must not be replaced either.
+            io::Error::new(
+                io::ErrorKind::Unsupported,
+                format!("block id {} is not registered", id),
+            )
+        })?;
+        Ok(block_by_name(name))
+    }
+}
+
+/// Looks up a saved block name, following renames.
+fn block_by_name(name: &str) -> BlockType {
+    let name = RENAMED_BLOCKS
+        .iter()
+        .find(|(old, _)| *old == name)
+        .map_or(name, |(_, new)| new);
+    BlockType::from_name(name).unwrap_or_else(|| {
+        warn!("Block {} no longer exists, replacing it", name);
+        MISSING_BLOCK
+    })
+}
+
+/// Visits every position of the box at `origin` in the order blocks are
+/// stored in.
+fn positions(origin: IVec3, size: IVec3) -> impl Iterator<Item = IVec3> {
+    (0..size.y).flat_map(move |y| {
+        (0..size.z).flat_map(move |z| (0..size.x).map(move |x| origin + IVec3::new(x, y, z)))
+    })
+}
+
+/// Saves the blocks of `grid`. The layout is
+///
+/// - the magic `CHNK` and the format version,
+/// - the grid's origin and size,
+/// - a palette of the registry ids used in the chunk,
+/// - the width of a palette index in bytes, 1 or 2 when the palette holds
+///   more than 256 blocks,
+/// - the palette index of every block in YZX order.
+///
+/// Numbers are big-endian.
+pub fn encode(grid: &ChunkGrid, registry: &BlockRegistry) -> Vec<u8> {
+    let mut palette = Vec::new();
+    let indices = positions(grid.origin, grid.size)
+        .map(|pos| {
+            let block = grid.get(pos);
+            match palette.iter().position(|b| *b == block) {
+                Some(index) => index as u16,
+                None => {
+                    palette.push(block);
+                    (palette.len() - 1) as u16
+                }
+            }
+        })
+        .collect::<Vec<_>>();
+
+    let mut bytes = MAGIC.to_vec();
+    bytes.extend(FORMAT_VERSION.to_be_bytes());
+    for value in [grid.origin, grid.size].iter().flat_map(|v| v.to_array()) {
+        bytes.extend(value.to_be_bytes());
+    }
+    bytes.extend((palette.len() as u16).to_be_bytes());
+    for block in &palette {
+        bytes.extend(registry.id(*block).to_be_bytes());
+    }
+    if palette.len() > 256 {
+        bytes.push(2);
+        bytes.extend(indices.into_iter().flat_map(u16::to_be_bytes));
+    } else {
+        bytes.push(1);
+        bytes.extend(indices.into_iter().map(|index| index as u8));
+    }
+    bytes
+}
+
+/// Fills `grid` with a saved chunk of any version. Chunks saved at another
+/// position or with another width are rejected as unsupported. A different height is the
+/// one size change that is migrated: blocks saved above the grid, because
+/// the world got lower since, are dropped and positions that weren't saved
+/// stay air.
+pub fn decode(grid: &mut ChunkGrid, bytes: &[u8], registry: &BlockRegistry) -> io::Result<()> {
+    let Some(rest) = bytes.strip_prefix(MAGIC) else {
+        return decode_v1(grid, bytes);
+    };
+    let mut reader = Reader { bytes: rest };
+    match reader.u16()? {
+        2 => decode_palette(grid, &mut reader, registry, Some(1)),
+        3 => decode_palette(grid, &mut reader, registry, None),
+        // Not corrupt, so the chunk must not be replaced.
+        version => Err(io::Error::new(
+            io::ErrorKind::Unsupported,
+            format!(
+                "chunk format version {} is newer than {}",
+                version, FORMAT_VERSION
+            ),
+        )),
+    }
+}
+
+/// Version 1: one byte per block, the block's index in [`V1_BLOCKS`], for
+/// the chunk at the grid's origin.
+fn decode_v1(grid: &mut ChunkGrid, bytes: &[u8]) -> io::Result<()> {
+    if bytes.len() != (V1_SIZE.x * V1_SIZE.y * V1_SIZE.z) as usize {
+        return Err(invalid_data(format!(
+            "version 1 chunk has {} blocks",
+            bytes.len()
+        )));
+    }
+    let blocks = V1_BLOCKS.map(block_by_name);
+    for (pos, id) in positions(grid.origin, V1_SIZE).zip(bytes) {
+        let block = blocks
+            .get(*id as usize)
+            .ok_or_else(|| invalid_data(format!("unknown block id {}", id)))?;
+        grid.set(pos, *block);
+    }
+    Ok(())
+}
+
+/// Versions 2 and 3: a palette of registry ids and an index into it for
+/// every block. Version 2 always used one byte per index, version 3 stores
+/// the width after the palette, so `index_width` is only given for version 2.
+fn decode_palette(
+    grid: &mut ChunkGrid,
+    reader: &mut Reader,
+    registry: &BlockRegistry,
+    index_width: Option<u8>,
+) -> io::Result<()> {
+    let origin = reader.ivec3()?;
+    let size = reader.ivec3()?;
+    if size.cmplt(IVec3::ZERO).any() {
+        return Err(invalid_data(format!("invalid chunk size {}", size)));
+    }
+    // Chunks saved with another chunk size aren't corrupt and must stay on
+    // disk, they just can't be loaded into chunks of this size.
+    if origin != grid.origin || size.x != grid.size.x || size.z != grid.size.z {
+        return Err(io::Error::new(
+            io::ErrorKind::Unsupported,
+            format!(
+                "chunk at {} of size {} was saved at {} with size {}",
+                grid.origin, grid.size, origin, size
+            ),
+        ));
+    }
+
+    let palette = (0..reader.u16()?)
+        .map(|_| registry.block(reader.u16()?))
+        .collect::<io::Result<Vec<_>>>()?;
+    let index_width = match index_width {
+        Some(width) => width,
+        None => reader.u8()?,
+    };
+    let len = [size.x, size.y, size.z, index_width as i32]
+        .into_iter()
+        .try_fold(1usize, |len, side| len.checked_mul(side as usize))
+        .ok_or_else(|| invalid_data(format!("invalid chunk size {}", size)))?;
+    let indices = reader.take(len)?;
+    let indices: Box<dyn Iterator<Item = usize>> = match index_width {
+        1 => Box::new(indices.iter().map(|index| *index as usize)),
+        2 => Box::new(
+            indices
+                .chunks_exact(2)
+                .map(|index| u16::from_be_bytes([index[0], index[1]]) as usize),
+        ),
+        width => return Err(invalid_data(format!("invalid index width {}", width))),
+    };
+    for (pos, index) in positions(origin, size).zip(indices) {
+        let block = palette
+            .get(index)
+            .ok_or_else(|| invalid_data("block is not in the palette"))?;
+        grid.set(pos, *block);
+    }
+    Ok(())
+}
+
+struct Reader<'a> {
+    bytes: &'a [u8],
+}
+
+impl<'a> Reader<'a> {
+    fn take(&mut self, len: usize) -> io::Result<&'a [u8]> {
+        if len > self.bytes.len() {
+            return Err(invalid_data("unexpected end of chunk data"));
+        }
+        let (taken, rest) = self.bytes.split_at(len);
+        self.bytes = rest;
+        Ok(taken)
+    }
+
+    fn u8(&mut self) -> io::Result<u8> {
+        Ok(self.take(1)?[0])
+    }
+
+    fn u16(&mut self) -> io::Result<u16> {
+        Ok(u16::from_be_bytes(self.take(2)?.try_into().unwrap()))
+    }
+
+    fn i32(&mut self) -> io::Result<i32> {
+        Ok(i32::from_be_bytes(self.take(4)?.try_into().unwrap()))
+    }
+
+    fn ivec3(&mut self) -> io::Result<IVec3> {
+        Ok(IVec3::new(self.i32()?, self.i32()?, self.i32()?))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const ORIGIN: IVec3 = IVec3::new(32, 0, -16);
+
+    fn fixture(name: &str) -> std::path::PathBuf {
+        Path::new(env!("CARGO_MANIFEST_DIR"))
+            .join("tests/fixtures")
+            .join(name)
+    }
+
+    /// Terrain the fixtures were saved from: bedrock, stone with coal, dirt
+    /// and grass under a bumpy surface, and water in the dips.
+    fn sample_block(pos: IVec3) -> BlockType {
+        let height = 40 + (pos.x.rem_euclid(16) + 2 * pos.z.rem_euclid(16)) % 7;
+        match pos.y {
+            0 => BlockType::Bedrock,
+            y if y < height - 3 && (pos.x + 3 * y + 5 * pos.z).rem_euclid(23) == 0 => {
+                BlockType::Coal
+            }
+            y if y < height - 3 => BlockType::Stone,
+            y if y < height => BlockType::Dirt,
+            y if y == height => BlockType::Grass,
+            y if y <= 45 => BlockType::Water,
+            _ => BlockType::Air,
+        }
+    }
+
+    fn sample_grid() -> ChunkGrid {
+        let mut grid = ChunkGrid::new(ORIGIN, V1_SIZE);
+        for pos in positions(grid.origin, grid.size) {
+            grid.set(pos, sample_block(pos));
+        }
+        grid
+    }
+
+    fn assert_sample(grid: &ChunkGrid) {
+        for pos in positions(grid.origin, grid.size) {
+            assert_eq!(grid.get(pos), sample_block(pos), "block at {}", pos);
+        }
+    }
+
+    fn registered() -> BlockRegistry {
+        BlockRegistry {
+            blocks: BlockType::ALL
+                .iter()
+                .map(|block| block.name().to_string())
+                .collect(),
+        }
+    }
+
+    fn decoded(bytes: &[u8], registry: &BlockRegistry) -> io::Result<ChunkGrid> {
+        let mut grid = ChunkGrid::new(ORIGIN, V1_SIZE);
+        decode(&mut grid, bytes, registry).map(|_| grid)
+    }
+
+    #[test]
+    fn round_trips_chunks() {
+        // Ids that don't follow the declaration order of the block types.
+        let mut registry = registered();
+        registry.blocks.rotate_left(7);
+        registry.blocks.swap(0, 20);
+
+        let bytes = encode(&sample_grid(), &registry);
+        assert_eq!(bytes[4..6], FORMAT_VERSION.to_be_bytes());
+        assert_sample(&decoded(&bytes, &registry).unwrap());
+    }
+
+    #[test]
+    fn decodes_version_1() {
+        let bytes = fs::read(fixture("chunk_v1.bin")).unwrap();
+        assert_sample(&decoded(&bytes, &registered()).unwrap());
+    }
+
+    /// `chunk_v2.bin` was saved with the ids of `chunk_v2_registry.ron`,
+    /// which lists the block types in reverse.
+    #[test]
+    fn decodes_version_2_with_its_registry() {
+        let bytes = fs::read(fixture("chunk_v2.bin")).unwrap();
+        assert_eq!(bytes[4..6], 2u16.to_be_bytes());
+        let registry = BlockRegistry::read(&fixture("chunk_v2_registry.ron")).unwrap();
+        assert_sample(&decoded(&bytes, &registry).unwrap());
+
+        let grid = decoded(&bytes, &registered()).unwrap();
+        assert_ne!(grid.get(ORIGIN), sample_block(ORIGIN));
+    }
+
+    #[test]
+    fn decodes_wide_palette_indices() {
+        let size = IVec3::new(16, 2, 16);
+        let mut bytes = MAGIC.to_vec();
+        bytes.extend(FORMAT_VERSION.to_be_bytes());
+        for value in [ORIGIN, size].iter().flat_map(|v| v.to_array()) {
+            bytes.extend(value.to_be_bytes());
+        }
+        // Every block type several times over, like a palette written by a
+        // version with more than 256 of them.
+        bytes.extend(300u16.to_be_bytes());
+        for index in 0..300u16 {
+            bytes.extend((index % BlockType::ALL.len() as u16).to_be_bytes());
+        }
+        bytes.push(2);
+        for index in 0..size.x * size.y * size.z {
+            bytes.extend((index as u16 % 256 + 44).to_be_bytes());
+        }
+
+        let mut grid = ChunkGrid::new(ORIGIN, size);
+        decode(&mut grid, &bytes, &registered()).unwrap();
+        for (index, pos) in positions(ORIGIN, size).enumerate() {
+            let expected = BlockType::ALL[(index % 256 + 44) % BlockType::ALL.len()];
+            assert_eq!(grid.get(pos), expected);
+        }
+    }
+
+    #[test]
+    fn migrates_only_height_changes() {
+        let registry = registered();
+        let bytes = encode(&sample_grid(), &registry);
+
+        let mut lower = ChunkGrid::new(ORIGIN, IVec3::new(16, 80, 16));
+        decode(&mut lower, &bytes, &registry).unwrap();
+        assert_sample(&lower);
+
+        let mut moved = ChunkGrid::new(ORIGIN + IVec3::X * 16, V1_SIZE);
+        let error = decode(&mut moved, &bytes, &registry).unwrap_err();
+        assert_eq!(error.kind(), io::ErrorKind::Unsupported);
+    }
+
+    /// `chunk_v3_narrow.bin` holds the first 8×8 columns of the sample,
+    /// saved by a build with a chunk size of 8.
+    #[test]
+    fn keeps_chunks_of_another_width() {
+        let bytes = fs::read(fixture("chunk_v3_narrow.bin")).unwrap();
+        let mut grid = ChunkGrid::new(ORIGIN, V1_SIZE);
+        let error = decode(&mut grid, &bytes, &registered()).unwrap_err();
+        assert_eq!(error.kind(), io::ErrorKind::Unsupported);
+
+        let mut narrow = ChunkGrid::new(ORIGIN, IVec3::new(8, 100, 8));
+        decode(&mut narrow, &bytes, &registered()).unwrap();
+        assert_sample(&narrow);
+    }
+
+    #[test]
+    fn rejects_newer_versions() {
+        let mut bytes = encode(&sample_grid(), &registered());
+        bytes[4..6].copy_from_slice(&(FORMAT_VERSION + 1).to_be_bytes());
+        let mut grid = ChunkGrid::new(ORIGIN, V1_SIZE);
+        let error = decode(&mut grid, &bytes, &registered()).unwrap_err();
+        assert_eq!(error.kind(), io::ErrorKind::Unsupported);
+    }
+}
@@ -0,0 +1,180 @@
+use bevy::prelude::{Resource, Vec2};
+use noise::Perlin;
+use serde::Deserialize;
+
+use super::terrain::get_perlin_value;
+
+pub const CONTINENTS_PATH: &str = "assets/terrain/continents.ron";
+
+/// Height of the terrain at one continentalness value. Between two points the
+/// height and hills are interpolated linearly.
+#[derive(Clone, Copy, Debug, Deserialize)]
+pub struct ContinentPoint {
+    pub continentalness: f32,
+    /// Height the local hills are added on top of.
+    pub height: f32,
+    /// Multiplier for the local hills, 1.0 keeps them as they are.
+    pub hills: f32,
+}
+
+#[derive(Clone, Debug, Deserialize)]
+pub struct ContinentSettings {
+    pub enabled: bool,
+    /// Combined with the world's seed by [`stage_seed`](crate::utils::random::stage_seed).
+    pub seed: u32,
+    /// Rough size of a continent in blocks.
+    pub scale: f32,
+    pub octaves: u32,
+    /// Control points sorted by continentalness, from deep ocean to mountains.
+    pub points: Vec<ContinentPoint>,
+    /// Raises the continentalness around the world origin so the player never
+    /// spawns in an ocean.
+    pub force_land_at_spawn: bool,
+    pub spawn_radius: f32,
+    /// Continentalness reached at the world origin when land is forced there.
+    pub spawn_continentalness: f32,
+}
+
+impl Default for ContinentSettings {
+    fn default() -> Self {
+        Self {
+            enabled: true,
+            seed: 10,
+            scale: 1500.0,
+            octaves: 4,
+            points: vec![
+                // Deep ocean
+                ContinentPoint {
+                    continentalness: -1.0,
+                    height: 20.0,
+                    hills: 0.2,
+                },
+                // Ocean
+                ContinentPoint {
+                    continentalness: -0.3,
+                    height: 38.0,
+                    hills: 0.3,
+                },
+                // Coast
+                ContinentPoint {
+                    continentalness: -0.1,
+                    height: 48.0,
+                    hills: 0.15,
+                },
+                // Plains
+                ContinentPoint {
+                    continentalness: 0.05,
+                    height: 50.0,
+                    hills: 0.3,
+                },
+                // Hills
+                ContinentPoint {
+                    continentalness: 0.35,
+                    height: 45.0,
+                    hills: 1.0,
+                },
+                // Mountains
+                ContinentPoint {
+                    continentalness: 0.6,
+                    height: 50.0,
+                    hills: 1.3,
+                },
+            ],
+            force_land_at_spawn: true,
+            spawn_radius: 400.0,
+            spawn_continentalness: 0.15,
+        }
+    }
+}
+
+/// Broad kind of terrain at a column, for systems that only care about the
+/// overall layout.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ContinentZone {
+    Ocean,
+    Coast,
+    Plains,
+    Mountains,
+}
+
+/// Low-frequency layer deciding where oceans, coasts, plains and mountain
+/// ranges are, over thousands of blocks. The local hills are raised, lowered
+/// and flattened according to it.
+#[derive(Resource, Clone)]
+pub struct Continents {
+    pub settings: ContinentSettings,
+    perlin: Perlin,
+}
+
+impl Continents {
+    pub fn new(settings: ContinentSettings) -> Self {
+        Self {
+            perlin: Perlin::new(settings.seed),
+            settings,
+        }
+    }
+
+    /// Continentalness of a column, from -1.0 in the deep ocean to 1.0 far inland.
+    pub fn continentalness(&self, x: i32, z: i32) -> f32 {
+        let settings = &self.settings;
+        let value = get_perlin_value(
+            self.perlin,
+            x as f32,
+            z as f32,
+            1.0,
+            1.0 / settings.scale,
+            settings.octaves,
+            0.5,
+            2.0,
+        )
+        .clamp(-1.0, 1.0);
+
+        if !settings.force_land_at_spawn {
+            return value;
+        }
+        let distance = Vec2::new(x as f32, z as f32).length();
+        let t = (1.0 - distance / settings.spawn_radius.max(1.0)).clamp(0.0, 1.0);
+        let t = t * t * (3.0 - 2.0 * t);
+        value + (settings.spawn_continentalness - value).max(0.0) * t
+    }
+
+    pub fn zone(&self, x: i32, z: i32) -> ContinentZone {
+        let continentalness = self.continentalness(x, z);
+        match continentalness {
+            c if c < -0.15 => ContinentZone::Ocean,
+            c if c < 0.0 => ContinentZone::Coast,
+            c if c < 0.45 => ContinentZone::Plains,
+            _ => ContinentZone::Mountains,
+        }
+    }
+
+    /// Height the local hills of a column start at and how much they are
+    /// scaled, `None` when the layer is disabled.
+    pub fn shape(&self, x: i32, z: i32) -> Option<(f32, f32)> {
+        if !self.settings.enabled || self.settings.points.is_empty() {
+            return None;
+        }
+        Some(self.interpolate(self.continentalness(x, z)))
+    }
+
+    fn interpolate(&self, continentalness: f32) -> (f32, f32) {
+        let points = &self.settings.points;
+        let first = points[0];
+        if continentalness <= first.continentalness {
+            return (first.height, first.hills);
+        }
+        for pair in points.windows(2) {
+            let (a, b) = (pair[0], pair[1]);
+            if continentalness <= b.continentalness {
+                let t = (continentalness - a.continentalness)
+                    / (b.continentalness - a.continentalness).max(f32::EPSILON);
+                return (
+                    a.height + (b.height - a.height) * t,
+                    a.hills + (b.hills - a.hills) * t,
+                );
+            }
+        }
+        let last = points[points.len() - 1];
+        (last.height, last.hills)
+    }
+}
@@ -0,0 +1,524 @@
+use std::{
+    collections::VecDeque,
+    fs::{self, File},
+    io::{self, BufRead, BufReader, Write},
+    path::{Path, PathBuf},
+};
+
+use bevy::prelude::*;
+use serde::{Deserialize, Serialize};
+
+use crate::utils::nbt::invalid_data;
+
+use super::{level::unix_time, pipeline::LoadedChunks, terrain::BlockType, worlds::OpenWorld};
+
+pub const EDITS_PATH: &str = "assets/terrain/edits.ron";
+
+/// Folder in a world's folder holding a journal of the edits of every session.
+pub const JOURNALS_DIR: &str = "edits";
+
+#[derive(Clone, Debug, Deserialize)]
+pub struct EditSettings {
+    /// Transactions that can be undone, older ones are forgotten.
+    pub history_limit: usize,
+    /// Writes every edit, undo and redo to a journal that can be replayed
+    /// onto a new world of the same seed.
+    pub journal: bool,
+}
+
+impl Default for EditSettings {
+    fn default() -> Self {
+        Self {
+            history_limit: 100,
+            journal: true,
+        }
+    }
+}
+
+/// A block that was changed.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct EditOp {
+    pub pos: [i32; 3],
+    #[serde(with = "block_name")]
+    pub old: BlockType,
+    #[serde(with = "block_name")]
+    pub new: BlockType,
+}
+
+/// Journals store blocks by [`BlockType::name`], which stays the same when
+/// block types are renamed or reordered in code.
+mod block_name {
+    use serde::{de::Error, Deserialize, Deserializer, Serializer};
+
+    use super::BlockType;
+
+    pub fn serialize<S: Serializer>(block: &BlockType, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_str(block.name())
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<BlockType, D::Error> {
+        let name = String::deserialize(deserializer)?;
+        BlockType::from_name(&name)
+            .ok_or_else(|| D::Error::custom(format!("unknown block {}", name)))
+    }
+}
+
+/// Changes that are undone and redone together.
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+pub struct Transaction {
+    pub ops: Vec<EditOp>,
+}
+
+/// One line of a journal.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub enum JournalEntry {
+    /// Starts every journal, with what a world needs to generate the same
+    /// terrain again.
+    World {
+        seed: u32,
+        preset: String,
+    },
+    Edit(Transaction),
+    Undo,
+    Redo,
+}
+
+/// Journal file of a session, created with its [`JournalEntry::World`] line
+/// on the first write so sessions without edits leave no file behind.
+struct Journal {
+    path: PathBuf,
+    world: JournalEntry,
+    file: Option<File>,
+}
+
+impl Journal {
+    /// Appends a line, flushing right away so it survives a crash.
+    fn write(&mut self, entry: &JournalEntry) -> io::Result<()> {
+        let file = match &mut self.file {
+            Some(file) => file,
+            None => {
+                if let Some(parent) = self.path.parent() {
+                    fs::create_dir_all(parent)?;
+                }
+                let mut file = File::create(&self.path)?;
+                write_line(&mut file, &self.world)?;
+                self.file.insert(file)
+            }
+        };
+        write_line(file, entry)
+    }
+}
+
+fn write_line(file: &mut File, entry: &JournalEntry) -> io::Result<()> {
+    let line = ron::to_string(entry).map_err(|e| invalid_data(e.to_string()))?;
+    writeln!(file, "{}", line)?;
+    file.flush()
+}
+
+/// Every block change goes through here so it can be undone and journaled.
+#[derive(Resource)]
+pub struct EditHistory {
+    undo: VecDeque<Transaction>,
+    redo: Vec<Transaction>,
+    /// Transaction started with [`Self::begin`] and not committed yet.
+    open: Option<Transaction>,
+    limit: usize,
+    journal: Option<Journal>,
+}
+
+impl EditHistory {
+    pub fn new(limit: usize) -> Self {
+        Self {
+            undo: VecDeque::new(),
+            redo: Vec::new(),
+            open: None,
+            limit,
+            journal: None,
+        }
+    }
+
+    /// History of the world the app plays in, journaling to a new file in the
+    /// world's folder when enabled. The file is only created by the first
+    /// edit.
+    pub fn for_world(settings: &EditSettings, world: &OpenWorld) -> Self {
+        let mut history = Self::new(settings.history_limit);
+        if let (true, Some(dir)) = (settings.journal, &world.dir) {
+            history.journal = Some(Journal {
+                path: dir
+                    .join(JOURNALS_DIR)
+                    .join(format!("{}.journal", unix_time())),
+                world: JournalEntry::World {
+                    seed: world.level.seed,
+                    preset: world.level.preset.clone(),
+                },
+                file: None,
+            });
+        }
+        history
+    }
+
+    /// Appends to the journal. The journal is dropped when writing fails.
+    fn write_journal(&mut self, entry: &JournalEntry) {
+        let Some(journal) = &mut self.journal else {
+            return;
+        };
+        if let Err(e) = journal.write(entry) {
+            warn!(
+                "Stopped journaling edits to {}: {}",
+                journal.path.display(),
+                e
+            );
+            self.journal = None;
+        }
+    }
+
+    /// Groups the following changes into one transaction until
+    /// [`Self::commit`].
+    pub fn begin(&mut self) {
+        self.open.get_or_insert_with(Transaction::default);
+    }
+
+    pub fn commit(&mut self) {
+        let Some(transaction) = self.open.take() else {
+            return;
+        };
+        if transaction.ops.is_empty() {
+            return;
+        }
+        self.write_journal(&JournalEntry::Edit(transaction.clone()));
+        self.undo.push_back(transaction);
+        while self.undo.len() > self.limit {
+            self.undo.pop_front();
+        }
+        self.redo.clear();
+    }
+
+    /// Changes a block like [`LoadedChunks::set_block`] and records it, in
+    /// its own transaction unless one was begun.
+    pub fn set_block(
+        &mut self,
+        loaded_chunks: &mut LoadedChunks,
+        pos: IVec3,
+        block: BlockType,
+    ) -> bool {
+        let Some(old) = loaded_chunks.block(pos) else {
+            return false;
+        };
+        if old == block {
+            return true;
+        }
+        loaded_chunks.set_block(pos, block);
+
+        let single = self.open.is_none();
+        self.begin();
+        self.open.as_mut().unwrap().ops.push(EditOp {
+            pos: pos.to_array(),
+            old,
+            new: block,
+        });
+        if single {
+            self.commit();
+        }
+        true
+    }
+
+    /// Reverts the last transaction. Returns `false` if there is none or one
+    /// of its chunks can't be changed right now, leaving it to try again.
+    pub fn undo(&mut self, loaded_chunks: &mut LoadedChunks) -> bool {
+        self.commit();
+        let Some(transaction) = self.undo.pop_back() else {
+            return false;
+        };
+        let ops = transaction.ops.iter().rev().map(|op| (op.pos, op.old));
+        if !apply(loaded_chunks, ops) {
+            self.undo.push_back(transaction);
+            return false;
+        }
+        self.write_journal(&JournalEntry::Undo);
+        self.redo.push(transaction);
+        true
+    }
+
+    /// Applies the last undone transaction again. Returns `false` like
+    /// [`Self::undo`].
+    pub fn redo(&mut self, loaded_chunks: &mut LoadedChunks) -> bool {
+        self.commit();
+        let Some(transaction) = self.redo.pop() else {
+            return false;
+        };
+        let ops = transaction.ops.iter().map(|op| (op.pos, op.new));
+        if !apply(loaded_chunks, ops) {
+            self.redo.push(transaction);
+            return false;
+        }
+        self.write_journal(&JournalEntry::Redo);
+        self.undo.push_back(transaction);
+        true
+    }
+
+    /// Whether there is a transaction to undo, to tell an empty history apart
+    /// from chunks that can't be changed when [`Self::undo`] fails.
+    pub fn can_undo(&self) -> bool {
+        !self.undo.is_empty()
+    }
+
+    pub fn can_redo(&self) -> bool {
+        !self.redo.is_empty()
+    }
+
+    /// Applies an entry read from a journal. Blocks that aren't what the
+    /// journal says they were are reported, as the replay no longer matches
+    /// the original session from there on.
+    pub fn replay(&mut self, loaded_chunks: &mut LoadedChunks, entry: &JournalEntry) -> bool {
+        match entry {
+            JournalEntry::World { .. } => true,
+            JournalEntry::Edit(transaction) => {
+                self.begin();
+                let mut applied = true;
+                for op in &transaction.ops {
+                    let pos = IVec3::from_array(op.pos);
+                    let found = loaded_chunks.block(pos);
+                    if found.is_some_and(|found| found != op.old) {
+                        warn!(
+                            "Block at {} is {:?} instead of {:?}, the replay diverges",
+                            pos,
+                            found.unwrap(),
+                            op.old
+                        );
+                    }
+                    applied &= self.set_block(loaded_chunks, pos, op.new);
+                }
+                self.commit();
+                applied
+            }
+            JournalEntry::Undo => self.undo(loaded_chunks),
+            JournalEntry::Redo => self.redo(loaded_chunks),
+        }
+    }
+}
+
+/// Sets every block if all of them can be changed, none otherwise.
+fn apply(
+    loaded_chunks: &mut LoadedChunks,
+    ops: impl Iterator<Item = ([i32; 3], BlockType)> + Clone,
+) -> bool {
+    if ops
+        .clone()
+        .any(|(pos, _)| loaded_chunks.block(IVec3::from_array(pos)).is_none())
+    {
+        return false;
+    }
+    for (pos, block) in ops {
+        loaded_chunks.set_block(IVec3::from_array(pos), block);
+    }
+    true
+}
+
+/// Journals to replay for `path`, oldest first: the journal itself, or every
+/// journal of a world's folder or its [`JOURNALS_DIR`] folder. Journals are
+/// named after the time their session started.
+pub fn journal_paths(path: &Path) -> io::Result<Vec<PathBuf>> {
+    if path.is_file() {
+        return Ok(vec![path.to_path_buf()]);
+    }
+    let dir = match path.join(JOURNALS_DIR) {
+        dir if dir.is_dir() => dir,
+        _ => path.to_path_buf(),
+    };
+    let mut journals = Vec::new();
+    for entry in fs::read_dir(&dir)? {
+        let path = entry?.path();
+        if path.extension().is_some_and(|e| e == "journal") {
+            journals.push(path);
+        }
+    }
+    journals.sort_by_key(|path| {
+        let started = path
+            .file_stem()
+            .and_then(|stem| stem.to_str())
+            .and_then(|stem| stem.parse::<u64>().ok());
+        (started, path.clone())
+    });
+    Ok(journals)
+}
+
+/// Reads every entry of a journal.
+pub fn read_journal(path: &Path) -> io::Result<Vec<JournalEntry>> {
+    BufReader::new(File::open(path)?)
+        .lines()
+        .enumerate()
+        .filter(|(_, line)| !line.as_ref().is_ok_and(|line| line.trim().is_empty()))
+        .map(|(number, line)| {
+            ron::from_str(&line?).map_err(|e| invalid_data(format!("line {}: {}", number + 1, e)))
+        })
+        .collect()
+}
+
+/// Undoes on Ctrl+Z and redoes on Ctrl+Y or Ctrl+Shift+Z.
+pub fn undo_redo_on_key(
+    keys: Res<Input<KeyCode>>,
+    mut history: ResMut<EditHistory>,
+    mut loaded_chunks: ResMut<LoadedChunks>,
+) {
+    if !keys.any_pressed([KeyCode::ControlLeft, KeyCode::ControlRight]) {
+        return;
+    }
+    let shift = keys.any_pressed([KeyCode::ShiftLeft, KeyCode::ShiftRight]);
+    let undo = keys.just_pressed(KeyCode::Z) && !shift;
+    let redo = keys.just_pressed(KeyCode::Y) || (keys.just_pressed(KeyCode::Z) && shift);
+    if undo && !history.undo(&mut loaded_chunks) {
+        if history.can_undo() {
+            info!("Can't undo while the changed chunks are being generated");
+        } else {
+            info!("Nothing to undo");
+        }
+    }
+    if redo && !history.redo(&mut loaded_chunks) {
+        if history.can_redo() {
+            info!("Can't redo while the changed chunks are being generated");
+        } else {
+            info!("Nothing to redo");
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use super::*;
+    use crate::plugins::terrain::{
+        pipeline::{chunk_id, ChunkStatus},
+        terrain::Chunk,
+    };
+
+    fn generated_chunk() -> LoadedChunks {
+        let mut loaded_chunks = LoadedChunks::default();
+        loaded_chunks.request(0, 0, ChunkStatus::Features);
+        let entry = loaded_chunks.chunks.get_mut(&chunk_id(0, 0)).unwrap();
+        entry.status = ChunkStatus::Features;
+        entry.chunk = Some(Arc::new(Chunk::new(0, 0)));
+        loaded_chunks
+    }
+
+    fn block(loaded_chunks: &LoadedChunks) -> BlockType {
+        loaded_chunks.block(IVec3::new(1, 2, 3)).unwrap()
+    }
+
+    /// Sets the block at 1, 2, 3 to each of `blocks`, one transaction each.
+    fn edit(history: &mut EditHistory, loaded_chunks: &mut LoadedChunks, blocks: &[BlockType]) {
+        for block in blocks {
+            assert!(history.set_block(loaded_chunks, IVec3::new(1, 2, 3), *block));
+        }
+    }
+
+    #[test]
+    fn undoes_and_redoes_in_order() {
+        let mut loaded_chunks = generated_chunk();
+        let mut history = EditHistory::new(10);
+        let blocks = [BlockType::Stone, BlockType::Dirt, BlockType::Sand];
+        edit(&mut history, &mut loaded_chunks, &blocks);
+
+        assert!(history.undo(&mut loaded_chunks));
+        assert_eq!(block(&loaded_chunks), BlockType::Dirt);
+        assert!(history.undo(&mut loaded_chunks));
+        assert!(history.undo(&mut loaded_chunks));
+        assert_eq!(block(&loaded_chunks), BlockType::Air);
+        assert!(!history.undo(&mut loaded_chunks));
+        assert!(!history.can_undo());
+
+        for expected in blocks {
+            assert!(history.redo(&mut loaded_chunks));
+            assert_eq!(block(&loaded_chunks), expected);
+        }
+        assert!(!history.redo(&mut loaded_chunks));
+    }
+
+    #[test]
+    fn forgets_transactions_over_the_limit() {
+        let mut loaded_chunks = generated_chunk();
+        let mut history = EditHistory::new(2);
+        let blocks = [BlockType::Stone, BlockType::Dirt, BlockType::Sand];
+        edit(&mut history, &mut loaded_chunks, &blocks);
+
+        assert!(history.undo(&mut loaded_chunks));
+        assert!(history.undo(&mut loaded_chunks));
+        assert!(!history.undo(&mut loaded_chunks));
+        assert_eq!(block(&loaded_chunks), BlockType::Stone);
+    }
+
+    #[test]
+    fn new_edits_clear_redo() {
+        let mut loaded_chunks = generated_chunk();
+        let mut history = EditHistory::new(10);
+        edit(&mut history, &mut loaded_chunks, &[BlockType::Stone]);
+        assert!(history.undo(&mut loaded_chunks));
+        assert!(history.can_redo());
+
+        edit(&mut history, &mut loaded_chunks, &[BlockType::Dirt]);
+        assert!(!history.can_redo());
+        assert!(!history.redo(&mut loaded_chunks));
+        assert_eq!(block(&loaded_chunks), BlockType::Dirt);
+    }
+
+    #[test]
+    fn keeps_transactions_of_busy_chunks() {
+        let mut loaded_chunks = generated_chunk();
+        let mut history = EditHistory::new(10);
+        edit(&mut history, &mut loaded_chunks, &[BlockType::Stone]);
+
+        loaded_chunks
+            .chunks
+            .get_mut(&chunk_id(0, 0))
+            .unwrap()
+            .in_flight = true;
+        assert!(!history.undo(&mut loaded_chunks));
+        assert!(history.can_undo());
+
+        loaded_chunks
+            .chunks
+            .get_mut(&chunk_id(0, 0))
+            .unwrap()
+            .in_flight = false;
+        assert!(history.undo(&mut loaded_chunks));
+        assert_eq!(block(&loaded_chunks), BlockType::Air);
+    }
+
+    #[test]
+    fn replays_the_journals_of_a_world_oldest_first() {
+        let world = std::env::temp_dir().join(format!("journals-{}", std::process::id()));
+        let dir = world.join(JOURNALS_DIR);
+        fs::create_dir_all(&dir).unwrap();
+        for name in ["900.journal", "1000.journal", "notes.txt"] {
+            fs::write(dir.join(name), "").unwrap();
+        }
+
+        let expected = vec![dir.join("900.journal"), dir.join("1000.journal")];
+        assert_eq!(journal_paths(&world).unwrap(), expected);
+        assert_eq!(journal_paths(&dir).unwrap(), expected);
+        assert_eq!(journal_paths(&expected[1]).unwrap(), &expected[1..]);
+        fs::remove_dir_all(&world).unwrap();
+    }
+
+    #[test]
+    fn journals_blocks_by_name() {
+        let entry = JournalEntry::Edit(Transaction {
+            ops: vec![EditOp {
+                pos: [1, -2, 3],
+                old: BlockType::TallGrass,
+                new: BlockType::Air,
+            }],
+        });
+        let line = ron::to_string(&entry).unwrap();
+        assert_eq!(
+            line,
+            r#"Edit((ops:[(pos:(1,-2,3),old:"tall_grass",new:"air")]))"#
+        );
+
+        let Ok(JournalEntry::Edit(read)) = ron::from_str(&line) else {
+            panic!("{} is not an edit", line);
+        };
+        assert_eq!(read.ops[0].old, BlockType::TallGrass);
+        assert!(ron::from_str::<JournalEntry>(&line.replace("air", "unobtainium")).is_err());
+    }
+}
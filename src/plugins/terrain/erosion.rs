@@ -0,0 +1,285 @@
+use std::sync::Arc;
+
+use bevy::prelude::{Resource, Vec2};
+use serde::Deserialize;
+
+use crate::utils::{
+    random::{chunk_seed, ChunkRng},
+    region_cache::{regions_covering, RegionCache},
+};
+
+use super::terrain::RENDER_AREA;
+
+pub const EROSION_PATH: &str = "assets/terrain/erosion.ron";
+
+const EROSION_SALT: u32 = 30;
+
+#[derive(Resource, Clone, Debug, Deserialize)]
+pub struct ErosionSettings {
+    pub enabled: bool,
+    /// Combined with the world's seed by [`stage_seed`](crate::utils::random::stage_seed).
+    pub seed: u32,
+    /// Width of the area a tile is eroded for, in blocks.
+    pub region_size: i32,
+    /// Extra blocks eroded around every region. Neighbouring tiles are blended
+    /// across this margin, which hides their seams.
+    pub overlap: i32,
+    /// Droplets simulated per block of a tile.
+    pub droplets_per_block: f32,
+    pub droplet_lifetime: u32,
+    /// How much a droplet keeps its direction instead of following the slope.
+    pub inertia: f32,
+    pub sediment_capacity: f32,
+    pub min_sediment_capacity: f32,
+    pub erode_speed: f32,
+    pub deposit_speed: f32,
+    pub evaporate_speed: f32,
+    pub gravity: f32,
+    pub thermal_iterations: u32,
+    /// Steepest height difference between neighbouring blocks that doesn't slide.
+    pub talus: f32,
+    /// Fraction of the excess material moved per thermal iteration.
+    pub thermal_rate: f32,
+}
+
+impl Default for ErosionSettings {
+    fn default() -> Self {
+        Self {
+            enabled: true,
+            seed: 10,
+            region_size: 128,
+            overlap: 32,
+            droplets_per_block: 0.6,
+            droplet_lifetime: 40,
+            inertia: 0.05,
+            sediment_capacity: 8.0,
+            min_sediment_capacity: 0.01,
+            erode_speed: 0.3,
+            deposit_speed: 0.3,
+            evaporate_speed: 0.01,
+            gravity: 4.0,
+            thermal_iterations: 8,
+            talus: 1.5,
+            thermal_rate: 0.25,
+        }
+    }
+}
+
+struct Tile {
+    size: i32,
+    heights: Vec<f32>,
+}
+
+impl Tile {
+    fn get(&self, x: i32, z: i32) -> f32 {
+        self.heights[(z * self.size + x) as usize]
+    }
+}
+
+/// Eroded heightmap, computed region by region and shared by every chunk task.
+/// Regions are eroded once and then reused for all chunks inside them.
+pub struct ErodedHeightmap {
+    settings: ErosionSettings,
+    tiles: RegionCache<Tile>,
+}
+
+#[derive(Resource, Clone)]
+pub struct ErosionCache(pub Option<Arc<ErodedHeightmap>>);
+
+impl ErosionCache {
+    pub fn new(settings: ErosionSettings) -> Self {
+        Self(settings.enabled.then(|| {
+            // Heights blend the 3×3 tiles around their own.
+            let capacity = regions_covering(RENDER_AREA, settings.region_size, 1);
+            Arc::new(ErodedHeightmap {
+                settings,
+                tiles: RegionCache::new(capacity),
+            })
+        }))
+    }
+}
+
+impl ErodedHeightmap {
+    /// Eroded height of a column. `base` returns the uneroded height and must
+    /// always be the same function for the same cache.
+    pub fn height(&self, x: i32, z: i32, base: &impl Fn(i32, i32) -> f32) -> f32 {
+        let size = self.settings.region_size;
+        let overlap = self.settings.overlap;
+        let region_x = x.div_euclid(size);
+        let region_z = z.div_euclid(size);
+
+        let mut total = 0.0;
+        let mut weights = 0.0;
+        for tile_x in region_x - 1..=region_x + 1 {
+            for tile_z in region_z - 1..=region_z + 1 {
+                let local_x = x - (tile_x * size - overlap);
+                let local_z = z - (tile_z * size - overlap);
+                let weight = edge_weight(local_x, size + 2 * overlap, overlap)
+                    * edge_weight(local_z, size + 2 * overlap, overlap);
+                if weight <= 0.0 {
+                    continue;
+                }
+                let tile = self
+                    .tiles
+                    .get_or_compute(tile_x, tile_z, || self.erode_tile(tile_x, tile_z, base));
+                total += tile.get(local_x, local_z) * weight;
+                weights += weight;
+            }
+        }
+
+        if weights > 0.0 {
+            total / weights
+        } else {
+            base(x, z)
+        }
+    }
+
+    fn erode_tile(&self, region_x: i32, region_z: i32, base: &impl Fn(i32, i32) -> f32) -> Tile {
+        let settings = &self.settings;
+        let size = settings.region_size + 2 * settings.overlap;
+        let origin_x = region_x * settings.region_size - settings.overlap;
+        let origin_z = region_z * settings.region_size - settings.overlap;
+
+        let mut heights = Vec::with_capacity((size * size) as usize);
+        for z in 0..size {
+            for x in 0..size {
+                heights.push(base(origin_x + x, origin_z + z));
+            }
+        }
+
+        let mut rng = ChunkRng::new(chunk_seed(settings.seed, EROSION_SALT, region_x, region_z));
+        let droplets = (settings.droplets_per_block * (size * size) as f32) as u32;
+        for _ in 0..droplets {
+            let start = Vec2::new(
+                rng.range_f32(0.0, (size - 1) as f32),
+                rng.range_f32(0.0, (size - 1) as f32),
+            );
+            simulate_droplet(&mut heights, size, start, settings);
+        }
+        for _ in 0..settings.thermal_iterations {
+            thermal_step(&mut heights, size, settings);
+        }
+
+        Tile { size, heights }
+    }
+}
+
+/// Weight of a tile at a local coordinate: zero on the outer edge of the
+/// overlap, rising linearly to one at the region border.
+fn edge_weight(local: i32, size: i32, overlap: i32) -> f32 {
+    if local < 0 || local >= size {
+        return 0.0;
+    }
+    let distance = local.min(size - 1 - local) as f32;
+    (distance / overlap.max(1) as f32).min(1.0)
+}
+
+/// Height and gradient at a point, interpolated from the four surrounding cells.
+fn sample(heights: &[f32], size: i32, pos: Vec2) -> (f32, Vec2) {
+    let cell_x = pos.x as i32;
+    let cell_z = pos.y as i32;
+    let u = pos.x - cell_x as f32;
+    let v = pos.y - cell_z as f32;
+    let index = (cell_z * size + cell_x) as usize;
+
+    let nw = heights[index];
+    let ne = heights[index + 1];
+    let sw = heights[index + size as usize];
+    let se = heights[index + size as usize + 1];
+
+    let gradient = Vec2::new(
+        (ne - nw) * (1.0 - v) + (se - sw) * v,
+        (sw - nw) * (1.0 - u) + (se - ne) * u,
+    );
+    let height = nw * (1.0 - u) * (1.0 - v) + ne * u * (1.0 - v) + sw * (1.0 - u) * v + se * u * v;
+    (height, gradient)
+}
+
+/// Adds `amount` to the four cells around `pos`, weighted by distance.
+fn distribute(heights: &mut [f32], size: i32, pos: Vec2, amount: f32) {
+    let cell_x = pos.x as i32;
+    let cell_z = pos.y as i32;
+    let u = pos.x - cell_x as f32;
+    let v = pos.y - cell_z as f32;
+    let index = (cell_z * size + cell_x) as usize;
+
+    heights[index] += amount * (1.0 - u) * (1.0 - v);
+    heights[index + 1] += amount * u * (1.0 - v);
+    heights[index + size as usize] += amount * (1.0 - u) * v;
+    heights[index + size as usize + 1] += amount * u * v;
+}
+
+fn simulate_droplet(heights: &mut [f32], size: i32, start: Vec2, settings: &ErosionSettings) {
+    let mut pos = start;
+    let mut direction = Vec2::ZERO;
+    let mut speed = 1.0;
+    let mut water = 1.0;
+    let mut sediment = 0.0;
+    let limit = (size - 1) as f32;
+
+    for _ in 0..settings.droplet_lifetime {
+        let (height, gradient) = sample(heights, size, pos);
+        direction = direction * settings.inertia - gradient * (1.0 - settings.inertia);
+        if direction.length_squared() < f32::EPSILON {
+            break;
+        }
+        direction = direction.normalize();
+
+        let next = pos + direction;
+        if next.x < 0.0 || next.y < 0.0 || next.x >= limit || next.y >= limit {
+            break;
+        }
+        let delta = sample(heights, size, next).0 - height;
+        let capacity = (-delta * speed * water * settings.sediment_capacity)
+            .max(settings.min_sediment_capacity);
+
+        if sediment > capacity || delta > 0.0 {
+            // Fill the pit the droplet climbs out of, or drop what it can't carry.
+            let deposit = if delta > 0.0 {
+                delta.min(sediment)
+            } else {
+                (sediment - capacity) * settings.deposit_speed
+            };
+            sediment -= deposit;
+            distribute(heights, size, pos, deposit);
+        } else {
+            let eroded = ((capacity - sediment) * settings.erode_speed).min(-delta);
+            sediment += eroded;
+            distribute(heights, size, pos, -eroded);
+        }
+
+        speed = (speed * speed + delta * settings.gravity).max(0.0).sqrt();
+        water *= 1.0 - settings.evaporate_speed;
+        pos = next;
+    }
+}
+
+/// Moves material from every cell to lower neighbours that are steeper than
+/// the talus angle.
+fn thermal_step(heights: &mut [f32], size: i32, settings: &ErosionSettings) {
+    let mut changes = vec![0.0; heights.len()];
+
+    for z in 1..size - 1 {
+        for x in 1..size - 1 {
+            let index = (z * size + x) as usize;
+            for neighbour in [
+                index - 1,
+                index + 1,
+                index - size as usize,
+                index + size as usize,
+            ] {
+                let difference = heights[index] - heights[neighbour];
+                if difference > settings.talus {
+                    let amount = (difference - settings.talus) * settings.thermal_rate / 4.0;
+                    changes[index] -= amount;
+                    changes[neighbour] += amount;
+                }
+            }
+        }
+    }
+
+    heights
+        .iter_mut()
+        .zip(changes)
+        .for_each(|(height, change)| *height += change);
+}
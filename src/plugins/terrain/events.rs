@@ -0,0 +1,57 @@
+use bevy::prelude::*;
+
+use super::{pipeline::LoadedChunks, terrain::BlockType};
+
+/// Marks the entity showing a chunk's mesh, with the chunk's origin in block
+/// coordinates.
+#[derive(Component, Clone, Copy, Debug)]
+pub struct ChunkEntity {
+    pub pos: IVec2,
+}
+
+/// Sent when a chunk's blocks become available, whether they were generated
+/// or loaded from disk.
+#[derive(Event, Clone, Copy, Debug)]
+pub struct ChunkLoaded {
+    pub pos: IVec2,
+}
+
+/// Sent when a chunk's mesh was spawned, again every time it is meshed anew.
+#[derive(Event, Clone, Copy, Debug)]
+pub struct ChunkMeshed {
+    pub pos: IVec2,
+    pub entity: Entity,
+}
+
+/// Sent when a chunk was dropped from [`LoadedChunks`], after its mesh was
+/// despawned.
+#[derive(Event, Clone, Copy, Debug)]
+pub struct ChunkUnloaded {
+    pub pos: IVec2,
+}
+
+/// Sent for every block that changed after its chunk was loaded.
+#[derive(Event, Clone, Copy, Debug)]
+pub struct BlockChanged {
+    pub pos: IVec3,
+    pub old: BlockType,
+    pub new: BlockType,
+}
+
+/// Sends the events [`LoadedChunks`] collected since the last frame, as it is
+/// changed from places that have no event writers, and despawns the meshes of
+/// unloaded chunks.
+pub fn send_chunk_events(
+    mut commands: Commands,
+    mut loaded_chunks: ResMut<LoadedChunks>,
+    mut block_changed: EventWriter<BlockChanged>,
+    mut chunk_unloaded: EventWriter<ChunkUnloaded>,
+) {
+    block_changed.send_batch(loaded_chunks.changed.drain(..));
+    for (pos, mesh) in loaded_chunks.unloaded.drain(..) {
+        if let Some(mesh) = mesh {
+            commands.entity(mesh).despawn();
+        }
+        chunk_unloaded.send(ChunkUnloaded { pos });
+    }
+}
@@ -0,0 +1,163 @@
+use bevy::prelude::{IVec3, Resource};
+use serde::Deserialize;
+
+use crate::utils::random::{chunk_seed, ChunkRng};
+
+use super::{
+    terrain::{BlockType, ChunkGrid, CHUNK_SIZE},
+    trees::{self, TreeSpecies},
+};
+
+pub const FEATURES_PATH: &str = "assets/terrain/features.ron";
+
+const FEATURE_SALT: u32 = 20;
+
+/// How many chunks away a feature may be anchored and still reach into a chunk.
+/// Features must therefore stay within `CHUNK_SIZE` blocks of their anchor.
+const FEATURE_REACH: i32 = 1;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Deserialize)]
+pub enum FeatureKind {
+    Tree(TreeSpecies),
+    Boulder,
+    Flower,
+    TallGrass,
+}
+
+#[derive(Clone, Debug, Deserialize)]
+pub struct FeaturePlacement {
+    pub kind: FeatureKind,
+    /// How many anchors are rolled per chunk.
+    pub attempts_per_chunk: u32,
+    /// Probability that a single attempt places the feature.
+    pub chance: f32,
+}
+
+#[derive(Resource, Clone, Debug, Deserialize)]
+pub struct FeatureSettings {
+    /// Combined with the world's seed by [`stage_seed`](crate::utils::random::stage_seed).
+    pub seed: u32,
+    pub placements: Vec<FeaturePlacement>,
+}
+
+impl Default for FeatureSettings {
+    fn default() -> Self {
+        Self {
+            seed: 10,
+            placements: vec![
+                FeaturePlacement {
+                    kind: FeatureKind::Tree(TreeSpecies::Oak),
+                    attempts_per_chunk: 3,
+                    chance: 0.5,
+                },
+                FeaturePlacement {
+                    kind: FeatureKind::Tree(TreeSpecies::Birch),
+                    attempts_per_chunk: 1,
+                    chance: 0.4,
+                },
+                FeaturePlacement {
+                    kind: FeatureKind::Tree(TreeSpecies::Spruce),
+                    attempts_per_chunk: 1,
+                    chance: 0.3,
+                },
+                FeaturePlacement {
+                    kind: FeatureKind::Tree(TreeSpecies::Jungle),
+                    attempts_per_chunk: 1,
+                    chance: 0.05,
+                },
+                FeaturePlacement {
+                    kind: FeatureKind::Tree(TreeSpecies::Dead),
+                    attempts_per_chunk: 1,
+                    chance: 0.1,
+                },
+                FeaturePlacement {
+                    kind: FeatureKind::Boulder,
+                    attempts_per_chunk: 1,
+                    chance: 0.1,
+                },
+                FeaturePlacement {
+                    kind: FeatureKind::Flower,
+                    attempts_per_chunk: 6,
+                    chance: 0.5,
+                },
+                FeaturePlacement {
+                    kind: FeatureKind::TallGrass,
+                    attempts_per_chunk: 12,
+                    chance: 0.6,
+                },
+            ],
+        }
+    }
+}
+
+/// A block of a feature, relative to the block the feature stands on.
+pub type FeatureBlock = (IVec3, BlockType);
+
+/// Stamps features into `grid`, including the parts of features anchored in
+/// neighbouring chunks. Anchors only depend on the seed, the chunk position,
+/// `surface` and `water_level`, so the neighbours never have to be generated
+/// first and the result doesn't depend on the order chunks are loaded in.
+pub fn place_features(
+    grid: &mut ChunkGrid,
+    settings: &FeatureSettings,
+    surface: impl Fn(i32, i32) -> i32,
+    water_level: impl Fn(i32, i32) -> Option<i32>,
+) {
+    for (chunk_x, chunk_z) in grid.chunks_within(FEATURE_REACH) {
+        let mut rng = ChunkRng::new(chunk_seed(settings.seed, FEATURE_SALT, chunk_x, chunk_z));
+
+        for placement in &settings.placements {
+            for _ in 0..placement.attempts_per_chunk {
+                // Always roll every value so one skipped attempt doesn't shift
+                // the ones after it.
+                let x = chunk_x * CHUNK_SIZE as i32 + rng.range_i32(0, CHUNK_SIZE as i32 - 1);
+                let z = chunk_z * CHUNK_SIZE as i32 + rng.range_i32(0, CHUNK_SIZE as i32 - 1);
+                let placed = rng.chance(placement.chance);
+                let mut feature_rng = ChunkRng::new(rng.next_u64());
+                if !placed || water_level(x, z).is_some() {
+                    continue;
+                }
+
+                let anchor = IVec3::new(x, surface(x, z) - 1, z);
+                let blocks = generate_feature(placement.kind, &mut feature_rng);
+                stamp(grid, anchor, &blocks);
+            }
+        }
+    }
+}
+
+/// Writes feature blocks into the grid without overwriting existing terrain.
+pub fn stamp(grid: &mut ChunkGrid, anchor: IVec3, blocks: &[FeatureBlock]) {
+    for (offset, block_type) in blocks {
+        let pos = anchor + *offset;
+        if !grid.get(pos).is_solid() {
+            grid.set(pos, *block_type);
+        }
+    }
+}
+
+pub fn generate_feature(kind: FeatureKind, rng: &mut ChunkRng) -> Vec<FeatureBlock> {
+    match kind {
+        FeatureKind::Tree(species) => trees::generate_tree(&species.parameters(), rng),
+        FeatureKind::Boulder => generate_boulder(rng),
+        FeatureKind::Flower => vec![(IVec3::Y, BlockType::Flower)],
+        FeatureKind::TallGrass => vec![(IVec3::Y, BlockType::TallGrass)],
+    }
+}
+
+fn generate_boulder(rng: &mut ChunkRng) -> Vec<FeatureBlock> {
+    let radius = rng.range_f32(1.0, 2.5);
+    let extent = radius.ceil() as i32;
+    let mut blocks = Vec::new();
+
+    for x in -extent..=extent {
+        for y in 0..=extent {
+            for z in -extent..=extent {
+                if ((x * x + y * y + z * z) as f32) <= radius * radius {
+                    blocks.push((IVec3::new(x, y, z), BlockType::Cobblestone));
+                }
+            }
+        }
+    }
+    blocks
+}
@@ -0,0 +1,292 @@
+use std::sync::Arc;
+
+use bevy::prelude::{warn, Resource};
+use image::ImageResult;
+use serde::Deserialize;
+
+use super::{
+    surface::{self, SurfaceRule},
+    terrain::ChunkGrid,
+};
+
+pub const HEIGHTMAP_PATH: &str = "assets/terrain/heightmap.ron";
+
+/// What the heightmap returns for columns outside the image.
+#[derive(Clone, Copy, Debug, Default, Deserialize)]
+pub enum EdgeMode {
+    /// Repeats the border pixels forever.
+    #[default]
+    Clamp,
+    /// Repeats the whole image.
+    Tile,
+    /// Repeats the image, flipping every other copy so the copies line up.
+    Mirror,
+}
+
+/// Surface blocks used where the biome image has this color.
+#[derive(Clone, Debug, Deserialize)]
+pub struct BiomeColor {
+    pub color: [u8; 3],
+    pub rule: SurfaceRule,
+}
+
+#[derive(Clone, Debug, Deserialize)]
+pub struct HeightmapSettings {
+    pub enabled: bool,
+    /// Grayscale PNG, 8 or 16 bits per pixel. Black is the lowest column.
+    pub image: String,
+    /// Height added by a white pixel on top of `offset`.
+    pub scale: f32,
+    /// Height of a black pixel.
+    pub offset: f32,
+    /// Width of a pixel in blocks. Heights are interpolated between pixels.
+    pub blocks_per_pixel: f32,
+    /// World position of the top left pixel.
+    pub origin: [i32; 2],
+    pub edge: EdgeMode,
+    /// Optional color image covering the same area as `image`. Columns get the
+    /// surface of the closest color in `biomes` instead of the surface rules.
+    pub biome_image: Option<String>,
+    pub biomes: Vec<BiomeColor>,
+    /// Erodes the imported terrain like the generated one, which changes the
+    /// heights the image describes.
+    #[serde(default)]
+    pub erosion: bool,
+    /// Cuts rivers into the imported terrain.
+    #[serde(default)]
+    pub rivers: bool,
+}
+
+impl Default for HeightmapSettings {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            image: "assets/terrain/heightmap.png".to_string(),
+            scale: 60.0,
+            offset: 30.0,
+            blocks_per_pixel: 1.0,
+            origin: [0, 0],
+            edge: EdgeMode::Clamp,
+            biome_image: None,
+            biomes: Vec::new(),
+            erosion: false,
+            rivers: false,
+        }
+    }
+}
+
+struct BiomeImage {
+    width: u32,
+    height: u32,
+    /// Index into `HeightmapSettings::biomes` for every pixel.
+    biomes: Vec<Option<usize>>,
+}
+
+/// Column heights read from an image, used instead of the Perlin height.
+pub struct ImportedHeightmap {
+    settings: HeightmapSettings,
+    width: u32,
+    height: u32,
+    /// Pixel values scaled to 0.0..=1.0.
+    values: Vec<f32>,
+    biomes: Option<BiomeImage>,
+}
+
+#[derive(Resource, Clone)]
+pub struct HeightmapCache(pub Option<Arc<ImportedHeightmap>>);
+
+impl HeightmapCache {
+    /// Loads the images named in `settings`. A heightmap that can't be read is
+    /// skipped with a warning and the generated terrain is used instead.
+    pub fn new(settings: HeightmapSettings) -> Self {
+        if !settings.enabled {
+            return Self(None);
+        }
+        match ImportedHeightmap::load(settings) {
+            Ok(heightmap) => Self(Some(Arc::new(heightmap))),
+            Err(e) => {
+                warn!("Failed to load heightmap: {}", e);
+                Self(None)
+            }
+        }
+    }
+}
+
+impl ImportedHeightmap {
+    pub fn load(settings: HeightmapSettings) -> ImageResult<Self> {
+        let image = image::open(&settings.image)?.into_luma16();
+        let (width, height) = image.dimensions();
+        let values = image
+            .pixels()
+            .map(|pixel| pixel.0[0] as f32 / u16::MAX as f32)
+            .collect();
+
+        let biomes = match &settings.biome_image {
+            Some(path) => {
+                let image = image::open(path)?.into_rgb8();
+                let (width, height) = image.dimensions();
+                let biomes = image
+                    .pixels()
+                    .map(|pixel| closest_biome(&settings.biomes, pixel.0))
+                    .collect();
+                Some(BiomeImage {
+                    width,
+                    height,
+                    biomes,
+                })
+            }
+            None => None,
+        };
+
+        Ok(Self {
+            settings,
+            width,
+            height,
+            values,
+            biomes,
+        })
+    }
+
+    pub fn settings(&self) -> &HeightmapSettings {
+        &self.settings
+    }
+
+    /// Height of the column at `x`, `z`, interpolated between the four
+    /// closest pixels.
+    pub fn height(&self, x: i32, z: i32) -> f32 {
+        let (u, v) = self.pixel_position(x, z);
+        let (u0, v0) = (u.floor(), v.floor());
+        let (tu, tv) = (u - u0, v - v0);
+        let sample = |du: i64, dv: i64| self.value(u0 as i64 + du, v0 as i64 + dv);
+
+        let top = sample(0, 0) * (1.0 - tu) + sample(1, 0) * tu;
+        let bottom = sample(0, 1) * (1.0 - tu) + sample(1, 1) * tu;
+        let value = top * (1.0 - tv) + bottom * tv;
+        self.settings.offset + value * self.settings.scale
+    }
+
+    /// Surface rule the biome image picks for the column at `x`, `z`.
+    pub fn biome(&self, x: i32, z: i32) -> Option<&SurfaceRule> {
+        let biomes = self.biomes.as_ref()?;
+        let (u, v) = self.pixel_position(x, z);
+        // The biome image may have a different resolution than the heightmap.
+        let bu = (u * biomes.width as f32 / self.width as f32).floor() as i64;
+        let bv = (v * biomes.height as f32 / self.height as f32).floor() as i64;
+        let bu = wrap(bu, biomes.width, self.settings.edge);
+        let bv = wrap(bv, biomes.height, self.settings.edge);
+        let index = biomes.biomes[(bv * biomes.width as usize) + bu]?;
+        Some(&self.settings.biomes[index].rule)
+    }
+
+    /// Replaces the surface of every column the biome image has a color for.
+    /// Runs after the surface rules, so columns without a biome keep theirs.
+    pub fn apply_biomes(&self, grid: &mut ChunkGrid, surface: impl Fn(i32, i32) -> i32) {
+        if self.biomes.is_none() {
+            return;
+        }
+        for x in grid.origin.x..grid.origin.x + grid.size.x {
+            for z in grid.origin.z..grid.origin.z + grid.size.z {
+                if let Some(rule) = self.biome(x, z) {
+                    surface::stamp_column(grid, x, z, surface(x, z), rule);
+                }
+            }
+        }
+    }
+
+    fn pixel_position(&self, x: i32, z: i32) -> (f32, f32) {
+        let blocks_per_pixel = self.settings.blocks_per_pixel.max(f32::EPSILON);
+        (
+            (x - self.settings.origin[0]) as f32 / blocks_per_pixel,
+            (z - self.settings.origin[1]) as f32 / blocks_per_pixel,
+        )
+    }
+
+    fn value(&self, u: i64, v: i64) -> f32 {
+        let u = wrap(u, self.width, self.settings.edge);
+        let v = wrap(v, self.height, self.settings.edge);
+        self.values[v * self.width as usize + u]
+    }
+}
+
+/// Maps a pixel coordinate outside the image back into it.
+fn wrap(coordinate: i64, size: u32, edge: EdgeMode) -> usize {
+    let size = size as i64;
+    let wrapped = match edge {
+        EdgeMode::Clamp => coordinate.clamp(0, size - 1),
+        EdgeMode::Tile => coordinate.rem_euclid(size),
+        EdgeMode::Mirror => {
+            let period = coordinate.rem_euclid(size * 2);
+            if period < size {
+                period
+            } else {
+                size * 2 - 1 - period
+            }
+        }
+    };
+    wrapped as usize
+}
+
+fn closest_biome(biomes: &[BiomeColor], color: [u8; 3]) -> Option<usize> {
+    biomes
+        .iter()
+        .enumerate()
+        .min_by_key(|(_, biome)| {
+            biome
+                .color
+                .iter()
+                .zip(color)
+                .map(|(a, b)| (*a as i32 - b as i32).pow(2))
+                .sum::<i32>()
+        })
+        .map(|(index, _)| index)
+}
+
+#[cfg(test)]
+mod tests {
+    use image::{GrayImage, ImageBuffer, Luma};
+
+    use super::*;
+
+    #[test]
+    fn wraps_coordinates_outside_the_image() {
+        let wrapped = |edge| {
+            [-5, -4, -1, 0, 3, 4, 7, 8, 9]
+                .map(|coordinate| wrap(coordinate, 4, edge))
+                .to_vec()
+        };
+        assert_eq!(wrapped(EdgeMode::Clamp), [0, 0, 0, 0, 3, 3, 3, 3, 3]);
+        assert_eq!(wrapped(EdgeMode::Tile), [3, 0, 3, 0, 3, 0, 3, 0, 1]);
+        assert_eq!(wrapped(EdgeMode::Mirror), [3, 3, 0, 0, 3, 3, 0, 0, 1]);
+    }
+
+    #[test]
+    fn scales_8_and_16_bit_images_alike() {
+        let dir = std::env::temp_dir().join(format!("heightmap-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let narrow = dir.join("narrow.png");
+        let wide = dir.join("wide.png");
+        // 51 of 255 and 13107 of 65535 are both a fifth of white.
+        GrayImage::from_raw(2, 1, vec![51, 255])
+            .unwrap()
+            .save(&narrow)
+            .unwrap();
+        ImageBuffer::<Luma<u16>, _>::from_raw(2, 1, vec![13107, 65535])
+            .unwrap()
+            .save(&wide)
+            .unwrap();
+
+        for path in [&narrow, &wide] {
+            let heightmap = ImportedHeightmap::load(HeightmapSettings {
+                enabled: true,
+                image: path.display().to_string(),
+                scale: 50.0,
+                offset: 10.0,
+                ..Default::default()
+            })
+            .unwrap();
+            assert!((heightmap.height(0, 0) - 20.0).abs() < 1e-4);
+            assert!((heightmap.height(1, 0) - 60.0).abs() < 1e-4);
+        }
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,113 @@
+use std::{
+    fs, io,
+    path::Path,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use bevy::prelude::*;
+use serde::{Deserialize, Serialize};
+
+use crate::{plugins::camera::camera::FlyCamera, utils::nbt::invalid_data};
+
+/// File in a world's folder holding its [`LevelMetadata`].
+pub const LEVEL_FILE: &str = "level.ron";
+
+/// Seconds a full day takes.
+const DAY_LENGTH: f32 = 1200.0;
+
+/// Time of day as a fraction of a day, 0 at midnight.
+#[derive(Resource, Clone, Copy, Debug, Default)]
+pub struct TimeOfDay(pub f32);
+
+pub fn advance_time(time: Res<Time>, mut time_of_day: ResMut<TimeOfDay>) {
+    time_of_day.0 = (time_of_day.0 + time.delta_seconds() / DAY_LENGTH).fract();
+}
+
+#[derive(Clone, Copy, Debug, Serialize, Deserialize)]
+pub struct CameraState {
+    pub position: [f32; 3],
+    pub yaw: f32,
+    pub pitch: f32,
+}
+
+impl Default for CameraState {
+    /// Where the camera starts in a new world.
+    fn default() -> Self {
+        Self {
+            position: [-2.0, 120.0, 5.0],
+            yaw: 0.0,
+            pitch: 0.0,
+        }
+    }
+}
+
+/// Everything about a world that isn't stored in its chunks.
+#[derive(Resource, Clone, Debug, Serialize, Deserialize)]
+pub struct LevelMetadata {
+    /// Name shown to players, the folder name may differ.
+    #[serde(default)]
+    pub name: String,
+    pub seed: u32,
+    /// Preset the world's generator settings were copied from.
+    #[serde(default)]
+    pub preset: String,
+    /// Unix time in seconds the world was created at.
+    #[serde(default)]
+    pub created: u64,
+    /// Unix time in seconds the world was last saved at.
+    #[serde(default)]
+    pub last_played: u64,
+    pub camera: CameraState,
+    pub time_of_day: f32,
+}
+
+impl LevelMetadata {
+    pub fn new(name: &str, seed: u32, preset: &str) -> Self {
+        Self {
+            name: name.to_string(),
+            seed,
+            preset: preset.to_string(),
+            created: unix_time(),
+            last_played: unix_time(),
+            camera: CameraState::default(),
+            time_of_day: 0.0,
+        }
+    }
+
+    /// Reads the metadata of the world in `world_dir`.
+    pub fn read(world_dir: &Path) -> io::Result<Self> {
+        let content = fs::read_to_string(world_dir.join(LEVEL_FILE))?;
+        ron::from_str(&content).map_err(|e| invalid_data(format!("invalid level metadata: {}", e)))
+    }
+
+    pub fn to_ron(&self) -> io::Result<String> {
+        ron::ser::to_string_pretty(self, Default::default())
+            .map_err(|e| invalid_data(e.to_string()))
+    }
+
+    /// Copy of the metadata with the current camera and time, about to be
+    /// saved.
+    pub fn capture(
+        &self,
+        camera: Option<(&Transform, &FlyCamera)>,
+        time_of_day: TimeOfDay,
+    ) -> Self {
+        let camera = camera.map_or(self.camera, |(transform, camera)| CameraState {
+            position: transform.translation.to_array(),
+            yaw: camera.yaw,
+            pitch: camera.pitch,
+        });
+        Self {
+            camera,
+            time_of_day: time_of_day.0,
+            last_played: unix_time(),
+            ..self.clone()
+        }
+    }
+}
+
+pub fn unix_time() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |time| time.as_secs())
+}
@@ -0,0 +1,61 @@
+use std::{collections::VecDeque, sync::Arc};
+
+use bevy::prelude::IVec3;
+
+use super::terrain::{Chunk, ChunkView};
+
+pub const MAX_LIGHT: u8 = 15;
+
+const DIRECTIONS: [IVec3; 6] = [
+    IVec3::X,
+    IVec3::NEG_X,
+    IVec3::Y,
+    IVec3::NEG_Y,
+    IVec3::Z,
+    IVec3::NEG_Z,
+];
+
+/// Computes sky light for every block of `chunk`. Light pours straight down
+/// every open column, including the border columns of the neighbours, and
+/// then spreads sideways into overhangs and caves, losing a level per block.
+pub fn compute_sky_light(chunk: &Chunk, neighbours: &[Arc<Chunk>]) -> Vec<u8> {
+    let view = ChunkView { chunk, neighbours };
+    let grid = &chunk.grid;
+    let mut light = vec![0; grid.len()];
+    let mut queue = VecDeque::new();
+
+    let top = grid.origin.y + grid.size.y - 1;
+    for x in grid.origin.x - 1..=grid.origin.x + grid.size.x {
+        for z in grid.origin.z - 1..=grid.origin.z + grid.size.z {
+            for y in (grid.origin.y..=top).rev() {
+                let pos = IVec3::new(x, y, z);
+                if view.block(pos).is_solid() {
+                    break;
+                }
+                if let Some(index) = grid.index_of(pos) {
+                    light[index] = MAX_LIGHT;
+                }
+                queue.push_back((pos, MAX_LIGHT));
+            }
+        }
+    }
+
+    while let Some((pos, level)) = queue.pop_front() {
+        if level <= 1 {
+            continue;
+        }
+        for direction in DIRECTIONS {
+            let next = pos + direction;
+            let Some(index) = grid.index_of(next) else {
+                continue;
+            };
+            if grid.get(next).is_solid() || light[index] >= level - 1 {
+                continue;
+            }
+            light[index] = level - 1;
+            queue.push_back((next, level - 1));
+        }
+    }
+
+    light
+}
@@ -0,0 +1,180 @@
+use bevy::prelude::{IVec3, Resource};
+use noise::{NoiseFn, Perlin};
+use serde::Deserialize;
+
+use crate::utils::random::{chunk_seed, ChunkRng};
+
+use super::terrain::{BlockType, ChunkGrid, CHUNK_SIZE};
+
+pub const MATERIALS_PATH: &str = "assets/terrain/materials.ron";
+
+const STRATA_SALT: u32 = 10;
+const BEDROCK_SALT: u32 = 11;
+const ORE_SALT: u32 = 12;
+
+/// A layer of base rock, used for every underground block up to `max_height`.
+#[derive(Clone, Debug, Deserialize)]
+pub struct Stratum {
+    pub block_type: BlockType,
+    pub max_height: i32,
+}
+
+#[derive(Clone, Debug, Deserialize)]
+pub struct OreSettings {
+    pub block_type: BlockType,
+    pub min_height: i32,
+    pub max_height: i32,
+    /// Number of blocks in a single vein.
+    pub vein_size: u32,
+    pub veins_per_chunk: u32,
+}
+
+/// Describes what the terrain is made of below its surface. Loaded from
+/// [`MATERIALS_PATH`], falling back to the built-in table.
+#[derive(Resource, Clone, Debug, Deserialize)]
+pub struct MaterialSettings {
+    /// Combined with the world's seed by [`stage_seed`](crate::utils::random::stage_seed).
+    pub seed: u32,
+    /// Bedrock is solid at `y = 0` and gets patchier up to this many layers.
+    pub bedrock_layers: i32,
+    /// How far strata boundaries wander up and down.
+    pub strata_wobble: f32,
+    /// Ordered bottom to top; the last stratum also fills everything above it.
+    pub strata: Vec<Stratum>,
+    pub ores: Vec<OreSettings>,
+}
+
+impl Default for MaterialSettings {
+    fn default() -> Self {
+        Self {
+            seed: 10,
+            bedrock_layers: 4,
+            strata_wobble: 3.0,
+            strata: vec![
+                Stratum {
+                    block_type: BlockType::Deepslate,
+                    max_height: 16,
+                },
+                Stratum {
+                    block_type: BlockType::Stone,
+                    max_height: 100,
+                },
+            ],
+            ores: vec![
+                OreSettings {
+                    block_type: BlockType::Coal,
+                    min_height: 10,
+                    max_height: 80,
+                    vein_size: 12,
+                    veins_per_chunk: 8,
+                },
+                OreSettings {
+                    block_type: BlockType::Iron,
+                    min_height: 5,
+                    max_height: 50,
+                    vein_size: 8,
+                    veins_per_chunk: 5,
+                },
+                OreSettings {
+                    block_type: BlockType::Gold,
+                    min_height: 2,
+                    max_height: 25,
+                    vein_size: 6,
+                    veins_per_chunk: 2,
+                },
+            ],
+        }
+    }
+}
+
+impl MaterialSettings {
+    fn stratum_at(&self, y: f32) -> BlockType {
+        self.strata
+            .iter()
+            .find(|s| y < s.max_height as f32)
+            .or(self.strata.last())
+            .map_or(BlockType::Stone, |s| s.block_type)
+    }
+
+    fn is_stratum(&self, block_type: BlockType) -> bool {
+        self.strata.iter().any(|s| s.block_type == block_type)
+    }
+}
+
+/// Replaces the uniform terrain fill with rock strata, bedrock and ore veins.
+/// The top of each column is left to the surface rules.
+pub fn apply_materials(
+    grid: &mut ChunkGrid,
+    settings: &MaterialSettings,
+    surface: impl Fn(i32, i32) -> i32,
+) {
+    let wobble = Perlin::new(settings.seed.wrapping_add(STRATA_SALT));
+
+    for x in grid.origin.x..grid.origin.x + grid.size.x {
+        for z in grid.origin.z..grid.origin.z + grid.size.z {
+            let height = surface(x, z);
+            let offset =
+                wobble.get([x as f64 * 0.02, z as f64 * 0.02]) as f32 * settings.strata_wobble;
+            // Bedrock patches are seeded per column so both chunks sharing a
+            // border agree on them.
+            let mut rng = ChunkRng::new(chunk_seed(settings.seed, BEDROCK_SALT, x, z));
+
+            for y in grid.origin.y..height.min(grid.origin.y + grid.size.y) {
+                let pos = IVec3::new(x, y, z);
+                if !grid.get(pos).is_solid() {
+                    continue;
+                }
+
+                let block_type = if y == 0
+                    || (y < settings.bedrock_layers && rng.chance(1.0 / (y + 1) as f32))
+                {
+                    BlockType::Bedrock
+                } else {
+                    settings.stratum_at(y as f32 + offset)
+                };
+                grid.set(pos, block_type);
+            }
+        }
+    }
+
+    let chunk_range = |min: i32, size: i32| {
+        let first = min.div_euclid(CHUNK_SIZE as i32);
+        let last = (min + size - 1).div_euclid(CHUNK_SIZE as i32);
+        // Veins can poke out of the chunk they start in.
+        (first - 1)..=(last + 1)
+    };
+    for chunk_x in chunk_range(grid.origin.x, grid.size.x) {
+        for chunk_z in chunk_range(grid.origin.z, grid.size.z) {
+            place_ores(grid, settings, chunk_x, chunk_z);
+        }
+    }
+}
+
+fn place_ores(grid: &mut ChunkGrid, settings: &MaterialSettings, chunk_x: i32, chunk_z: i32) {
+    let mut rng = ChunkRng::new(chunk_seed(settings.seed, ORE_SALT, chunk_x, chunk_z));
+
+    for ore in &settings.ores {
+        for _ in 0..ore.veins_per_chunk {
+            let mut pos = IVec3::new(
+                chunk_x * CHUNK_SIZE as i32 + rng.range_i32(0, CHUNK_SIZE as i32 - 1),
+                rng.range_i32(ore.min_height, ore.max_height),
+                chunk_z * CHUNK_SIZE as i32 + rng.range_i32(0, CHUNK_SIZE as i32 - 1),
+            );
+
+            for _ in 0..ore.vein_size {
+                if settings.is_stratum(grid.get(pos)) {
+                    grid.set(pos, ore.block_type);
+                }
+                pos += match rng.range_i32(0, 5) {
+                    0 => IVec3::X,
+                    1 => IVec3::NEG_X,
+                    2 => IVec3::Y,
+                    3 => IVec3::NEG_Y,
+                    4 => IVec3::Z,
+                    _ => IVec3::NEG_Z,
+                };
+                pos.y = pos.y.clamp(ore.min_height, ore.max_height);
+            }
+        }
+    }
+}
@@ -0,0 +1,421 @@
+use std::{
+    fmt::Write as _,
+    fs, io,
+    path::{Path, PathBuf},
+};
+
+use bevy::{
+    prelude::*,
+    render::mesh::{Indices, VertexAttributeValues},
+};
+
+use crate::{plugins::camera::camera::FlyCamera, utils::nbt::invalid_data};
+
+use super::{
+    pipeline::{chunk_id, ChunkStatus, LoadedChunks},
+    terrain::CHUNK_SIZE,
+    vox::EXPORT_DIR,
+};
+
+/// Texture the chunk meshes are drawn with.
+pub const TEXTURE_PATH: &str = "assets/grass.png";
+
+/// Chunks exported around the camera in every direction by the keybinding.
+const EXPORT_RADIUS: i32 = 2;
+
+const GLB_MAGIC: u32 = 0x4654_6c67;
+const GLB_JSON: u32 = 0x4e4f_534a;
+const GLB_BIN: u32 = 0x004e_4942;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum MeshFormat {
+    /// Binary glTF with the texture embedded.
+    Glb,
+    /// Wavefront OBJ with a `.mtl` material and the texture next to it.
+    Obj,
+}
+
+impl MeshFormat {
+    pub fn from_path(path: &Path) -> Option<Self> {
+        match path.extension().and_then(|e| e.to_str()) {
+            Some("glb") => Some(MeshFormat::Glb),
+            Some("obj") => Some(MeshFormat::Obj),
+            _ => None,
+        }
+    }
+
+    fn extension(self) -> &'static str {
+        match self {
+            MeshFormat::Glb => "glb",
+            MeshFormat::Obj => "obj",
+        }
+    }
+}
+
+/// Positions of the chunks within `radius` chunks of the chunk containing `center`.
+pub fn region_chunks(center: Vec3, radius: i32) -> impl Iterator<Item = (i32, i32)> {
+    let chunk_size = CHUNK_SIZE as i32;
+    let center_x = (center.x.floor() as i32).div_euclid(chunk_size);
+    let center_z = (center.z.floor() as i32).div_euclid(chunk_size);
+    (-radius..=radius).flat_map(move |dx| {
+        (-radius..=radius)
+            .map(move |dz| ((center_x + dx) * chunk_size, (center_z + dz) * chunk_size))
+    })
+}
+
+/// The meshes of several chunks merged into one. Chunk meshes are already in
+/// world coordinates so they are simply appended.
+#[derive(Default)]
+pub struct MergedMesh {
+    pub positions: Vec<[f32; 3]>,
+    pub normals: Vec<[f32; 3]>,
+    pub uvs: Vec<[f32; 2]>,
+    pub colors: Vec<[f32; 4]>,
+    pub indices: Vec<u32>,
+}
+
+impl MergedMesh {
+    /// Merges the meshes of the chunks within `radius` of `center`. Chunks that
+    /// aren't meshed yet are left out.
+    pub fn from_region(loaded_chunks: &LoadedChunks, center: Vec3, radius: i32) -> Self {
+        let mut merged = Self::default();
+        for (x, z) in region_chunks(center, radius) {
+            let Some(chunk) = loaded_chunks
+                .chunks
+                .get(&chunk_id(x, z))
+                .filter(|entry| entry.status == ChunkStatus::Meshed)
+                .and_then(|entry| entry.chunk.as_ref())
+            else {
+                continue;
+            };
+            merged.append(&chunk.mesh);
+        }
+        merged
+    }
+
+    fn append(&mut self, mesh: &Mesh) {
+        let (
+            Some(VertexAttributeValues::Float32x3(positions)),
+            Some(VertexAttributeValues::Float32x3(normals)),
+            Some(VertexAttributeValues::Float32x2(uvs)),
+            Some(VertexAttributeValues::Float32x4(colors)),
+            Some(Indices::U32(indices)),
+        ) = (
+            mesh.attribute(Mesh::ATTRIBUTE_POSITION),
+            mesh.attribute(Mesh::ATTRIBUTE_NORMAL),
+            mesh.attribute(Mesh::ATTRIBUTE_UV_0),
+            mesh.attribute(Mesh::ATTRIBUTE_COLOR),
+            mesh.indices(),
+        )
+        else {
+            return;
+        };
+        let offset = self.positions.len() as u32;
+        self.positions.extend(positions);
+        self.normals.extend(normals);
+        self.uvs.extend(uvs);
+        self.colors.extend(colors);
+        self.indices.extend(indices.iter().map(|i| i + offset));
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.indices.is_empty()
+    }
+}
+
+/// Writes `mesh` to `path` in the format picked from its extension, along
+/// with the texture. Returns the paths of all written files.
+pub fn export_mesh(mesh: &MergedMesh, path: &Path) -> io::Result<Vec<PathBuf>> {
+    let format = MeshFormat::from_path(path).ok_or_else(|| {
+        invalid_data(format!(
+            "{} is neither a .glb nor a .obj file",
+            path.display()
+        ))
+    })?;
+    if mesh.is_empty() {
+        return Err(invalid_data("there are no meshed chunks to export"));
+    }
+    if let Some(parent) = path.parent() {
+        fs::create_dir_all(parent)?;
+    }
+    let texture = fs::read(TEXTURE_PATH)?;
+
+    match format {
+        MeshFormat::Glb => {
+            fs::write(path, write_glb(mesh, &texture))?;
+            Ok(vec![path.to_path_buf()])
+        }
+        MeshFormat::Obj => {
+            let texture_path = path.with_extension("png");
+            let material_path = path.with_extension("mtl");
+            let file_name = |path: &Path| {
+                path.file_name()
+                    .and_then(|name| name.to_str())
+                    .unwrap_or_default()
+                    .to_string()
+            };
+            fs::write(path, write_obj(mesh, &file_name(&material_path)))?;
+            fs::write(&material_path, write_mtl(&file_name(&texture_path)))?;
+            fs::write(&texture_path, texture)?;
+            Ok(vec![path.to_path_buf(), material_path, texture_path])
+        }
+    }
+}
+
+/// Binary glTF holding a single mesh with vertex colors and the embedded
+/// texture.
+pub fn write_glb(mesh: &MergedMesh, texture: &[u8]) -> Vec<u8> {
+    let mut buffer = Vec::new();
+    let mut views = Vec::new();
+    let mut push_view = |buffer: &mut Vec<u8>, bytes: Vec<u8>| {
+        views.push((buffer.len(), bytes.len()));
+        buffer.extend(bytes);
+        // Every view starts at a multiple of four.
+        buffer.resize(buffer.len().next_multiple_of(4), 0);
+        views.len() - 1
+    };
+    let positions = push_view(&mut buffer, float_bytes(&mesh.positions));
+    let normals = push_view(&mut buffer, float_bytes(&mesh.normals));
+    let uvs = push_view(&mut buffer, float_bytes(&mesh.uvs));
+    let colors = push_view(&mut buffer, float_bytes(&mesh.colors));
+    let indices = push_view(
+        &mut buffer,
+        mesh.indices.iter().flat_map(|i| i.to_le_bytes()).collect(),
+    );
+    let image = push_view(&mut buffer, texture.to_vec());
+
+    let mut min = [f32::MAX; 3];
+    let mut max = [f32::MIN; 3];
+    for position in &mesh.positions {
+        for axis in 0..3 {
+            min[axis] = min[axis].min(position[axis]);
+            max[axis] = max[axis].max(position[axis]);
+        }
+    }
+
+    let mut json = String::new();
+    json.push_str(r#"{"asset":{"version":"2.0","generator":"mc-clone"},"#);
+    json.push_str(r#""scene":0,"scenes":[{"nodes":[0]}],"nodes":[{"mesh":0,"name":"terrain"}],"#);
+    json.push_str(r#""meshes":[{"primitives":[{"attributes":{"POSITION":0,"NORMAL":1,"TEXCOORD_0":2,"COLOR_0":3},"indices":4,"material":0}]}],"#);
+    json.push_str(r#""materials":[{"pbrMetallicRoughness":{"baseColorTexture":{"index":0},"metallicFactor":0.0,"roughnessFactor":1.0}}],"#);
+    // Nearest filtering keeps the pixelated look of the blocks.
+    json.push_str(r#""samplers":[{"magFilter":9728,"minFilter":9728}],"#);
+    json.push_str(r#""textures":[{"sampler":0,"source":0}],"#);
+    let _ = write!(
+        json,
+        r#""images":[{{"bufferView":{},"mimeType":"image/png"}}],"#,
+        image
+    );
+
+    let vertices = mesh.positions.len();
+    let _ = write!(
+        json,
+        r#""accessors":[{{"bufferView":{},"componentType":5126,"count":{},"type":"VEC3","min":[{},{},{}],"max":[{},{},{}]}},"#,
+        positions, vertices, min[0], min[1], min[2], max[0], max[1], max[2]
+    );
+    let _ = write!(
+        json,
+        r#"{{"bufferView":{},"componentType":5126,"count":{},"type":"VEC3"}},"#,
+        normals, vertices
+    );
+    let _ = write!(
+        json,
+        r#"{{"bufferView":{},"componentType":5126,"count":{},"type":"VEC2"}},"#,
+        uvs, vertices
+    );
+    let _ = write!(
+        json,
+        r#"{{"bufferView":{},"componentType":5126,"count":{},"type":"VEC4"}},"#,
+        colors, vertices
+    );
+    let _ = write!(
+        json,
+        r#"{{"bufferView":{},"componentType":5125,"count":{},"type":"SCALAR"}}],"#,
+        indices,
+        mesh.indices.len()
+    );
+
+    json.push_str(r#""bufferViews":["#);
+    for (i, (offset, length)) in views.iter().enumerate() {
+        if i > 0 {
+            json.push(',');
+        }
+        let _ = write!(
+            json,
+            r#"{{"buffer":0,"byteOffset":{},"byteLength":{}}}"#,
+            offset, length
+        );
+    }
+    let _ = write!(json, r#"],"buffers":[{{"byteLength":{}}}]}}"#, buffer.len());
+
+    let mut json = json.into_bytes();
+    json.resize(json.len().next_multiple_of(4), b' ');
+
+    let mut glb = Vec::with_capacity(28 + json.len() + buffer.len());
+    glb.extend(GLB_MAGIC.to_le_bytes());
+    glb.extend(2u32.to_le_bytes());
+    glb.extend(((28 + json.len() + buffer.len()) as u32).to_le_bytes());
+    glb.extend((json.len() as u32).to_le_bytes());
+    glb.extend(GLB_JSON.to_le_bytes());
+    glb.extend(json);
+    glb.extend((buffer.len() as u32).to_le_bytes());
+    glb.extend(GLB_BIN.to_le_bytes());
+    glb.extend(buffer);
+    glb
+}
+
+fn float_bytes<const N: usize>(values: &[[f32; N]]) -> Vec<u8> {
+    values
+        .iter()
+        .flatten()
+        .flat_map(|value| value.to_le_bytes())
+        .collect()
+}
+
+/// Wavefront OBJ of `mesh` using the material library `material_file`. Vertex
+/// colors are written after the positions, which most tools understand.
+pub fn write_obj(mesh: &MergedMesh, material_file: &str) -> String {
+    let mut obj = String::new();
+    let _ = writeln!(obj, "mtllib {}", material_file);
+    let _ = writeln!(obj, "o terrain");
+    for (position, color) in mesh.positions.iter().zip(&mesh.colors) {
+        let _ = writeln!(
+            obj,
+            "v {} {} {} {} {} {}",
+            position[0], position[1], position[2], color[0], color[1], color[2]
+        );
+    }
+    for uv in &mesh.uvs {
+        // OBJ texture coordinates start at the bottom of the image.
+        let _ = writeln!(obj, "vt {} {}", uv[0], 1.0 - uv[1]);
+    }
+    for normal in &mesh.normals {
+        let _ = writeln!(obj, "vn {} {} {}", normal[0], normal[1], normal[2]);
+    }
+    let _ = writeln!(obj, "usemtl terrain");
+    for triangle in mesh.indices.chunks_exact(3) {
+        let _ = write!(obj, "f");
+        for index in triangle {
+            let index = index + 1;
+            let _ = write!(obj, " {}/{}/{}", index, index, index);
+        }
+        obj.push('\n');
+    }
+    obj
+}
+
+fn write_mtl(texture_file: &str) -> String {
+    format!(
+        "newmtl terrain\nKa 1 1 1\nKd 1 1 1\nKs 0 0 0\nd 1\nillum 1\nmap_Kd {}\n",
+        texture_file
+    )
+}
+
+/// Exports the meshes around the camera to [`EXPORT_DIR`], as glTF when F7 is
+/// pressed and as OBJ when F8 is pressed.
+pub fn export_meshes_on_key(
+    keys: Res<Input<KeyCode>>,
+    camera: Query<&Transform, With<FlyCamera>>,
+    loaded_chunks: Res<LoadedChunks>,
+) {
+    let format = if keys.just_pressed(KeyCode::F7) {
+        MeshFormat::Glb
+    } else if keys.just_pressed(KeyCode::F8) {
+        MeshFormat::Obj
+    } else {
+        return;
+    };
+    let Ok(transform) = camera.get_single() else {
+        return;
+    };
+    let center = transform.translation;
+    let mesh = MergedMesh::from_region(&loaded_chunks, center, EXPORT_RADIUS);
+    let path = Path::new(EXPORT_DIR).join(format!(
+        "X{}Z{}.{}",
+        center.x.floor() as i32,
+        center.z.floor() as i32,
+        format.extension()
+    ));
+
+    match export_mesh(&mesh, &path) {
+        Ok(_) => info!("Exported meshes to {}", path.display()),
+        Err(e) => warn!("Failed to export {}: {}", path.display(), e),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Two triangles sharing an edge.
+    fn quad() -> MergedMesh {
+        MergedMesh {
+            positions: vec![
+                [0.0, 0.0, 0.0],
+                [1.0, 0.0, 0.0],
+                [1.0, 2.0, 0.0],
+                [0.0, 2.0, -1.0],
+            ],
+            normals: vec![[0.0, 0.0, 1.0]; 4],
+            uvs: vec![[0.0, 0.0], [1.0, 0.0], [1.0, 1.0], [0.0, 1.0]],
+            colors: vec![[1.0, 0.5, 0.25, 1.0]; 4],
+            indices: vec![0, 1, 2, 0, 2, 3],
+        }
+    }
+
+    fn u32_at(bytes: &[u8], offset: usize) -> u32 {
+        u32::from_le_bytes(bytes[offset..offset + 4].try_into().unwrap())
+    }
+
+    #[test]
+    fn writes_aligned_glb_chunks() {
+        // Five bytes leave the texture's view unaligned unless it is padded.
+        let texture = [1, 2, 3, 4, 5];
+        let glb = write_glb(&quad(), &texture);
+
+        assert_eq!(u32_at(&glb, 0), GLB_MAGIC);
+        assert_eq!(u32_at(&glb, 4), 2);
+        assert_eq!(u32_at(&glb, 8) as usize, glb.len());
+
+        let json_len = u32_at(&glb, 12) as usize;
+        assert_eq!(u32_at(&glb, 16), GLB_JSON);
+        assert_eq!(json_len % 4, 0);
+        let json = std::str::from_utf8(&glb[20..20 + json_len]).unwrap();
+
+        let bin = 20 + json_len;
+        let bin_len = u32_at(&glb, bin) as usize;
+        assert_eq!(u32_at(&glb, bin + 4), GLB_BIN);
+        assert_eq!(bin_len % 4, 0);
+        assert_eq!(bin + 8 + bin_len, glb.len());
+        assert!(json.contains(&format!(r#""buffers":[{{"byteLength":{}}}]"#, bin_len)));
+
+        let offsets = json
+            .split(r#""byteOffset":"#)
+            .skip(1)
+            .map(|rest| rest.split(',').next().unwrap().parse::<usize>().unwrap())
+            .collect::<Vec<_>>();
+        assert_eq!(offsets.len(), 6);
+        assert!(offsets.iter().all(|offset| offset % 4 == 0));
+        let image = bin + 8 + offsets[5];
+        assert_eq!(glb[image..image + texture.len()], texture);
+    }
+
+    #[test]
+    fn obj_indices_start_at_one() {
+        let obj = write_obj(&quad(), "terrain.mtl");
+        let lines = obj.lines().collect::<Vec<_>>();
+        assert_eq!(lines[0], "mtllib terrain.mtl");
+        assert_eq!(
+            lines.iter().filter(|line| line.starts_with("v ")).count(),
+            4
+        );
+        assert!(lines.contains(&"v 1 2 0 1 0.5 0.25"));
+        // The texture's top becomes OBJ's bottom.
+        assert!(lines.contains(&"vt 1 0"));
+        let faces = lines
+            .iter()
+            .filter(|line| line.starts_with("f "))
+            .collect::<Vec<_>>();
+        assert_eq!(faces, [&"f 1/1/1 2/2/2 3/3/3", &"f 1/1/1 3/3/3 4/4/4"]);
+    }
+}
@@ -1 +1,27 @@
+pub mod anvil;
+pub mod autosave;
+pub mod block_names;
+pub mod carver;
+pub mod chunk_format;
+pub mod continents;
+pub mod edits;
+pub mod erosion;
+pub mod events;
+pub mod features;
+pub mod heightmap;
+pub mod level;
+pub mod light;
+pub mod materials;
+pub mod mesh_export;
+pub mod pipeline;
+pub mod region;
+pub mod rivers;
+pub mod schematic;
+pub mod structures;
+pub mod surface;
+#[allow(clippy::module_inception)]
 pub mod terrain;
+pub mod tickets;
+pub mod trees;
+pub mod vox;
+pub mod worlds;
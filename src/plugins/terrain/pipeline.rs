@@ -0,0 +1,608 @@
+use std::{cmp::Reverse, collections::BinaryHeap, sync::Arc};
+
+use bevy::{
+    prelude::*,
+    render::render_resource::PrimitiveTopology,
+    tasks::{AsyncComputeTaskPool, Task},
+    utils::HashMap,
+};
+use futures_lite::future;
+
+use super::{
+    events::{BlockChanged, ChunkLoaded, ChunkMeshed},
+    region::WorldStorage,
+    terrain::{
+        run_stage, spawn_chunk_mesh, BlockType, Chunk, ChunkGenerator, GeneratorSettings,
+        CHUNK_SIZE,
+    },
+    tickets::ChunkTickets,
+};
+
+/// Stage tasks running at once. Chunks further down the queue wait, so the
+/// ones near the loaders aren't stuck behind thousands of far away ones.
+pub const MAX_TASKS_IN_FLIGHT: usize = 64;
+
+/// Generation progress of a chunk. Every status is reached by running one stage
+/// on a chunk that has the previous status.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum ChunkStatus {
+    Empty,
+    Noise,
+    Surface,
+    Carvers,
+    Structures,
+    Features,
+    Light,
+    Meshed,
+}
+
+impl ChunkStatus {
+    pub fn next(self) -> Option<ChunkStatus> {
+        match self {
+            ChunkStatus::Empty => Some(ChunkStatus::Noise),
+            ChunkStatus::Noise => Some(ChunkStatus::Surface),
+            ChunkStatus::Surface => Some(ChunkStatus::Carvers),
+            ChunkStatus::Carvers => Some(ChunkStatus::Structures),
+            ChunkStatus::Structures => Some(ChunkStatus::Features),
+            ChunkStatus::Features => Some(ChunkStatus::Light),
+            ChunkStatus::Light => Some(ChunkStatus::Meshed),
+            ChunkStatus::Meshed => None,
+        }
+    }
+
+    /// Status all four horizontal neighbours must have reached before a chunk
+    /// can run the stage producing this status.
+    pub fn neighbour_requirement(self) -> Option<ChunkStatus> {
+        match self {
+            // Light flows in from the neighbours' border columns, which may
+            // still change until their features are placed.
+            ChunkStatus::Light => Some(ChunkStatus::Features),
+            // Border faces are culled and shaded using the neighbours' blocks
+            // and light.
+            ChunkStatus::Meshed => Some(ChunkStatus::Light),
+            _ => None,
+        }
+    }
+}
+
+pub struct ChunkEntry {
+    pub x: i32,
+    pub z: i32,
+    pub status: ChunkStatus,
+    /// Status the chunk should eventually be advanced to.
+    pub target: ChunkStatus,
+    /// Result of the last finished stage, `None` while the chunk is `Empty`.
+    pub chunk: Option<Arc<Chunk>>,
+    /// Whether a stage task is currently running for this chunk.
+    pub in_flight: bool,
+    /// Whether the blocks changed since the chunk was last saved. It stays
+    /// set while a save of the chunk is running.
+    pub dirty: bool,
+    /// Counts the changes of the blocks, so a save can tell whether the chunk
+    /// changed again while it was running.
+    pub changes: u64,
+    /// Entity showing the chunk's mesh, once it is meshed.
+    pub mesh: Option<Entity>,
+    /// Chunks of higher priority run their stages first.
+    pub priority: i32,
+    /// Tells this entry apart from earlier entries of the same chunk, so
+    /// tasks spawned before the chunk was unloaded and requested again are
+    /// recognized.
+    pub generation: u64,
+}
+
+#[derive(Resource, Default)]
+pub struct LoadedChunks {
+    pub chunks: HashMap<String, ChunkEntry>,
+    /// Blocks changed since [`send_chunk_events`] last ran.
+    pub(super) changed: Vec<BlockChanged>,
+    /// Origins and meshes of the chunks unloaded since [`send_chunk_events`]
+    /// last ran.
+    pub(super) unloaded: Vec<(IVec2, Option<Entity>)>,
+    /// Generation of the next chunk that is added.
+    next_generation: u64,
+}
+
+pub fn chunk_id(x: i32, z: i32) -> String {
+    format!("X{}Z{}", x, z)
+}
+
+/// Id of the chunk containing the block at `pos`.
+fn block_chunk_id(pos: IVec3) -> String {
+    let chunk_size = CHUNK_SIZE as i32;
+    chunk_id(
+        pos.x.div_euclid(chunk_size) * chunk_size,
+        pos.z.div_euclid(chunk_size) * chunk_size,
+    )
+}
+
+impl LoadedChunks {
+    /// Makes sure the chunk at `x`, `z` exists and will be generated up to at
+    /// least `target`. Returns whether the chunk was newly added.
+    pub fn request(&mut self, x: i32, z: i32, target: ChunkStatus) -> bool {
+        match self.chunks.get_mut(&chunk_id(x, z)) {
+            Some(entry) => {
+                entry.target = entry.target.max(target);
+                false
+            }
+            None => {
+                self.next_generation += 1;
+                self.chunks.insert(
+                    chunk_id(x, z),
+                    ChunkEntry {
+                        x,
+                        z,
+                        status: ChunkStatus::Empty,
+                        target,
+                        chunk: None,
+                        in_flight: false,
+                        dirty: false,
+                        changes: 0,
+                        mesh: None,
+                        priority: 0,
+                        generation: self.next_generation,
+                    },
+                );
+                true
+            }
+        }
+    }
+
+    /// Changes a block of a loaded chunk and marks the chunk to be saved, lit
+    /// and meshed again. Returns `false` if the chunk isn't generated yet or
+    /// a stage is running on it. Edits go through
+    /// [`EditHistory::set_block`](super::edits::EditHistory::set_block) so
+    /// they can be undone.
+    pub(super) fn set_block(&mut self, pos: IVec3, block: BlockType) -> bool {
+        if self.block(pos).is_none() {
+            return false;
+        }
+        let entry = self.chunks.get_mut(&block_chunk_id(pos)).unwrap();
+        let grid = &mut Arc::make_mut(entry.chunk.as_mut().unwrap()).grid;
+        let old = grid.get(pos);
+        grid.set(pos, block);
+        if old != block {
+            self.changed.push(BlockChanged {
+                pos,
+                old,
+                new: block,
+            });
+        }
+        entry.dirty = true;
+        entry.changes += 1;
+        entry.status = entry.status.min(ChunkStatus::Features);
+        true
+    }
+
+    /// Drops the chunk at `x`, `z` and despawns its mesh. Its blocks are lost,
+    /// so callers save dirty chunks first. A stage still running for it is
+    /// cancelled. Only chunks whose blocks were available, and so were sent
+    /// in a [`ChunkLoaded`], are reported as
+    /// unloaded.
+    pub fn unload(&mut self, x: i32, z: i32) -> Option<ChunkEntry> {
+        let entry = self.chunks.remove(&chunk_id(x, z))?;
+        if entry.status >= ChunkStatus::Features {
+            self.unloaded.push((IVec2::new(x, z), entry.mesh));
+        }
+        Some(entry)
+    }
+
+    pub fn set_priority(&mut self, x: i32, z: i32, priority: i32) {
+        if let Some(entry) = self.chunks.get_mut(&chunk_id(x, z)) {
+            entry.priority = priority;
+        }
+    }
+
+    /// Block at `pos`, `None` when [`Self::set_block`] couldn't change it.
+    pub fn block(&self, pos: IVec3) -> Option<BlockType> {
+        let entry = self.chunks.get(&block_chunk_id(pos))?;
+        if entry.in_flight || entry.status < ChunkStatus::Features {
+            return None;
+        }
+        let chunk = entry.chunk.as_ref()?;
+        chunk.grid.contains(pos).then(|| chunk.grid.get(pos))
+    }
+
+    pub fn status(&self, x: i32, z: i32) -> Option<ChunkStatus> {
+        self.chunks.get(&chunk_id(x, z)).map(|entry| entry.status)
+    }
+
+    /// Number of chunks in every status, for inspecting the pipeline.
+    pub fn status_counts(&self) -> HashMap<ChunkStatus, usize> {
+        let mut counts = HashMap::new();
+        for entry in self.chunks.values() {
+            *counts.entry(entry.status).or_insert(0) += 1;
+        }
+        counts
+    }
+}
+
+fn neighbour_positions(x: i32, z: i32) -> [(i32, i32); 4] {
+    let size = CHUNK_SIZE as i32;
+    [(x - size, z), (x + size, z), (x, z - size), (x, z + size)]
+}
+
+/// Neighbours needed to run the stage producing `next`, or `None` if some of
+/// them aren't far enough along yet. Missing neighbours are requested.
+fn ready_neighbours(
+    loaded_chunks: &mut LoadedChunks,
+    x: i32,
+    z: i32,
+    next: ChunkStatus,
+) -> Option<Vec<Arc<Chunk>>> {
+    let priority = loaded_chunks.chunks[&chunk_id(x, z)].priority;
+    let mut neighbours = Vec::new();
+    if let Some(required) = next.neighbour_requirement() {
+        for (neighbour_x, neighbour_z) in neighbour_positions(x, z) {
+            loaded_chunks.request(neighbour_x, neighbour_z, required);
+            let neighbour = loaded_chunks
+                .chunks
+                .get_mut(&chunk_id(neighbour_x, neighbour_z))
+                .unwrap();
+            // Chunks waiting for their neighbours pass on their priority.
+            neighbour.priority = neighbour.priority.max(priority);
+            if neighbour.status >= required {
+                neighbours.extend(neighbour.chunk.clone());
+            }
+        }
+        if neighbours.len() < 4 {
+            return None;
+        }
+    }
+    Some(neighbours)
+}
+
+/// Runs the stage producing `next` and returns the status reached. Chunks
+/// that were saved are loaded instead of generated, with everything up to
+/// their features already in place.
+fn run_next_stage(
+    next: ChunkStatus,
+    chunk: Option<Arc<Chunk>>,
+    x: i32,
+    z: i32,
+    neighbours: &[Arc<Chunk>],
+    generator: &ChunkGenerator,
+) -> (ChunkStatus, Chunk) {
+    if let (ChunkStatus::Noise, Some(storage)) = (next, &generator.storage) {
+        match storage.load_chunk(x, z) {
+            Ok(Some(chunk)) => return (ChunkStatus::Features, chunk),
+            Ok(None) => {}
+            Err(e) => warn!("Failed to load chunk {} {}, generating it: {}", x, z, e),
+        }
+    }
+    let chunk = match chunk {
+        Some(chunk) => stage_input(&chunk, next),
+        None => Chunk::new(x, z),
+    };
+    (next, run_stage(next, chunk, neighbours, generator))
+}
+
+/// Copy of the parts of `chunk` the stage producing `next` works on. Only
+/// meshing reads the light, and the mesh and its blocks are built from
+/// scratch, so the other stages don't copy them.
+fn stage_input(chunk: &Chunk, next: ChunkStatus) -> Chunk {
+    Chunk {
+        position: chunk.position,
+        grid: chunk.grid.clone(),
+        light: match next {
+            ChunkStatus::Meshed => chunk.light.clone(),
+            _ => Vec::new(),
+        },
+        blocks: Vec::new(),
+        mesh: Mesh::new(PrimitiveTopology::TriangleList),
+    }
+}
+
+/// Runs every stage on the calling thread until all requested chunks reached
+/// their target, for tools that generate terrain without an app.
+pub fn generate_blocking(loaded_chunks: &mut LoadedChunks, generator: &ChunkGenerator) {
+    loop {
+        let mut waiting = loaded_chunks
+            .chunks
+            .iter()
+            .filter(|(_, entry)| entry.status < entry.target)
+            .map(|(id, entry)| (id.clone(), entry.x, entry.z, entry.status))
+            .collect::<Vec<_>>();
+        if waiting.is_empty() {
+            return;
+        }
+        // Keeps the order, and with it the result, independent of hashing.
+        waiting.sort_by_key(|(_, x, z, status)| (*status, *x, *z));
+
+        for (id, x, z, status) in waiting {
+            let Some(next) = status.next() else {
+                continue;
+            };
+            let Some(neighbours) = ready_neighbours(loaded_chunks, x, z, next) else {
+                continue;
+            };
+            let entry = loaded_chunks.chunks.get_mut(&id).unwrap();
+            let (status, chunk) =
+                run_next_stage(next, entry.chunk.clone(), x, z, &neighbours, generator);
+            entry.chunk = Some(Arc::new(chunk));
+            entry.status = status;
+        }
+    }
+}
+
+struct StageResult {
+    status: ChunkStatus,
+    chunk: Chunk,
+}
+
+#[derive(Component)]
+pub struct ComputeStage {
+    id: String,
+    /// [`ChunkEntry::generation`] of the chunk the task was spawned for.
+    generation: u64,
+    task: Task<StageResult>,
+}
+
+/// Stage tasks that finished and that were cancelled because their chunk was
+/// unloaded, since the app started.
+#[derive(Resource, Default, Debug)]
+pub struct StageTaskStats {
+    pub completed: usize,
+    pub cancelled: usize,
+}
+
+/// Spawns tasks for the next stage of the chunks that are behind their
+/// target and whose neighbours are far enough along, highest priority and
+/// nearest to a loader first, keeping at most [`MAX_TASKS_IN_FLIGHT`]
+/// running. The order is worked out again every frame, so it follows the
+/// loaders as they move.
+pub fn advance_chunks(
+    mut commands: Commands,
+    mut loaded_chunks: ResMut<LoadedChunks>,
+    tickets: Res<ChunkTickets>,
+    settings: GeneratorSettings,
+) {
+    let in_flight = loaded_chunks
+        .chunks
+        .values()
+        .filter(|entry| entry.in_flight)
+        .count();
+    let Some(mut free) = MAX_TASKS_IN_FLIGHT
+        .checked_sub(in_flight)
+        .filter(|free| *free > 0)
+    else {
+        return;
+    };
+    let thread_pool = AsyncComputeTaskPool::get();
+    let generator = Arc::new(settings.generator());
+
+    let mut waiting = loaded_chunks
+        .chunks
+        .iter()
+        .filter(|(_, entry)| !entry.in_flight && entry.status < entry.target)
+        .map(|(id, entry)| {
+            let distance = tickets.distance_squared(entry.x, entry.z);
+            (
+                Reverse((-entry.priority, distance, entry.x, entry.z)),
+                id.clone(),
+                entry.status,
+            )
+        })
+        .collect::<BinaryHeap<_>>();
+
+    while let Some((Reverse((_, _, x, z)), id, status)) = waiting.pop() {
+        if free == 0 {
+            break;
+        }
+        let Some(next) = status.next() else {
+            continue;
+        };
+
+        let Some(neighbours) = ready_neighbours(&mut loaded_chunks, x, z, next) else {
+            continue;
+        };
+
+        let entry = loaded_chunks.chunks.get_mut(&id).unwrap();
+        entry.in_flight = true;
+        free -= 1;
+        let chunk = entry.chunk.clone();
+        let generation = entry.generation;
+        let generator = generator.clone();
+        let task = thread_pool.spawn(async move {
+            let (status, chunk) = run_next_stage(next, chunk, x, z, &neighbours, &generator);
+            StageResult { status, chunk }
+        });
+        commands.spawn(ComputeStage {
+            id,
+            generation,
+            task,
+        });
+    }
+}
+
+/// Stores finished stages and spawns the meshes of chunks that reached
+/// `Meshed`, replacing their previous mesh, sending the events of both.
+/// Tasks of chunks that were unloaded meanwhile are cancelled.
+#[allow(clippy::too_many_arguments)]
+pub fn collect_stage_results(
+    mut commands: Commands,
+    mut meshes: ResMut<Assets<Mesh>>,
+    mut materials: ResMut<Assets<StandardMaterial>>,
+    server: Res<AssetServer>,
+    storage: Res<WorldStorage>,
+    mut stage_tasks: Query<(Entity, &mut ComputeStage)>,
+    mut loaded_chunks: ResMut<LoadedChunks>,
+    mut chunk_loaded: EventWriter<ChunkLoaded>,
+    mut chunk_meshed: EventWriter<ChunkMeshed>,
+    mut stats: ResMut<StageTaskStats>,
+) {
+    let save_generated = storage.0.as_ref().is_some_and(|s| s.save_generated);
+    let mut cancelled = 0;
+    for (e, mut stage) in &mut stage_tasks {
+        // Dropping the task of a chunk that was unloaded since it was spawned
+        // cancels it. A chunk requested again meanwhile has a new generation
+        // and maybe a task of its own.
+        let stale = loaded_chunks
+            .chunks
+            .get(&stage.id)
+            .is_none_or(|entry| entry.generation != stage.generation);
+        if stale {
+            commands.entity(e).despawn();
+            cancelled += 1;
+            continue;
+        }
+        let Some(result) = future::block_on(future::poll_once(&mut stage.task)) else {
+            continue;
+        };
+        commands.entity(e).despawn();
+        stats.completed += 1;
+
+        let entry = loaded_chunks.chunks.get_mut(&stage.id).unwrap();
+        let pos = IVec2::new(entry.x, entry.z);
+        if entry.status < ChunkStatus::Features && result.status >= ChunkStatus::Features {
+            chunk_loaded.send(ChunkLoaded { pos });
+        }
+        if result.status == ChunkStatus::Meshed {
+            if let Some(old) = entry.mesh.take() {
+                commands.entity(old).despawn();
+            }
+            let entity = spawn_chunk_mesh(
+                &mut commands,
+                &mut meshes,
+                &mut materials,
+                &server,
+                &result.chunk,
+            );
+            entry.mesh = Some(entity);
+            chunk_meshed.send(ChunkMeshed { pos, entity });
+        }
+        // A chunk that just finished generating its features is saved when
+        // `save_generated` is set. Only the `Structures` → `Features` step
+        // counts: chunks loaded from disk jump from `Empty` to `Features` and
+        // are already saved.
+        if save_generated
+            && entry.status == ChunkStatus::Structures
+            && result.status == ChunkStatus::Features
+        {
+            entry.dirty = true;
+            entry.changes += 1;
+        }
+        entry.status = result.status;
+        entry.chunk = Some(Arc::new(result.chunk));
+        entry.in_flight = false;
+    }
+
+    if cancelled > 0 {
+        stats.cancelled += cancelled;
+        debug!(
+            "Cancelled {} stage tasks of unloaded chunks, {} completed and {} cancelled so far",
+            cancelled, stats.completed, stats.cancelled
+        );
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::plugins::terrain::{
+        block_names::BlockNames, region::PersistenceSettings, worlds::OpenWorld,
+    };
+
+    fn set_status(loaded_chunks: &mut LoadedChunks, x: i32, z: i32, status: ChunkStatus) {
+        loaded_chunks.request(x, z, status);
+        let entry = loaded_chunks.chunks.get_mut(&chunk_id(x, z)).unwrap();
+        entry.status = status;
+        entry.chunk = Some(Arc::new(Chunk::new(x, z)));
+    }
+
+    #[test]
+    fn waits_for_the_neighbours_a_stage_needs() {
+        let mut loaded_chunks = LoadedChunks::default();
+        set_status(&mut loaded_chunks, 0, 0, ChunkStatus::Features);
+        loaded_chunks.set_priority(0, 0, 5);
+
+        assert!(
+            ready_neighbours(&mut loaded_chunks, 0, 0, ChunkStatus::Features)
+                .unwrap()
+                .is_empty()
+        );
+        assert!(ready_neighbours(&mut loaded_chunks, 0, 0, ChunkStatus::Light).is_none());
+        for (x, z) in neighbour_positions(0, 0) {
+            let entry = &loaded_chunks.chunks[&chunk_id(x, z)];
+            assert_eq!(
+                (entry.status, entry.target),
+                (ChunkStatus::Empty, ChunkStatus::Features)
+            );
+            assert_eq!(entry.priority, 5);
+        }
+
+        for (x, z) in neighbour_positions(0, 0).into_iter().skip(1) {
+            set_status(&mut loaded_chunks, x, z, ChunkStatus::Features);
+        }
+        assert!(ready_neighbours(&mut loaded_chunks, 0, 0, ChunkStatus::Light).is_none());
+        set_status(&mut loaded_chunks, -16, 0, ChunkStatus::Light);
+        assert_eq!(
+            ready_neighbours(&mut loaded_chunks, 0, 0, ChunkStatus::Light)
+                .unwrap()
+                .len(),
+            4
+        );
+        // Meshing needs lit neighbours.
+        assert!(ready_neighbours(&mut loaded_chunks, 0, 0, ChunkStatus::Meshed).is_none());
+    }
+
+    #[test]
+    fn generates_requested_chunks_and_their_neighbours() {
+        let world = OpenWorld::open(&PersistenceSettings {
+            enabled: false,
+            ..Default::default()
+        });
+        let generator = ChunkGenerator::load(&BlockNames::default(), &world);
+        let generate = || {
+            let mut loaded_chunks = LoadedChunks::default();
+            loaded_chunks.request(0, 0, ChunkStatus::Meshed);
+            generate_blocking(&mut loaded_chunks, &generator);
+            loaded_chunks
+        };
+        let loaded_chunks = generate();
+
+        let center = loaded_chunks.chunks[&chunk_id(0, 0)].chunk.clone().unwrap();
+        assert_eq!(loaded_chunks.status(0, 0), Some(ChunkStatus::Meshed));
+        assert!(!center.blocks.is_empty());
+        assert_eq!(center.light.len(), center.grid.len());
+        for (x, z) in neighbour_positions(0, 0) {
+            assert_eq!(loaded_chunks.status(x, z), Some(ChunkStatus::Light));
+        }
+        // Neighbours of the neighbours only need their blocks, and keep no
+        // light or mesh.
+        let corner = loaded_chunks.chunks[&chunk_id(16, 16)]
+            .chunk
+            .clone()
+            .unwrap();
+        assert_eq!(loaded_chunks.status(16, 16), Some(ChunkStatus::Features));
+        assert!(corner.light.is_empty() && corner.blocks.is_empty());
+        assert_eq!(loaded_chunks.chunks.len(), 1 + 4 + 8);
+
+        let again = generate();
+        let again = again.chunks[&chunk_id(0, 0)].chunk.as_ref().unwrap();
+        assert_eq!(again.light, center.light);
+        assert_eq!(again.blocks.len(), center.blocks.len());
+    }
+
+    #[test]
+    fn reports_unloads_of_loaded_chunks_only() {
+        let mut loaded_chunks = LoadedChunks::default();
+        loaded_chunks.request(0, 0, ChunkStatus::Meshed);
+        loaded_chunks.request(16, 0, ChunkStatus::Meshed);
+        loaded_chunks
+            .chunks
+            .get_mut(&chunk_id(0, 0))
+            .unwrap()
+            .status = ChunkStatus::Structures;
+        loaded_chunks
+            .chunks
+            .get_mut(&chunk_id(16, 0))
+            .unwrap()
+            .status = ChunkStatus::Features;
+
+        assert!(loaded_chunks.unload(0, 0).is_some());
+        assert!(loaded_chunks.unload(16, 0).is_some());
+        assert!(loaded_chunks.unload(32, 0).is_none());
+        assert_eq!(loaded_chunks.unloaded, vec![(IVec2::new(16, 0), None)]);
+    }
+}
@@ -0,0 +1,831 @@
+use std::{
+    fs::{self, File},
+    io::{self, Read, Seek, SeekFrom, Write},
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex},
+};
+
+use bevy::{prelude::*, utils::HashMap};
+use flate2::{read::ZlibDecoder, write::ZlibEncoder, Compression, Crc};
+use serde::Deserialize;
+
+use crate::utils::nbt::invalid_data;
+
+use super::{
+    chunk_format::{self, BlockRegistry},
+    level::{LevelMetadata, LEVEL_FILE},
+    terrain::{Chunk, CHUNK_SIZE},
+};
+
+pub const PERSISTENCE_PATH: &str = "assets/terrain/persistence.ron";
+
+/// Block registry of a world, next to its region folder.
+const REGISTRY_FILE: &str = "registry.ron";
+
+/// Everything a save is about to write, kept until all files are written.
+const JOURNAL_FILE: &str = "journal";
+
+const JOURNAL_MAGIC: &[u8; 4] = b"JRNL";
+
+const SECTOR_SIZE: u64 = 4096;
+
+/// Chunks per side of a region file.
+const REGION_SIZE: i32 = 32;
+
+/// Compression byte of zlib compressed payloads, the same as in Anvil files.
+const ZLIB: u8 = 2;
+
+/// Flag on the compression byte of payloads followed by a CRC-32 of their
+/// uncompressed data. Payloads saved before checksums existed don't have it.
+const CHECKSUM: u8 = 0x40;
+
+/// Extension of the copies kept of corrupt chunks and region files.
+const CORRUPT_EXTENSION: &str = "corrupt";
+
+#[derive(Clone, Debug, Deserialize)]
+pub struct PersistenceSettings {
+    pub enabled: bool,
+    /// Folder holding a folder per world.
+    pub saves: String,
+    /// Folder of the world to play in, created when it doesn't exist.
+    pub world: String,
+    /// Seed the world is created with, [`WORLD_SEED`](super::terrain::WORLD_SEED)
+    /// when unset. Worlds that exist keep the seed they were created with.
+    #[serde(default)]
+    pub seed: Option<u32>,
+    /// Also saves chunks nobody changed. They are otherwise regenerated from
+    /// the seed every time.
+    pub save_generated: bool,
+    /// Seconds between two autosaves.
+    pub autosave_interval: f32,
+}
+
+impl Default for PersistenceSettings {
+    fn default() -> Self {
+        Self {
+            enabled: true,
+            saves: "saves".to_string(),
+            world: "default".to_string(),
+            seed: None,
+            save_generated: false,
+            autosave_interval: 30.0,
+        }
+    }
+}
+
+/// A chunk encoded for saving, at chunk coordinates.
+pub struct SavedChunk {
+    pub chunk_x: i32,
+    pub chunk_z: i32,
+    pub payload: Vec<u8>,
+}
+
+/// Chunks saved in region files of 32×32 chunks. Every file starts with a
+/// table of where each chunk's compressed payload is, in 4 KiB sectors.
+///
+/// Files are never changed in place. Saves write new files next to the old
+/// ones and rename them over, after writing everything they are about to do
+/// to a journal. A save that is interrupted is finished from the journal the
+/// next time the world is opened, so the files never mix two saves.
+///
+/// Chunks that turn out to be corrupt when loading are copied to a `.corrupt`
+/// file and removed from their region, so they are generated again.
+pub struct RegionStorage {
+    world_dir: PathBuf,
+    region_dir: PathBuf,
+    registry: BlockRegistry,
+    pub save_generated: bool,
+    /// Location tables of the region files read so far. Locked while reading
+    /// a chunk so a save can't replace the file in between.
+    tables: Mutex<HashMap<(i32, i32), Vec<u32>>>,
+    /// Held while rewriting region files, so removing a corrupt chunk can't
+    /// undo a save running at the same time.
+    writing: Mutex<()>,
+}
+
+/// Something wrong with a saved world, found by [`RegionStorage::verify`].
+#[derive(Debug)]
+pub struct Problem {
+    pub path: PathBuf,
+    /// Chunk coordinates of the broken chunk, `None` when the whole file is.
+    pub chunk: Option<(i32, i32)>,
+    pub error: io::Error,
+}
+
+impl std::fmt::Display for Problem {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self.chunk {
+            Some((chunk_x, chunk_z)) => write!(
+                f,
+                "{}: chunk {} {}: {}",
+                self.path.display(),
+                chunk_x,
+                chunk_z,
+                self.error
+            ),
+            None => write!(f, "{}: {}", self.path.display(), self.error),
+        }
+    }
+}
+
+#[derive(Resource, Clone)]
+pub struct WorldStorage(pub Option<Arc<RegionStorage>>);
+
+impl WorldStorage {
+    /// Opens the storage of the world in `world_dir`.
+    pub fn open(world_dir: PathBuf, settings: &PersistenceSettings) -> Self {
+        let registry = match BlockRegistry::open(&world_dir.join(REGISTRY_FILE)) {
+            Ok(registry) => registry,
+            Err(e) => {
+                // Saving without the registry could mix up the blocks of
+                // chunks saved before.
+                warn!("Not saving {}: {}", world_dir.display(), e);
+                return Self(None);
+            }
+        };
+        let storage = RegionStorage {
+            region_dir: world_dir.join("region"),
+            world_dir,
+            registry,
+            save_generated: settings.save_generated,
+            tables: Mutex::new(HashMap::new()),
+            writing: Mutex::new(()),
+        };
+        if let Err(e) = storage.recover() {
+            warn!("Not saving {}: {}", storage.world_dir.display(), e);
+            return Self(None);
+        }
+        Self(Some(Arc::new(storage)))
+    }
+}
+
+impl RegionStorage {
+    /// Opens a world only to read it, without registering new blocks or
+    /// finishing an interrupted save.
+    pub fn open_read_only(world_dir: PathBuf) -> io::Result<Self> {
+        Ok(Self {
+            registry: BlockRegistry::read(&world_dir.join(REGISTRY_FILE))?,
+            region_dir: world_dir.join("region"),
+            world_dir,
+            save_generated: false,
+            tables: Mutex::new(HashMap::new()),
+            writing: Mutex::new(()),
+        })
+    }
+
+    fn region_path(&self, region_x: i32, region_z: i32) -> PathBuf {
+        self.region_dir
+            .join(format!("r.{}.{}.region", region_x, region_z))
+    }
+
+    /// Location table of a region file, read from disk on first use. Files
+    /// that don't exist have an empty table.
+    fn table<'a>(
+        &self,
+        tables: &'a mut HashMap<(i32, i32), Vec<u32>>,
+        region_x: i32,
+        region_z: i32,
+    ) -> io::Result<&'a [u32]> {
+        if !tables.contains_key(&(region_x, region_z)) {
+            let table = match File::open(self.region_path(region_x, region_z)) {
+                Ok(mut file) => {
+                    let mut header = vec![0u8; SECTOR_SIZE as usize];
+                    file.read_exact(&mut header)
+                        .map_err(|e| truncated(e, "region file header is cut off"))?;
+                    header
+                        .chunks_exact(4)
+                        .map(|b| u32::from_be_bytes(b.try_into().unwrap()))
+                        .collect()
+                }
+                Err(e) if e.kind() == io::ErrorKind::NotFound => {
+                    vec![0; (REGION_SIZE * REGION_SIZE) as usize]
+                }
+                Err(e) => return Err(e),
+            };
+            tables.insert((region_x, region_z), table);
+        }
+        Ok(&tables[&(region_x, region_z)])
+    }
+
+    /// Reads the payload of the chunk at chunk coordinates `chunk_x`,
+    /// `chunk_z`, `None` when it was never saved.
+    pub fn read(&self, chunk_x: i32, chunk_z: i32) -> io::Result<Option<Vec<u8>>> {
+        let (region_x, region_z) = (
+            chunk_x.div_euclid(REGION_SIZE),
+            chunk_z.div_euclid(REGION_SIZE),
+        );
+        // Region files are replaced by renaming, so the file opened together
+        // with the table keeps matching it after the lock is released.
+        let (location, mut file) = {
+            let mut tables = self.tables.lock().unwrap();
+            let location =
+                self.table(&mut tables, region_x, region_z)?[table_index(chunk_x, chunk_z)];
+            if location >> 8 == 0 || location & 0xff == 0 {
+                return Ok(None);
+            }
+            (location, File::open(self.region_path(region_x, region_z))?)
+        };
+        let (sector, sectors) = ((location >> 8) as u64, (location & 0xff) as u64);
+
+        file.seek(SeekFrom::Start(sector * SECTOR_SIZE))?;
+        let mut data = vec![0u8; (sectors * SECTOR_SIZE) as usize];
+        file.read_exact(&mut data)
+            .map_err(|e| truncated(e, "chunk is cut off by the end of the region file"))?;
+        decompress(&data).map(Some)
+    }
+
+    /// Replaces the given chunks in their region files.
+    ///
+    /// Every region with a changed chunk is read and written again as a whole,
+    /// which costs at least one sector per saved chunk, up to 4 MiB for a full
+    /// region, however few of its chunks changed. Autosaves batch the dirty
+    /// chunks so a region is rewritten at most once per save.
+    fn write_chunks(&self, chunks: &[SavedChunk]) -> io::Result<()> {
+        let mut regions = HashMap::<(i32, i32), Vec<(usize, Option<Vec<u8>>)>>::new();
+        for chunk in chunks {
+            regions
+                .entry((
+                    chunk.chunk_x.div_euclid(REGION_SIZE),
+                    chunk.chunk_z.div_euclid(REGION_SIZE),
+                ))
+                .or_default()
+                .push((
+                    table_index(chunk.chunk_x, chunk.chunk_z),
+                    Some(compress(&chunk.payload)?),
+                ));
+        }
+        let _writing = self.writing.lock().unwrap();
+        for ((region_x, region_z), changes) in regions {
+            self.rewrite_region(region_x, region_z, changes)?;
+        }
+        Ok(())
+    }
+
+    /// Rewrites a region file as a whole with the payloads at the given table
+    /// indices replaced or removed, which also drops the space of old
+    /// payloads. The caller holds `writing`.
+    fn rewrite_region(
+        &self,
+        region_x: i32,
+        region_z: i32,
+        changes: Vec<(usize, Option<Vec<u8>>)>,
+    ) -> io::Result<()> {
+        fs::create_dir_all(&self.region_dir)?;
+        let path = self.region_path(region_x, region_z);
+        let old = match fs::read(&path) {
+            Ok(old) => old,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => Vec::new(),
+            Err(e) => return Err(e),
+        };
+        let mut payloads = (0..(REGION_SIZE * REGION_SIZE) as usize)
+            .map(|index| old_payload(&old, index))
+            .collect::<Vec<_>>();
+        for (index, payload) in changes {
+            payloads[index] = payload;
+        }
+
+        let mut table = Vec::with_capacity(payloads.len());
+        let mut body = Vec::new();
+        for payload in &payloads {
+            let Some(payload) = payload else {
+                table.push(0);
+                continue;
+            };
+            let sector = 1 + body.len() as u64 / SECTOR_SIZE;
+            let sectors = payload.len() as u64 / SECTOR_SIZE;
+            if sectors > 0xff || sector >= 1 << 24 {
+                return Err(invalid_data("chunk doesn't fit in a region file"));
+            }
+            table.push(((sector as u32) << 8) | sectors as u32);
+            body.extend_from_slice(payload);
+        }
+        let mut file = table
+            .iter()
+            .flat_map(|location| location.to_be_bytes())
+            .collect::<Vec<_>>();
+        file.extend(body);
+
+        // Renaming while holding the lock keeps readers from using the old
+        // table with the new file.
+        let temp = write_temp(&path, &file)?;
+        let mut tables = self.tables.lock().unwrap();
+        fs::rename(temp, &path)?;
+        tables.insert((region_x, region_z), table);
+        Ok(())
+    }
+
+    /// Copies a corrupt chunk to a `.corrupt` file next to its region file
+    /// and removes it from the region. When the region's header itself is
+    /// broken the whole file is moved aside instead.
+    fn quarantine(&self, chunk_x: i32, chunk_z: i32) -> io::Result<PathBuf> {
+        let (region_x, region_z) = (
+            chunk_x.div_euclid(REGION_SIZE),
+            chunk_z.div_euclid(REGION_SIZE),
+        );
+        let _writing = self.writing.lock().unwrap();
+        let path = self.region_path(region_x, region_z);
+        let file = fs::read(&path)?;
+        if file.len() < SECTOR_SIZE as usize {
+            let backup = unused_backup(&path);
+            let mut tables = self.tables.lock().unwrap();
+            fs::rename(&path, &backup)?;
+            tables.remove(&(region_x, region_z));
+            return Ok(backup);
+        }
+
+        let index = table_index(chunk_x, chunk_z);
+        let location = u32::from_be_bytes(file[index * 4..index * 4 + 4].try_into().unwrap());
+        let start = ((location >> 8) as usize * SECTOR_SIZE as usize).min(file.len());
+        let end = (start + (location & 0xff) as usize * SECTOR_SIZE as usize).min(file.len());
+        let backup = unused_backup(
+            &self
+                .region_dir
+                .join(format!("c.{}.{}.chunk", chunk_x, chunk_z)),
+        );
+        write_atomic(&backup, &file[start..end])?;
+        self.rewrite_region(region_x, region_z, vec![(index, None)])?;
+        Ok(backup)
+    }
+
+    /// Reads and decodes the chunk at chunk coordinates `chunk_x`, `chunk_z`.
+    fn read_chunk(&self, chunk_x: i32, chunk_z: i32) -> io::Result<Option<Chunk>> {
+        let chunk_size = CHUNK_SIZE as i32;
+        let Some(payload) = self.read(chunk_x, chunk_z)? else {
+            return Ok(None);
+        };
+        let mut chunk = Chunk::new(chunk_x * chunk_size, chunk_z * chunk_size);
+        chunk_format::decode(&mut chunk.grid, &payload, &self.registry)?;
+        Ok(Some(chunk))
+    }
+
+    /// Reads the saved blocks of the chunk at `x`, `z`, in block coordinates.
+    /// Corrupt chunks are moved aside with a warning and treated as never
+    /// saved, so they are generated from the seed again.
+    pub fn load_chunk(&self, x: i32, z: i32) -> io::Result<Option<Chunk>> {
+        let chunk_size = CHUNK_SIZE as i32;
+        let (chunk_x, chunk_z) = (x.div_euclid(chunk_size), z.div_euclid(chunk_size));
+        match self.read_chunk(chunk_x, chunk_z) {
+            Err(e) if e.kind() == io::ErrorKind::InvalidData => {
+                match self.quarantine(chunk_x, chunk_z) {
+                    Ok(backup) => warn!(
+                        "Chunk {} {} is corrupt, generating it again and keeping a copy at {}: {}",
+                        x,
+                        z,
+                        backup.display(),
+                        e
+                    ),
+                    Err(backup_error) => warn!(
+                        "Chunk {} {} is corrupt, generating it again without a copy ({}): {}",
+                        x, z, backup_error, e
+                    ),
+                }
+                Ok(None)
+            }
+            result => result,
+        }
+    }
+
+    /// Reads every saved chunk and the world's metadata without changing
+    /// anything, returning what is broken.
+    pub fn verify(&self) -> io::Result<Vec<Problem>> {
+        let mut problems = Vec::new();
+        let journal = self.world_dir.join(JOURNAL_FILE);
+        if journal.exists() {
+            problems.push(Problem {
+                path: journal,
+                chunk: None,
+                error: io::Error::other(
+                    "a save was interrupted, it is finished when the world is opened",
+                ),
+            });
+        }
+        if let Err(error) = LevelMetadata::read(&self.world_dir) {
+            problems.push(Problem {
+                path: self.world_dir.join(LEVEL_FILE),
+                chunk: None,
+                error,
+            });
+        }
+
+        let entries = match fs::read_dir(&self.region_dir) {
+            Ok(entries) => entries,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(problems),
+            Err(e) => return Err(e),
+        };
+        let mut regions = Vec::new();
+        for entry in entries {
+            let path = entry?.path();
+            if let Some(region) = region_coordinates(&path) {
+                regions.push((region, path));
+            }
+        }
+        regions.sort();
+
+        for ((region_x, region_z), path) in regions {
+            let table = {
+                let mut tables = self.tables.lock().unwrap();
+                match self.table(&mut tables, region_x, region_z) {
+                    Ok(table) => table.to_vec(),
+                    Err(error) => {
+                        problems.push(Problem {
+                            path,
+                            chunk: None,
+                            error,
+                        });
+                        continue;
+                    }
+                }
+            };
+            for (index, location) in table.into_iter().enumerate() {
+                if location == 0 {
+                    continue;
+                }
+                let chunk_x = region_x * REGION_SIZE + index as i32 % REGION_SIZE;
+                let chunk_z = region_z * REGION_SIZE + index as i32 / REGION_SIZE;
+                if let Err(error) = self.read_chunk(chunk_x, chunk_z) {
+                    problems.push(Problem {
+                        path: path.clone(),
+                        chunk: Some((chunk_x, chunk_z)),
+                        error,
+                    });
+                }
+            }
+        }
+        Ok(problems)
+    }
+
+    pub fn encode_chunk(&self, chunk: &Chunk) -> SavedChunk {
+        let chunk_size = CHUNK_SIZE as i32;
+        SavedChunk {
+            chunk_x: chunk.grid.origin.x.div_euclid(chunk_size),
+            chunk_z: chunk.grid.origin.z.div_euclid(chunk_size),
+            payload: chunk_format::encode(&chunk.grid, &self.registry),
+        }
+    }
+
+    /// Saves the chunks and the metadata together. Only one save may run at a
+    /// time.
+    pub fn save(&self, chunks: &[SavedChunk], level: &LevelMetadata) -> io::Result<()> {
+        let level = level.to_ron()?;
+        let journal = self.world_dir.join(JOURNAL_FILE);
+        fs::create_dir_all(&self.world_dir)?;
+        write_atomic(&journal, &encode_journal(chunks, &level))?;
+        self.apply(chunks, &level)?;
+        fs::remove_file(journal)
+    }
+
+    fn apply(&self, chunks: &[SavedChunk], level: &str) -> io::Result<()> {
+        self.write_chunks(chunks)?;
+        write_atomic(&self.world_dir.join(LEVEL_FILE), level.as_bytes())
+    }
+
+    /// Finishes a save that was interrupted after writing its journal.
+    fn recover(&self) -> io::Result<()> {
+        let journal = self.world_dir.join(JOURNAL_FILE);
+        let bytes = match fs::read(&journal) {
+            Ok(bytes) => bytes,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(()),
+            Err(e) => return Err(e),
+        };
+        let (chunks, level) = decode_journal(&bytes)?;
+        warn!(
+            "Finishing an interrupted save of {} chunks in {}",
+            chunks.len(),
+            self.world_dir.display()
+        );
+        self.apply(&chunks, &level)?;
+        fs::remove_file(journal)
+    }
+}
+
+fn table_index(chunk_x: i32, chunk_z: i32) -> usize {
+    (chunk_x.rem_euclid(REGION_SIZE) + chunk_z.rem_euclid(REGION_SIZE) * REGION_SIZE) as usize
+}
+
+/// Sectors holding the chunk at `index` of an existing region file, `None`
+/// when the chunk isn't in the file.
+fn old_payload(file: &[u8], index: usize) -> Option<Vec<u8>> {
+    let location = u32::from_be_bytes(file.get(index * 4..index * 4 + 4)?.try_into().unwrap());
+    let start = (location >> 8) as usize * SECTOR_SIZE as usize;
+    let len = (location & 0xff) as usize * SECTOR_SIZE as usize;
+    if start == 0 || len == 0 {
+        return None;
+    }
+    file.get(start..start + len).map(<[u8]>::to_vec)
+}
+
+fn checksum(payload: &[u8]) -> u32 {
+    let mut crc = Crc::new();
+    crc.update(payload);
+    crc.sum()
+}
+
+/// Length, compression byte, checksum and compressed payload, padded to whole
+/// sectors.
+fn compress(payload: &[u8]) -> io::Result<Vec<u8>> {
+    let mut encoder = ZlibEncoder::new(Vec::new(), Compression::default());
+    encoder.write_all(payload)?;
+    let compressed = encoder.finish()?;
+    let mut data = ((compressed.len() + 5) as u32).to_be_bytes().to_vec();
+    data.push(ZLIB | CHECKSUM);
+    data.extend(checksum(payload).to_be_bytes());
+    data.extend(compressed);
+    data.resize(
+        (data.len() as u64).div_ceil(SECTOR_SIZE) as usize * SECTOR_SIZE as usize,
+        0,
+    );
+    Ok(data)
+}
+
+/// Payload of the sectors read for a chunk, checking its length and checksum.
+fn decompress(data: &[u8]) -> io::Result<Vec<u8>> {
+    let length = u32::from_be_bytes([data[0], data[1], data[2], data[3]]) as usize;
+    if length == 0 || length + 4 > data.len() {
+        return Err(invalid_data(format!("chunk has invalid length {}", length)));
+    }
+    let compression = data[4];
+    let (compressed, expected) = if compression & CHECKSUM != 0 {
+        if length < 5 {
+            return Err(invalid_data(format!("chunk has invalid length {}", length)));
+        }
+        let expected = u32::from_be_bytes(data[5..9].try_into().unwrap());
+        (&data[9..4 + length], Some(expected))
+    } else {
+        (&data[5..4 + length], None)
+    };
+    if compression & !CHECKSUM != ZLIB {
+        return Err(invalid_data(format!(
+            "unsupported chunk compression {}",
+            compression
+        )));
+    }
+
+    let mut payload = Vec::new();
+    ZlibDecoder::new(compressed)
+        .read_to_end(&mut payload)
+        .map_err(|e| invalid_data(format!("chunk can't be decompressed: {}", e)))?;
+    if expected.is_some_and(|expected| expected != checksum(&payload)) {
+        return Err(invalid_data("chunk doesn't match its checksum"));
+    }
+    Ok(payload)
+}
+
+/// Reading past the end of a file means it was cut off, which is corruption
+/// rather than an I/O error.
+fn truncated(error: io::Error, message: &str) -> io::Error {
+    if error.kind() == io::ErrorKind::UnexpectedEof {
+        invalid_data(message)
+    } else {
+        error
+    }
+}
+
+/// Region coordinates of a region file's path, `None` for other files.
+fn region_coordinates(path: &Path) -> Option<(i32, i32)> {
+    let name = path.file_name()?.to_str()?;
+    let mut parts = name.strip_prefix("r.")?.strip_suffix(".region")?.split('.');
+    let coordinates = (parts.next()?.parse().ok()?, parts.next()?.parse().ok()?);
+    parts.next().is_none().then_some(coordinates)
+}
+
+/// `path` with the corrupt extension added, numbered when a copy is already
+/// there.
+fn unused_backup(path: &Path) -> PathBuf {
+    let mut number = 1;
+    loop {
+        let mut name = path.file_name().unwrap_or_default().to_os_string();
+        if number > 1 {
+            name.push(format!(".{}", number));
+        }
+        name.push(format!(".{}", CORRUPT_EXTENSION));
+        let backup = path.with_file_name(name);
+        if !backup.exists() {
+            return backup;
+        }
+        number += 1;
+    }
+}
+
+/// Writes `bytes` to a temporary file next to `path` and flushes it to disk.
+fn write_temp(path: &Path, bytes: &[u8]) -> io::Result<PathBuf> {
+    let mut name = path.file_name().unwrap_or_default().to_os_string();
+    name.push(".tmp");
+    let temp = path.with_file_name(name);
+    let mut file = File::create(&temp)?;
+    file.write_all(bytes)?;
+    file.sync_all()?;
+    Ok(temp)
+}
+
+/// Replaces the file at `path`. A crash leaves either the old or the new
+/// file, never a partly written one.
+pub fn write_atomic(path: &Path, bytes: &[u8]) -> io::Result<()> {
+    fs::rename(write_temp(path, bytes)?, path)
+}
+
+fn encode_journal(chunks: &[SavedChunk], level: &str) -> Vec<u8> {
+    let mut bytes = JOURNAL_MAGIC.to_vec();
+    bytes.extend((chunks.len() as u32).to_be_bytes());
+    for chunk in chunks {
+        bytes.extend(chunk.chunk_x.to_be_bytes());
+        bytes.extend(chunk.chunk_z.to_be_bytes());
+        bytes.extend((chunk.payload.len() as u32).to_be_bytes());
+        bytes.extend(&chunk.payload);
+    }
+    bytes.extend((level.len() as u32).to_be_bytes());
+    bytes.extend(level.as_bytes());
+    bytes
+}
+
+fn decode_journal(bytes: &[u8]) -> io::Result<(Vec<SavedChunk>, String)> {
+    let mut reader = JournalReader {
+        bytes: bytes
+            .strip_prefix(JOURNAL_MAGIC)
+            .ok_or_else(|| invalid_data("journal has no header"))?,
+    };
+    let count = reader.u32()?;
+    let mut chunks = Vec::new();
+    for _ in 0..count {
+        let chunk_x = reader.u32()? as i32;
+        let chunk_z = reader.u32()? as i32;
+        let len = reader.u32()? as usize;
+        chunks.push(SavedChunk {
+            chunk_x,
+            chunk_z,
+            payload: reader.take(len)?.to_vec(),
+        });
+    }
+    let len = reader.u32()? as usize;
+    let level = String::from_utf8(reader.take(len)?.to_vec())
+        .map_err(|_| invalid_data("journal has invalid level metadata"))?;
+    Ok((chunks, level))
+}
+
+struct JournalReader<'a> {
+    bytes: &'a [u8],
+}
+
+impl<'a> JournalReader<'a> {
+    fn take(&mut self, len: usize) -> io::Result<&'a [u8]> {
+        if len > self.bytes.len() {
+            return Err(invalid_data("journal ends early"));
+        }
+        let (taken, rest) = self.bytes.split_at(len);
+        self.bytes = rest;
+        Ok(taken)
+    }
+
+    fn u32(&mut self) -> io::Result<u32> {
+        Ok(u32::from_be_bytes(self.take(4)?.try_into().unwrap()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::plugins::terrain::terrain::{BlockType, ChunkGrid};
+
+    /// Empty world folder in the system's temporary folder.
+    fn world_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("region-{}-{}", name, std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        dir
+    }
+
+    /// Storage with the chunk at `0`, `0` saved in it.
+    fn storage(dir: &Path) -> Arc<RegionStorage> {
+        let storage = WorldStorage::open(dir.to_path_buf(), &PersistenceSettings::default())
+            .0
+            .unwrap();
+        let mut chunk = Chunk::new(0, 0);
+        chunk.grid.set(IVec3::new(1, 2, 3), BlockType::Gold);
+        storage
+            .write_chunks(&[SavedChunk {
+                chunk_x: 0,
+                chunk_z: 0,
+                payload: chunk_format::encode(&chunk.grid, &storage.registry),
+            }])
+            .unwrap();
+        storage
+    }
+
+    fn loaded(storage: &RegionStorage) -> io::Result<Option<ChunkGrid>> {
+        storage
+            .load_chunk(0, 0)
+            .map(|chunk| chunk.map(|chunk| chunk.grid))
+    }
+
+    /// Changes the region file of the saved chunk and drops the cached table.
+    fn damage(storage: &RegionStorage, change: impl FnOnce(&mut Vec<u8>)) {
+        let path = storage.region_path(0, 0);
+        let mut file = fs::read(&path).unwrap();
+        change(&mut file);
+        fs::write(&path, file).unwrap();
+        storage.tables.lock().unwrap().clear();
+    }
+
+    fn backups(storage: &RegionStorage) -> Vec<String> {
+        let mut names = fs::read_dir(&storage.region_dir)
+            .unwrap()
+            .map(|entry| entry.unwrap().file_name().into_string().unwrap())
+            .filter(|name| name.ends_with(CORRUPT_EXTENSION))
+            .collect::<Vec<_>>();
+        names.sort();
+        names
+    }
+
+    #[test]
+    fn decompresses_what_it_compressed() {
+        let payload = (0..10000).map(|i| (i % 251) as u8).collect::<Vec<_>>();
+        let data = compress(&payload).unwrap();
+        assert_eq!(data.len() as u64 % SECTOR_SIZE, 0);
+        assert_eq!(decompress(&data).unwrap(), payload);
+    }
+
+    #[test]
+    fn rejects_damaged_payloads() {
+        let payload = vec![7u8; 5000];
+        let data = compress(&payload).unwrap();
+        let length = u32::from_be_bytes(data[..4].try_into().unwrap());
+
+        let mut cut = data.clone();
+        cut[..4].copy_from_slice(&(length - 4).to_be_bytes());
+        let error = decompress(&cut).unwrap_err();
+        assert!(error.to_string().starts_with("chunk can't be decompressed"));
+
+        let mut long = data.clone();
+        long[..4].copy_from_slice(&(data.len() as u32).to_be_bytes());
+        assert_eq!(
+            decompress(&long).unwrap_err().kind(),
+            io::ErrorKind::InvalidData
+        );
+
+        let mut wrong_checksum = data.clone();
+        wrong_checksum[5] ^= 0xff;
+        let error = decompress(&wrong_checksum).unwrap_err();
+        assert_eq!(error.to_string(), "chunk doesn't match its checksum");
+    }
+
+    #[test]
+    fn loads_saved_chunks() {
+        let dir = world_dir("load");
+        let grid = loaded(&storage(&dir)).unwrap().unwrap();
+        assert_eq!(grid.get(IVec3::new(1, 2, 3)), BlockType::Gold);
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn quarantines_chunks_that_fail_their_checksum() {
+        let dir = world_dir("checksum");
+        let storage = storage(&dir);
+        // The checksum is the first thing after the table.
+        damage(&storage, |file| file[SECTOR_SIZE as usize + 5] ^= 0xff);
+
+        assert!(loaded(&storage).unwrap().is_none());
+        assert_eq!(backups(&storage), ["c.0.0.chunk.corrupt"]);
+        // The chunk is gone from the region and isn't quarantined again.
+        assert_eq!(storage.read(0, 0).unwrap(), None);
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn quarantines_chunks_cut_off_by_the_end_of_the_file() {
+        let dir = world_dir("truncated");
+        let storage = storage(&dir);
+        damage(&storage, |file| file.truncate(SECTOR_SIZE as usize + 100));
+
+        assert!(loaded(&storage).unwrap().is_none());
+        assert_eq!(backups(&storage), ["c.0.0.chunk.corrupt"]);
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn quarantines_regions_shorter_than_their_header() {
+        let dir = world_dir("header");
+        let storage = storage(&dir);
+        damage(&storage, |file| file.truncate(100));
+
+        assert!(loaded(&storage).unwrap().is_none());
+        assert_eq!(backups(&storage), ["r.0.0.region.corrupt"]);
+        assert!(!storage.region_path(0, 0).exists());
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn keeps_chunks_with_unregistered_blocks() {
+        let dir = world_dir("unregistered");
+        let storage = storage(&dir);
+        let mut registry = storage.registry.clone();
+        registry.blocks.truncate(1);
+        let storage = RegionStorage {
+            registry,
+            ..RegionStorage::open_read_only(dir.clone()).unwrap()
+        };
+
+        let error = loaded(&storage).err().unwrap();
+        assert_eq!(error.kind(), io::ErrorKind::Unsupported);
+        assert!(backups(&storage).is_empty());
+        assert!(storage.read(0, 0).unwrap().is_some());
+        fs::remove_dir_all(dir).unwrap();
+    }
+}
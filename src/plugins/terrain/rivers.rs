@@ -0,0 +1,246 @@
+use std::sync::Arc;
+
+use bevy::{
+    prelude::{IVec2, Resource},
+    utils::{HashMap, HashSet},
+};
+use serde::Deserialize;
+
+use crate::utils::{
+    random::{chunk_seed, ChunkRng},
+    region_cache::{regions_covering, RegionCache},
+};
+
+use super::terrain::{RENDER_AREA, SEA_LEVEL};
+
+pub const RIVERS_PATH: &str = "assets/terrain/rivers.ron";
+
+const RIVER_SALT: u32 = 40;
+
+const DIRECTIONS: [IVec2; 8] = [
+    IVec2::new(1, 0),
+    IVec2::new(-1, 0),
+    IVec2::new(0, 1),
+    IVec2::new(0, -1),
+    IVec2::new(1, 1),
+    IVec2::new(1, -1),
+    IVec2::new(-1, 1),
+    IVec2::new(-1, -1),
+];
+
+#[derive(Resource, Clone, Debug, Deserialize)]
+pub struct RiverSettings {
+    pub enabled: bool,
+    /// Combined with the world's seed by [`stage_seed`](crate::utils::random::stage_seed).
+    pub seed: u32,
+    /// Rivers are laid out per region of this many blocks. A river never gets
+    /// further than one region away from the region it starts in.
+    pub region_size: i32,
+    /// Random spots tried as river sources in every region.
+    pub sources_per_region: u32,
+    /// Only spots at least this high become sources.
+    pub source_min_height: i32,
+    pub max_length: u32,
+    /// Half width of the water channel at the source.
+    pub width: f32,
+    /// How much the half width grows every 100 blocks.
+    pub width_growth: f32,
+    pub depth: i32,
+    /// Width of the sloped banks next to the channel.
+    pub bank_width: f32,
+}
+
+impl Default for RiverSettings {
+    fn default() -> Self {
+        Self {
+            enabled: true,
+            seed: 10,
+            region_size: 256,
+            sources_per_region: 6,
+            source_min_height: 75,
+            max_length: 240,
+            width: 1.0,
+            width_growth: 1.5,
+            depth: 2,
+            bank_width: 4.0,
+        }
+    }
+}
+
+/// Changes a river makes to a column.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct RiverCell {
+    /// New terrain height of the column.
+    pub bed: i32,
+    /// Height of the water surface, `None` on the banks.
+    pub water: Option<i32>,
+}
+
+impl RiverCell {
+    fn merge(self, other: RiverCell) -> RiverCell {
+        RiverCell {
+            bed: self.bed.min(other.bed),
+            water: self.water.max(other.water),
+        }
+    }
+}
+
+type RiverCells = HashMap<(i32, i32), RiverCell>;
+
+/// Rivers traced over the terrain, computed region by region and shared by all
+/// chunk tasks. Paths only depend on the seed and the heightmap, so every chunk
+/// a river flows through sees the same river.
+pub struct RiverNetwork {
+    settings: RiverSettings,
+    regions: RegionCache<RiverCells>,
+}
+
+#[derive(Resource, Clone)]
+pub struct RiverCache(pub Option<Arc<RiverNetwork>>);
+
+impl RiverCache {
+    pub fn new(settings: RiverSettings) -> Self {
+        Self(settings.enabled.then(|| {
+            // Columns look up the rivers of the 3×3 regions around their own.
+            let capacity = regions_covering(RENDER_AREA, settings.region_size, 1);
+            Arc::new(RiverNetwork {
+                settings,
+                regions: RegionCache::new(capacity),
+            })
+        }))
+    }
+}
+
+impl RiverNetwork {
+    /// Returns how rivers change the column at `x`, `z`. `base` returns the
+    /// terrain height before rivers and must always be the same function.
+    pub fn column(&self, x: i32, z: i32, base: &impl Fn(i32, i32) -> i32) -> Option<RiverCell> {
+        let region_x = x.div_euclid(self.settings.region_size);
+        let region_z = z.div_euclid(self.settings.region_size);
+
+        let mut result: Option<RiverCell> = None;
+        for source_x in region_x - 1..=region_x + 1 {
+            for source_z in region_z - 1..=region_z + 1 {
+                let cells = self.regions.get_or_compute(source_x, source_z, || {
+                    self.trace_region(source_x, source_z, base)
+                });
+                if let Some(cell) = cells.get(&(x, z)) {
+                    result = Some(result.map_or(*cell, |r| r.merge(*cell)));
+                }
+            }
+        }
+        result
+    }
+
+    fn trace_region(
+        &self,
+        region_x: i32,
+        region_z: i32,
+        base: &impl Fn(i32, i32) -> i32,
+    ) -> RiverCells {
+        let settings = &self.settings;
+        let mut rng = ChunkRng::new(chunk_seed(settings.seed, RIVER_SALT, region_x, region_z));
+        let mut cells = RiverCells::default();
+
+        for _ in 0..settings.sources_per_region {
+            let source = IVec2::new(
+                region_x * settings.region_size + rng.range_i32(0, settings.region_size - 1),
+                region_z * settings.region_size + rng.range_i32(0, settings.region_size - 1),
+            );
+            if base(source.x, source.y) < settings.source_min_height {
+                continue;
+            }
+            let path = trace_path(source, settings, base);
+            carve_path(&path, settings, base, &mut cells);
+        }
+        cells
+    }
+}
+
+/// Follows the steepest descent from `source` until the river reaches the sea.
+/// Water keeps flowing straight through small bumps, cutting a channel into
+/// them. Returns the path with the water level at every point.
+fn trace_path(
+    source: IVec2,
+    settings: &RiverSettings,
+    base: &impl Fn(i32, i32) -> i32,
+) -> Vec<(IVec2, i32)> {
+    let max_length = settings
+        .max_length
+        .min((settings.region_size as f32 - settings.bank_width * 2.0) as u32);
+    let mut path = Vec::new();
+    let mut visited = HashSet::new();
+    let mut position = source;
+    let mut level = base(source.x, source.y);
+    let mut direction: Option<IVec2> = None;
+
+    for _ in 0..max_length {
+        path.push((position, level));
+        visited.insert(position);
+        if level <= SEA_LEVEL {
+            break;
+        }
+
+        let height = base(position.x, position.y);
+        let lowest = DIRECTIONS
+            .iter()
+            .map(|d| position + *d)
+            .filter(|p| !visited.contains(p))
+            .map(|p| (p, base(p.x, p.y)))
+            .min_by_key(|(_, h)| *h);
+
+        let next = match (lowest, direction) {
+            (Some((p, h)), _) if h < height => p,
+            (_, Some(d)) if !visited.contains(&(position + d)) => position + d,
+            (Some((p, _)), None) => p,
+            _ => break,
+        };
+        direction = Some(next - position);
+        position = next;
+        level = level.min(base(position.x, position.y));
+    }
+    path
+}
+
+fn carve_path(
+    path: &[(IVec2, i32)],
+    settings: &RiverSettings,
+    base: &impl Fn(i32, i32) -> i32,
+    cells: &mut RiverCells,
+) {
+    for (step, (position, level)) in path.iter().enumerate() {
+        let width = settings.width + settings.width_growth * step as f32 / 100.0;
+        let water = level - 1;
+        let reach = (width + settings.bank_width).ceil() as i32;
+
+        for dx in -reach..=reach {
+            for dz in -reach..=reach {
+                let distance = ((dx * dx + dz * dz) as f32).sqrt();
+                let x = position.x + dx;
+                let z = position.y + dz;
+
+                let cell = if distance <= width {
+                    RiverCell {
+                        bed: water - settings.depth,
+                        water: Some(water),
+                    }
+                } else if distance <= width + settings.bank_width {
+                    let original = base(x, z);
+                    let t = (distance - width) / settings.bank_width;
+                    let bed = water + (t * (original - water) as f32).round() as i32;
+                    if bed >= original {
+                        continue;
+                    }
+                    RiverCell { bed, water: None }
+                } else {
+                    continue;
+                };
+
+                cells
+                    .entry((x, z))
+                    .and_modify(|existing| *existing = existing.merge(cell))
+                    .or_insert(cell);
+            }
+        }
+    }
+}
@@ -0,0 +1,396 @@
+use std::{fs, io, path::Path, sync::Arc};
+
+use bevy::prelude::{warn, IVec3, Resource};
+use serde::Deserialize;
+
+use crate::utils::nbt::{self, invalid_data, Tag};
+
+use super::{
+    block_names::BlockNames,
+    pipeline::{chunk_id, LoadedChunks},
+    structures::Rotation,
+    terrain::{BlockType, ChunkGrid, CHUNK_SIZE},
+    vox,
+};
+
+pub const SCHEMATICS_PATH: &str = "assets/terrain/schematics.ron";
+
+/// Largest schematic volume that is loaded, 256 blocks in every direction.
+const MAX_BLOCKS: usize = 1 << 24;
+
+/// A build imported from a Sponge schematic, a vanilla structure file or a
+/// MagicaVoxel model.
+#[derive(Clone, Debug)]
+pub struct Schematic {
+    pub size: IVec3,
+    /// Indexed by `x + z * size.x + y * size.x * size.z`. `None` leaves the
+    /// world untouched, like structure voids.
+    pub blocks: Vec<Option<BlockType>>,
+}
+
+impl Schematic {
+    /// Schematic of `size` without any blocks. Sizes come straight from
+    /// files, so negative ones and ones over [`MAX_BLOCKS`] are rejected.
+    fn empty(size: IVec3) -> io::Result<Self> {
+        let volume = size
+            .to_array()
+            .into_iter()
+            .try_fold(1usize, |volume, side| {
+                volume.checked_mul(usize::try_from(side).ok()?)
+            })
+            .filter(|volume| *volume <= MAX_BLOCKS)
+            .ok_or_else(|| invalid_data(format!("invalid schematic size {}", size)))?;
+        Ok(Self {
+            size,
+            blocks: vec![None; volume],
+        })
+    }
+
+    fn index(&self, pos: IVec3) -> usize {
+        (pos.x + pos.z * self.size.x + pos.y * self.size.x * self.size.z) as usize
+    }
+
+    pub fn get(&self, pos: IVec3) -> Option<BlockType> {
+        if pos.cmplt(IVec3::ZERO).any() || pos.cmpge(self.size).any() {
+            return None;
+        }
+        self.blocks[self.index(pos)]
+    }
+
+    /// Copies the blocks of the loaded chunks in the box starting at `min`.
+    /// Air and positions in chunks that aren't generated yet are left empty.
+    pub fn capture(loaded_chunks: &LoadedChunks, min: IVec3, size: IVec3) -> io::Result<Self> {
+        let chunk_size = CHUNK_SIZE as i32;
+        let mut schematic = Self::empty(size)?;
+        for y in 0..size.y {
+            for z in 0..size.z {
+                for x in 0..size.x {
+                    let pos = min + IVec3::new(x, y, z);
+                    let Some(chunk) = loaded_chunks
+                        .chunks
+                        .get(&chunk_id(
+                            pos.x.div_euclid(chunk_size) * chunk_size,
+                            pos.z.div_euclid(chunk_size) * chunk_size,
+                        ))
+                        .and_then(|entry| entry.chunk.as_ref())
+                    else {
+                        continue;
+                    };
+                    let block = chunk.grid.get(pos);
+                    if block != BlockType::Air {
+                        let index = schematic.index(IVec3::new(x, y, z));
+                        schematic.blocks[index] = Some(block);
+                    }
+                }
+            }
+        }
+        Ok(schematic)
+    }
+
+    /// Reads a `.schem`, `.nbt` or `.vox` file, picking the format from the
+    /// extension.
+    pub fn load(path: impl AsRef<Path>, names: &BlockNames) -> io::Result<Self> {
+        let path = path.as_ref();
+        let bytes = fs::read(path)?;
+        match path.extension().and_then(|e| e.to_str()) {
+            Some("schem") => Self::from_sponge(&nbt::read(&bytes)?.1, names),
+            Some("nbt") => Self::from_structure(&nbt::read(&bytes)?.1, names),
+            Some("vox") => vox::read_vox(&bytes),
+            _ => Err(invalid_data(format!(
+                "{} is not a .schem, .nbt or .vox file",
+                path.display()
+            ))),
+        }
+    }
+
+    /// Sponge schematic, version 2 or 3.
+    pub fn from_sponge(root: &Tag, names: &BlockNames) -> io::Result<Self> {
+        // Version 3 wraps everything in a `Schematic` compound.
+        let schematic = root.get("Schematic").unwrap_or(root);
+        let dimension = |key: &str| {
+            schematic
+                .get(key)
+                .and_then(Tag::as_i64)
+                // Sizes are stored as signed shorts but are never negative.
+                .map(|value| value as u16 as i32)
+                .ok_or_else(|| invalid_data(format!("schematic has no {}", key)))
+        };
+        let size = IVec3::new(
+            dimension("Width")?,
+            dimension("Height")?,
+            dimension("Length")?,
+        );
+
+        let blocks = schematic.get("Blocks").unwrap_or(schematic);
+        let palette = blocks
+            .get("Palette")
+            .and_then(Tag::as_compound)
+            .ok_or_else(|| invalid_data("schematic has no palette"))?;
+        let data = blocks
+            .get("Data")
+            .or_else(|| blocks.get("BlockData"))
+            .and_then(Tag::as_byte_array)
+            .ok_or_else(|| invalid_data("schematic has no block data"))?;
+
+        // Palette ids are dense, every id below the palette's length is used
+        // once.
+        let mut palette_blocks = vec![None; palette.len()];
+        for (name, id) in palette {
+            let block = id
+                .as_i32()
+                .and_then(|id| usize::try_from(id).ok())
+                .and_then(|id| palette_blocks.get_mut(id))
+                .filter(|block| block.is_none())
+                .ok_or_else(|| invalid_data(format!("invalid palette id for {}", name)))?;
+            *block = Some(names.lookup(name));
+        }
+
+        let mut schematic = Self::empty(size)?;
+        let mut bytes = data.iter().map(|b| *b as u8);
+        for block in schematic.blocks.iter_mut() {
+            let id = read_varint(&mut bytes)?;
+            *block =
+                Some(palette_blocks.get(id).copied().flatten().ok_or_else(|| {
+                    invalid_data(format!("block id {} is not in the palette", id))
+                })?);
+        }
+        Ok(schematic)
+    }
+
+    /// Vanilla structure block file. Positions without a block stay untouched.
+    pub fn from_structure(root: &Tag, names: &BlockNames) -> io::Result<Self> {
+        let size = read_position(root.get("size"))?;
+
+        // Files with several palettes pick one at random in game, the first
+        // one is as good as any.
+        let palette = root
+            .get("palette")
+            .or_else(|| root.get("palettes")?.as_list()?.first())
+            .and_then(Tag::as_list)
+            .ok_or_else(|| invalid_data("structure has no palette"))?;
+        let palette = palette
+            .iter()
+            .map(|state| {
+                let name = state.get("Name").and_then(Tag::as_str).unwrap_or_default();
+                // Structure voids mark positions the structure doesn't change.
+                (name != "minecraft:structure_void").then(|| names.lookup(name))
+            })
+            .collect::<Vec<_>>();
+
+        let mut schematic = Self::empty(size)?;
+        let blocks = root
+            .get("blocks")
+            .and_then(Tag::as_list)
+            .ok_or_else(|| invalid_data("structure has no blocks"))?;
+        for block in blocks {
+            let pos = read_position(block.get("pos"))?;
+            let state = block
+                .get("state")
+                .and_then(Tag::as_i32)
+                .and_then(|state| usize::try_from(state).ok())
+                .and_then(|state| palette.get(state))
+                .ok_or_else(|| invalid_data("block state is not in the palette"))?;
+            if pos.cmplt(IVec3::ZERO).any() || pos.cmpge(size).any() {
+                return Err(invalid_data(format!(
+                    "block at {} is outside the structure",
+                    pos
+                )));
+            }
+            let index = schematic.index(pos);
+            schematic.blocks[index] = *state;
+        }
+        Ok(schematic)
+    }
+}
+
+fn read_position(tag: Option<&Tag>) -> io::Result<IVec3> {
+    let values = tag
+        .and_then(Tag::as_list)
+        .filter(|values| values.len() == 3)
+        .ok_or_else(|| invalid_data("position is not a list of three numbers"))?;
+    let value = |i: usize| {
+        values[i]
+            .as_i32()
+            .ok_or_else(|| invalid_data("position is not a list of three numbers"))
+    };
+    Ok(IVec3::new(value(0)?, value(1)?, value(2)?))
+}
+
+fn read_varint(bytes: &mut impl Iterator<Item = u8>) -> io::Result<usize> {
+    let mut value = 0usize;
+    for shift in (0..35).step_by(7) {
+        let byte = bytes
+            .next()
+            .ok_or_else(|| invalid_data("block data ends early"))?;
+        value |= ((byte & 0x7f) as usize) << shift;
+        if byte & 0x80 == 0 {
+            return Ok(value);
+        }
+    }
+    Err(invalid_data("varint is too long"))
+}
+
+/// Where to paste a schematic into the world.
+#[derive(Clone, Debug, Deserialize)]
+pub struct SchematicPlacement {
+    pub path: String,
+    /// World position of the schematic's minimum corner after rotating it.
+    pub position: [i32; 3],
+    #[serde(default)]
+    pub rotation: Rotation,
+}
+
+#[derive(Clone, Debug, Default, Deserialize)]
+pub struct SchematicSettings {
+    pub placements: Vec<SchematicPlacement>,
+}
+
+/// Schematics pasted into the world while chunks are generated, loaded from
+/// [`SCHEMATICS_PATH`].
+#[derive(Resource, Clone, Default)]
+pub struct PastedSchematics(pub Arc<Vec<(SchematicPlacement, Schematic)>>);
+
+impl PastedSchematics {
+    /// Loads every placed schematic. Files that can't be read are skipped with
+    /// a warning.
+    pub fn load(settings: SchematicSettings, names: &BlockNames) -> Self {
+        let schematics = settings
+            .placements
+            .into_iter()
+            .filter_map(|placement| match Schematic::load(&placement.path, names) {
+                Ok(schematic) => Some((placement, schematic)),
+                Err(e) => {
+                    warn!("Failed to load schematic {}: {}", placement.path, e);
+                    None
+                }
+            })
+            .collect();
+        Self(Arc::new(schematics))
+    }
+
+    /// Pastes the parts of every schematic that overlap `grid`.
+    pub fn paste_into(&self, grid: &mut ChunkGrid) {
+        for (placement, schematic) in self.0.iter() {
+            paste(
+                grid,
+                schematic,
+                IVec3::from(placement.position),
+                placement.rotation,
+            );
+        }
+    }
+}
+
+/// Writes `schematic` into `grid` with its minimum corner at `origin` after
+/// rotating it. Only the blocks inside the grid are written.
+pub fn paste(grid: &mut ChunkGrid, schematic: &Schematic, origin: IVec3, rotation: Rotation) {
+    let max = origin + rotation.size(schematic.size);
+    if origin.cmpge(grid.origin + grid.size).any() || max.cmple(grid.origin).any() {
+        return;
+    }
+
+    for y in 0..schematic.size.y {
+        for z in 0..schematic.size.z {
+            for x in 0..schematic.size.x {
+                let pos = IVec3::new(x, y, z);
+                if let Some(block) = schematic.get(pos) {
+                    grid.set(origin + rotation.apply(pos, schematic.size), block);
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use bevy::utils::HashMap;
+
+    use super::*;
+
+    fn fixture_path(name: &str) -> std::path::PathBuf {
+        Path::new(env!("CARGO_MANIFEST_DIR"))
+            .join("tests/fixtures")
+            .join(name)
+    }
+
+    fn fixture(name: &str) -> io::Result<Schematic> {
+        Schematic::load(fixture_path(name), &BlockNames::default())
+    }
+
+    /// `small.schem` is a 3×2×2 Sponge v2 schematic: a stone floor with one
+    /// dirt block, whose palette id needs a two byte varint, and an upright
+    /// oak log on top. Unused note block states fill the palette up to dirt.
+    #[test]
+    fn reads_sponge_schematics() {
+        let schematic = fixture("small.schem").unwrap();
+        assert_eq!(schematic.size, IVec3::new(3, 2, 2));
+        let get = |x, y, z| schematic.get(IVec3::new(x, y, z));
+        assert_eq!(get(0, 0, 0), Some(BlockType::Stone));
+        assert_eq!(get(1, 0, 1), Some(BlockType::Dirt));
+        assert_eq!(get(2, 0, 1), Some(BlockType::Stone));
+        assert_eq!(get(1, 1, 0), Some(BlockType::Log));
+        assert_eq!(get(0, 1, 1), Some(BlockType::Air));
+        assert_eq!(get(3, 0, 0), None);
+    }
+
+    /// `small.nbt` is a 2×2×2 structure: a cobblestone floor, a log with
+    /// block states and a structure void, with one position left out.
+    #[test]
+    fn reads_structure_files() {
+        let schematic = fixture("small.nbt").unwrap();
+        assert_eq!(schematic.size, IVec3::splat(2));
+        let get = |x, y, z| schematic.get(IVec3::new(x, y, z));
+        for (x, z) in [(0, 0), (1, 0), (0, 1), (1, 1)] {
+            assert_eq!(get(x, 0, z), Some(BlockType::Cobblestone));
+        }
+        assert_eq!(get(0, 1, 0), Some(BlockType::Log));
+        assert_eq!(get(1, 1, 1), None);
+        assert_eq!(get(1, 1, 0), None);
+    }
+
+    #[test]
+    fn rejects_oversized_schematics() {
+        let dimension = Tag::Short(-1);
+        let root = Tag::Compound(HashMap::from_iter([
+            ("Width".to_string(), dimension.clone()),
+            ("Height".to_string(), dimension.clone()),
+            ("Length".to_string(), dimension),
+            ("Palette".to_string(), Tag::Compound(HashMap::new())),
+            ("BlockData".to_string(), Tag::ByteArray(Vec::new())),
+        ]));
+        let error = Schematic::from_sponge(&root, &BlockNames::default()).unwrap_err();
+        assert_eq!(error.kind(), io::ErrorKind::InvalidData);
+
+        assert!(Schematic::empty(IVec3::new(4, -1, 4)).is_err());
+        assert!(Schematic::empty(IVec3::splat(i32::MAX)).is_err());
+        assert_eq!(
+            Schematic::empty(IVec3::splat(256)).unwrap().blocks.len(),
+            MAX_BLOCKS
+        );
+    }
+
+    #[test]
+    fn rejects_truncated_block_data() {
+        let (_, mut root) = nbt::read(&fs::read(fixture_path("small.schem")).unwrap()).unwrap();
+        if let Tag::Compound(children) = &mut root {
+            children.insert("BlockData".to_string(), Tag::ByteArray(vec![1, 1]));
+        }
+        let error = Schematic::from_sponge(&root, &BlockNames::default()).unwrap_err();
+        assert_eq!(error.to_string(), "block data ends early");
+    }
+
+    #[test]
+    fn rejects_sparse_palettes() {
+        let (_, mut root) = nbt::read(&fs::read(fixture_path("small.schem")).unwrap()).unwrap();
+        let Tag::Compound(children) = &mut root else {
+            panic!("small.schem has no root compound");
+        };
+        let Some(Tag::Compound(palette)) = children.get_mut("Palette") else {
+            panic!("small.schem has no palette");
+        };
+        let len = palette.len() as i32;
+        palette.insert("minecraft:sand".to_string(), Tag::Int(len + 1000));
+        let error = Schematic::from_sponge(&root, &BlockNames::default()).unwrap_err();
+        assert_eq!(error.to_string(), "invalid palette id for minecraft:sand");
+    }
+}
@@ -0,0 +1,688 @@
+use std::{collections::VecDeque, sync::Arc};
+
+use bevy::{
+    prelude::{warn, IVec3, Resource},
+    utils::HashMap,
+};
+use serde::Deserialize;
+
+use crate::utils::{
+    random::{chunk_seed, ChunkRng},
+    region_cache::{regions_covering, RegionCache},
+};
+
+use super::terrain::{BlockType, ChunkGrid, CHUNK_SIZE, RENDER_AREA, SEA_LEVEL};
+
+pub const STRUCTURES_PATH: &str = "assets/terrain/structures.ron";
+
+const STRUCTURE_SALT: u32 = 50;
+
+/// Direction a connector points to, seen from the piece it belongs to.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Deserialize)]
+pub enum Facing {
+    North,
+    East,
+    South,
+    West,
+    Up,
+    Down,
+}
+
+impl Facing {
+    pub fn offset(self) -> IVec3 {
+        match self {
+            Facing::North => IVec3::NEG_Z,
+            Facing::East => IVec3::X,
+            Facing::South => IVec3::Z,
+            Facing::West => IVec3::NEG_X,
+            Facing::Up => IVec3::Y,
+            Facing::Down => IVec3::NEG_Y,
+        }
+    }
+
+    pub fn opposite(self) -> Facing {
+        match self {
+            Facing::North => Facing::South,
+            Facing::East => Facing::West,
+            Facing::South => Facing::North,
+            Facing::West => Facing::East,
+            Facing::Up => Facing::Down,
+            Facing::Down => Facing::Up,
+        }
+    }
+
+    pub fn rotate(self, rotation: Rotation) -> Facing {
+        let mut facing = self;
+        for _ in 0..rotation.quarter_turns() {
+            facing = match facing {
+                Facing::North => Facing::East,
+                Facing::East => Facing::South,
+                Facing::South => Facing::West,
+                Facing::West => Facing::North,
+                vertical => vertical,
+            };
+        }
+        facing
+    }
+}
+
+/// Rotation of a piece around the vertical axis, clockwise seen from above.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Deserialize)]
+pub enum Rotation {
+    #[default]
+    None,
+    Clockwise90,
+    Clockwise180,
+    CounterClockwise90,
+}
+
+impl Rotation {
+    pub const ALL: [Rotation; 4] = [
+        Rotation::None,
+        Rotation::Clockwise90,
+        Rotation::Clockwise180,
+        Rotation::CounterClockwise90,
+    ];
+
+    pub fn quarter_turns(self) -> usize {
+        match self {
+            Rotation::None => 0,
+            Rotation::Clockwise90 => 1,
+            Rotation::Clockwise180 => 2,
+            Rotation::CounterClockwise90 => 3,
+        }
+    }
+
+    /// Size of a box of `size` blocks after rotating it.
+    pub fn size(self, size: IVec3) -> IVec3 {
+        match self {
+            Rotation::None | Rotation::Clockwise180 => size,
+            Rotation::Clockwise90 | Rotation::CounterClockwise90 => {
+                IVec3::new(size.z, size.y, size.x)
+            }
+        }
+    }
+
+    /// Moves a position inside a box of `size` blocks to where it ends up when
+    /// the box is rotated in place, keeping its minimum corner at the origin.
+    pub fn apply(self, pos: IVec3, size: IVec3) -> IVec3 {
+        match self {
+            Rotation::None => pos,
+            Rotation::Clockwise90 => IVec3::new(size.z - 1 - pos.z, pos.y, pos.x),
+            Rotation::Clockwise180 => IVec3::new(size.x - 1 - pos.x, pos.y, size.z - 1 - pos.z),
+            Rotation::CounterClockwise90 => IVec3::new(pos.z, pos.y, size.x - 1 - pos.x),
+        }
+    }
+}
+
+/// Box of blocks inside a piece, both corners included.
+#[derive(Clone, Debug, Deserialize)]
+pub struct BlockBox {
+    pub min: [i32; 3],
+    pub max: [i32; 3],
+    pub block: BlockType,
+}
+
+/// Point where another piece can be attached. Connectors sit on the outer
+/// layer of a piece and the attached piece starts right next to them.
+#[derive(Clone, Debug, Deserialize)]
+pub struct Connector {
+    pub position: [i32; 3],
+    pub facing: Facing,
+    /// Pool the attached piece is picked from. Connectors without a pool only
+    /// accept pieces and never grow the structure.
+    pub pool: Option<String>,
+    /// Name other connectors can target.
+    #[serde(default)]
+    pub name: Option<String>,
+    /// Name of the connector the attached piece has to be attached with. Any
+    /// connector facing the right way fits when this is `None`.
+    #[serde(default)]
+    pub target: Option<String>,
+}
+
+/// Connector of a placed piece that still waits for a piece to be attached.
+struct OpenConnector {
+    pos: IVec3,
+    facing: Facing,
+    pool: String,
+    target: Option<String>,
+    depth: u32,
+}
+
+#[derive(Clone, Debug, Deserialize)]
+pub struct StructurePiece {
+    pub name: String,
+    pub size: [i32; 3],
+    /// Filled in order, so later boxes overwrite earlier ones. Air boxes hollow
+    /// out the terrain the piece is placed in.
+    pub boxes: Vec<BlockBox>,
+    pub connectors: Vec<Connector>,
+    /// Fills the columns below the piece down to the terrain.
+    pub foundation: Option<BlockType>,
+}
+
+/// How pieces of a pool are placed vertically.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Deserialize)]
+pub enum Projection {
+    /// Pieces line up with the connector they are attached to.
+    #[default]
+    Rigid,
+    /// Pieces are moved so their bottom layer replaces the terrain surface,
+    /// for roads and houses that follow the ground.
+    TerrainMatching,
+}
+
+#[derive(Clone, Debug, Deserialize)]
+pub struct PoolEntry {
+    pub piece: String,
+    pub weight: u32,
+}
+
+#[derive(Clone, Debug, Deserialize)]
+pub struct StructurePool {
+    pub name: String,
+    pub pieces: Vec<PoolEntry>,
+    /// Piece used once a branch reaches the structure's depth limit, to close
+    /// it off. Branches simply end when there is none.
+    pub fallback: Option<String>,
+    pub projection: Projection,
+}
+
+#[derive(Clone, Copy, Debug, Deserialize)]
+pub enum StructurePlacement {
+    /// The start piece sits on the terrain, never below the sea level.
+    Surface,
+    /// The start piece is buried at a random height in this range.
+    Underground { min_y: i32, max_y: i32 },
+}
+
+/// A kind of structure and where it may start. The world is split into cells
+/// of `spacing` chunks and every cell rolls at most one start.
+#[derive(Clone, Debug, Deserialize)]
+pub struct StructureKind {
+    pub name: String,
+    pub start_pool: String,
+    pub spacing: i32,
+    /// Chunks at the end of every cell where no start is placed, keeping starts
+    /// of neighbouring cells apart.
+    pub separation: i32,
+    pub chance: f32,
+    /// How many pieces deep branches may grow from the start piece.
+    pub max_depth: u32,
+    /// No piece may reach further than this many blocks from the start.
+    pub max_distance: i32,
+    pub placement: StructurePlacement,
+}
+
+/// Structure templates loaded from [`STRUCTURES_PATH`]. There are no built-in
+/// structures, so nothing is placed without the file.
+#[derive(Resource, Clone, Debug, Deserialize)]
+pub struct StructureSettings {
+    pub enabled: bool,
+    /// Combined with the world's seed by [`stage_seed`](crate::utils::random::stage_seed).
+    pub seed: u32,
+    pub pieces: Vec<StructurePiece>,
+    pub pools: Vec<StructurePool>,
+    pub structures: Vec<StructureKind>,
+}
+
+impl Default for StructureSettings {
+    fn default() -> Self {
+        Self {
+            enabled: true,
+            seed: 10,
+            pieces: Vec::new(),
+            pools: Vec::new(),
+            structures: Vec::new(),
+        }
+    }
+}
+
+/// A piece placed in the world.
+#[derive(Clone, Copy, Debug)]
+pub struct PlacedPiece {
+    pub piece: usize,
+    pub rotation: Rotation,
+    pub min: IVec3,
+    /// Size after rotation.
+    pub size: IVec3,
+}
+
+impl PlacedPiece {
+    pub fn max(&self) -> IVec3 {
+        self.min + self.size
+    }
+
+    pub fn intersects(&self, other: &PlacedPiece) -> bool {
+        self.min.cmplt(other.max()).all() && other.min.cmplt(self.max()).all()
+    }
+
+    fn intersects_grid(&self, grid: &ChunkGrid) -> bool {
+        self.min.cmplt(grid.origin + grid.size).all() && grid.origin.cmplt(self.max()).all()
+    }
+}
+
+/// All pieces of one structure. Pieces never overlap each other.
+#[derive(Clone, Debug)]
+pub struct StructureStart {
+    pub kind: usize,
+    pub pieces: Vec<PlacedPiece>,
+}
+
+/// Structure layouts, computed once per start cell and shared by all chunk
+/// tasks. A layout only depends on the seed, the cell and the terrain height,
+/// so every chunk a structure overlaps stamps the same pieces.
+pub struct StructureLayouts {
+    settings: StructureSettings,
+    pieces: HashMap<String, usize>,
+    pools: HashMap<String, usize>,
+    /// One cache per structure kind, keyed by start cell.
+    starts: Vec<RegionCache<Option<StructureStart>>>,
+}
+
+#[derive(Resource, Clone)]
+pub struct StructureCache(pub Option<Arc<StructureLayouts>>);
+
+impl StructureCache {
+    pub fn new(settings: StructureSettings) -> Self {
+        if !settings.enabled || settings.structures.is_empty() {
+            return Self(None);
+        }
+        Self(Some(Arc::new(StructureLayouts::new(settings))))
+    }
+}
+
+impl StructureLayouts {
+    pub fn new(settings: StructureSettings) -> Self {
+        let pieces = settings
+            .pieces
+            .iter()
+            .enumerate()
+            .map(|(index, piece)| (piece.name.clone(), index))
+            .collect::<HashMap<_, _>>();
+        let pools = settings
+            .pools
+            .iter()
+            .enumerate()
+            .map(|(index, pool)| (pool.name.clone(), index))
+            .collect::<HashMap<_, _>>();
+
+        for pool in &settings.pools {
+            for name in pool.pieces.iter().map(|e| &e.piece).chain(&pool.fallback) {
+                if !pieces.contains_key(name) {
+                    warn!("Structure pool {} uses unknown piece {}", pool.name, name);
+                }
+            }
+        }
+        for kind in &settings.structures {
+            if !pools.contains_key(&kind.start_pool) {
+                warn!(
+                    "Structure {} uses unknown pool {}",
+                    kind.name, kind.start_pool
+                );
+            }
+        }
+
+        Self {
+            // Keeps the start cells of every chunk in view cached, along with
+            // the cells `max_distance` away that chunks at the edge look into.
+            starts: settings
+                .structures
+                .iter()
+                .map(|kind| {
+                    let cell_size = kind.spacing.max(1) * CHUNK_SIZE as i32;
+                    let margin = (kind.max_distance.max(0) + cell_size - 1) / cell_size;
+                    RegionCache::new(regions_covering(RENDER_AREA, cell_size, margin))
+                })
+                .collect(),
+            settings,
+            pieces,
+            pools,
+        }
+    }
+
+    pub fn piece(&self, index: usize) -> &StructurePiece {
+        &self.settings.pieces[index]
+    }
+
+    /// Structure starting in the cell at `cell_x`, `cell_z` of structure kind
+    /// `kind`, if any. `surface` must always be the same function.
+    pub fn start(
+        &self,
+        kind: usize,
+        cell_x: i32,
+        cell_z: i32,
+        surface: &impl Fn(i32, i32) -> i32,
+    ) -> Arc<Option<StructureStart>> {
+        self.starts[kind].get_or_compute(cell_x, cell_z, || {
+            self.layout(kind, cell_x, cell_z, surface)
+        })
+    }
+
+    fn layout(
+        &self,
+        kind_index: usize,
+        cell_x: i32,
+        cell_z: i32,
+        surface: &impl Fn(i32, i32) -> i32,
+    ) -> Option<StructureStart> {
+        let kind = &self.settings.structures[kind_index];
+        let salt = STRUCTURE_SALT + kind_index as u32;
+        let mut rng = ChunkRng::new(chunk_seed(self.settings.seed, salt, cell_x, cell_z));
+
+        let chunk_size = CHUNK_SIZE as i32;
+        let range = (kind.spacing - kind.separation).max(1);
+        let chunk_x = cell_x * kind.spacing + rng.range_i32(0, range - 1);
+        let chunk_z = cell_z * kind.spacing + rng.range_i32(0, range - 1);
+        if !rng.chance(kind.chance) {
+            return None;
+        }
+        let center = IVec3::new(
+            chunk_x * chunk_size + chunk_size / 2,
+            0,
+            chunk_z * chunk_size + chunk_size / 2,
+        );
+
+        let start_pool = &self.settings.pools[*self.pools.get(&kind.start_pool)?];
+        let piece = self.pick_piece(&start_pool.pieces, &mut rng)?;
+        let rotation = Rotation::ALL[rng.range_i32(0, 3) as usize];
+        let size = rotation.size(IVec3::from(self.piece(piece).size));
+        let y = match kind.placement {
+            StructurePlacement::Surface => {
+                let height = surface(center.x, center.z);
+                if height <= SEA_LEVEL {
+                    return None;
+                }
+                height - 1
+            }
+            StructurePlacement::Underground { min_y, max_y } => rng.range_i32(min_y, max_y),
+        };
+        let start = PlacedPiece {
+            piece,
+            rotation,
+            min: IVec3::new(center.x - size.x / 2, y, center.z - size.z / 2),
+            size,
+        };
+
+        let mut pieces = vec![start];
+        let mut open = VecDeque::new();
+        open.extend(self.open_connectors(&start, None, 0));
+
+        while let Some(open_connector) = open.pop_front() {
+            let OpenConnector {
+                pos, facing, depth, ..
+            } = open_connector;
+            let Some(pool) = self
+                .pools
+                .get(&open_connector.pool)
+                .map(|i| &self.settings.pools[*i])
+            else {
+                continue;
+            };
+            let candidates = if depth >= kind.max_depth {
+                pool.fallback
+                    .iter()
+                    .filter_map(|name| self.pieces.get(name).copied())
+                    .collect()
+            } else {
+                self.shuffled_pieces(&pool.pieces, &mut rng)
+            };
+            let first_rotation = rng.range_i32(0, 3) as usize;
+
+            'candidates: for candidate in candidates {
+                for turn in 0..4 {
+                    let rotation = Rotation::ALL[(first_rotation + turn) % 4];
+                    let template = self.piece(candidate);
+                    let size = rotation.size(IVec3::from(template.size));
+
+                    for (index, connector) in template.connectors.iter().enumerate() {
+                        if connector.facing.rotate(rotation) != facing.opposite()
+                            || (open_connector.target.is_some()
+                                && connector.name != open_connector.target)
+                        {
+                            continue;
+                        }
+                        let local = rotation
+                            .apply(IVec3::from(connector.position), IVec3::from(template.size));
+                        let mut min = pos + facing.offset() - local;
+                        if pool.projection == Projection::TerrainMatching
+                            && !matches!(facing, Facing::Up | Facing::Down)
+                        {
+                            min.y = surface(min.x + size.x / 2, min.z + size.z / 2) - 1;
+                        }
+                        let placed = PlacedPiece {
+                            piece: candidate,
+                            rotation,
+                            min,
+                            size,
+                        };
+
+                        let reach = (placed.min - center)
+                            .abs()
+                            .max((placed.max() - center).abs());
+                        if reach.x > kind.max_distance
+                            || reach.z > kind.max_distance
+                            || pieces.iter().any(|other| other.intersects(&placed))
+                        {
+                            continue;
+                        }
+
+                        open.extend(self.open_connectors(&placed, Some(index), depth + 1));
+                        pieces.push(placed);
+                        break 'candidates;
+                    }
+                }
+            }
+        }
+
+        Some(StructureStart {
+            kind: kind_index,
+            pieces,
+        })
+    }
+
+    /// Every connector of `placed` that grows the structure, except the one it
+    /// was attached with.
+    fn open_connectors(
+        &self,
+        placed: &PlacedPiece,
+        attached: Option<usize>,
+        depth: u32,
+    ) -> Vec<OpenConnector> {
+        let template = self.piece(placed.piece);
+        template
+            .connectors
+            .iter()
+            .enumerate()
+            .filter(|(index, _)| Some(*index) != attached)
+            .filter_map(|(_, connector)| {
+                let pool = connector.pool.clone()?;
+                let local = placed
+                    .rotation
+                    .apply(IVec3::from(connector.position), IVec3::from(template.size));
+                Some(OpenConnector {
+                    pos: placed.min + local,
+                    facing: connector.facing.rotate(placed.rotation),
+                    pool,
+                    target: connector.target.clone(),
+                    depth,
+                })
+            })
+            .collect()
+    }
+
+    fn pick_piece(&self, entries: &[PoolEntry], rng: &mut ChunkRng) -> Option<usize> {
+        self.shuffled_pieces(entries, rng).into_iter().next()
+    }
+
+    /// Pieces of a pool in a random order, heavier pieces tending to come first.
+    fn shuffled_pieces(&self, entries: &[PoolEntry], rng: &mut ChunkRng) -> Vec<usize> {
+        let mut remaining = entries
+            .iter()
+            .filter_map(|entry| Some((*self.pieces.get(&entry.piece)?, entry.weight)))
+            .filter(|(_, weight)| *weight > 0)
+            .collect::<Vec<_>>();
+        let mut order = Vec::with_capacity(remaining.len());
+
+        while !remaining.is_empty() {
+            let total = remaining.iter().map(|(_, weight)| *weight).sum::<u32>();
+            let mut roll = (rng.next_f32() * total as f32) as u32;
+            let index = remaining
+                .iter()
+                .position(|(_, weight)| {
+                    if roll < *weight {
+                        return true;
+                    }
+                    roll -= weight;
+                    false
+                })
+                .unwrap_or(remaining.len() - 1);
+            order.push(remaining.remove(index).0);
+        }
+        order
+    }
+}
+
+/// Stamps the parts of every structure that overlap `grid`. Structures may
+/// start several chunks away, their start cells are found from the kind's
+/// `max_distance`.
+pub fn place_structures(
+    grid: &mut ChunkGrid,
+    layouts: &StructureLayouts,
+    surface: impl Fn(i32, i32) -> i32,
+) {
+    for (kind_index, kind) in layouts.settings.structures.iter().enumerate() {
+        let cell_size = kind.spacing.max(1) * CHUNK_SIZE as i32;
+        let reach = kind.max_distance;
+        let min_x = (grid.origin.x - reach).div_euclid(cell_size);
+        let max_x = (grid.origin.x + grid.size.x - 1 + reach).div_euclid(cell_size);
+        let min_z = (grid.origin.z - reach).div_euclid(cell_size);
+        let max_z = (grid.origin.z + grid.size.z - 1 + reach).div_euclid(cell_size);
+
+        for cell_x in min_x..=max_x {
+            for cell_z in min_z..=max_z {
+                let start = layouts.start(kind_index, cell_x, cell_z, &surface);
+                let Some(start) = start.as_ref() else {
+                    continue;
+                };
+                for placed in &start.pieces {
+                    if placed.intersects_grid(grid) {
+                        stamp_piece(grid, layouts.piece(placed.piece), placed, &surface);
+                    }
+                }
+            }
+        }
+    }
+}
+
+fn stamp_piece(
+    grid: &mut ChunkGrid,
+    template: &StructurePiece,
+    placed: &PlacedPiece,
+    surface: &impl Fn(i32, i32) -> i32,
+) {
+    let size = IVec3::from(template.size);
+
+    if let Some(foundation) = template.foundation {
+        for x in placed.min.x..placed.max().x {
+            for z in placed.min.z..placed.max().z {
+                for y in surface(x, z)..placed.min.y {
+                    grid.set(IVec3::new(x, y, z), foundation);
+                }
+            }
+        }
+    }
+
+    for block_box in &template.boxes {
+        for x in block_box.min[0]..=block_box.max[0] {
+            for y in block_box.min[1]..=block_box.max[1] {
+                for z in block_box.min[2]..=block_box.max[2] {
+                    let local = placed.rotation.apply(IVec3::new(x, y, z), size);
+                    let pos = placed.min + local;
+                    if grid.get(pos) != BlockType::Bedrock {
+                        grid.set(pos, block_box.block);
+                    }
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{fs, path::Path};
+
+    use super::*;
+    use crate::plugins::terrain::terrain::MAX_HEIGHT;
+
+    fn load_layouts() -> StructureLayouts {
+        let path = Path::new(env!("CARGO_MANIFEST_DIR")).join(STRUCTURES_PATH);
+        StructureLayouts::new(ron::from_str(&fs::read_to_string(path).unwrap()).unwrap())
+    }
+
+    fn surface(_: i32, _: i32) -> i32 {
+        SEA_LEVEL + 10
+    }
+
+    /// Blocks of the chunks at `chunks`, generated in that order.
+    fn generate(
+        layouts: &StructureLayouts,
+        chunks: &[(i32, i32)],
+    ) -> HashMap<(i32, i32), Vec<BlockType>> {
+        let chunk_size = CHUNK_SIZE as i32;
+        let size = IVec3::new(chunk_size, MAX_HEIGHT as i32, chunk_size);
+        chunks
+            .iter()
+            .map(|&(x, z)| {
+                let origin = IVec3::new(x * chunk_size, 0, z * chunk_size);
+                let mut grid = ChunkGrid::new(origin, size);
+                place_structures(&mut grid, layouts, surface);
+                let mut blocks = Vec::new();
+                for x in 0..size.x {
+                    for y in 0..size.y {
+                        for z in 0..size.z {
+                            blocks.push(grid.get(origin + IVec3::new(x, y, z)));
+                        }
+                    }
+                }
+                ((x, z), blocks)
+            })
+            .collect()
+    }
+
+    #[test]
+    fn structures_do_not_depend_on_the_chunk_order() {
+        let layouts = load_layouts();
+        let start = (0..16)
+            .find_map(|cell| layouts.start(0, cell, 0, &surface).as_ref().clone())
+            .expect("no village in the first 16 cells");
+        let chunk_size = CHUNK_SIZE as i32;
+        let min = start
+            .pieces
+            .iter()
+            .map(|p| p.min)
+            .reduce(IVec3::min)
+            .unwrap();
+        let max = start
+            .pieces
+            .iter()
+            .map(|p| p.max() - IVec3::ONE)
+            .reduce(IVec3::max)
+            .unwrap();
+        let chunks = (min.x.div_euclid(chunk_size)..=max.x.div_euclid(chunk_size))
+            .flat_map(|x| {
+                (min.z.div_euclid(chunk_size)..=max.z.div_euclid(chunk_size)).map(move |z| (x, z))
+            })
+            .collect::<Vec<_>>();
+        let reversed = chunks.iter().rev().copied().collect::<Vec<_>>();
+
+        let forward = generate(&load_layouts(), &chunks);
+        assert_eq!(generate(&load_layouts(), &reversed), forward);
+        assert_eq!(generate(&layouts, &reversed), forward);
+        assert!(forward
+            .values()
+            .flatten()
+            .any(|block| *block != BlockType::Air));
+    }
+}
@@ -0,0 +1,258 @@
+use bevy::prelude::{IVec3, Resource};
+use serde::Deserialize;
+
+use super::terrain::{BlockType, ChunkGrid, SEA_LEVEL};
+
+pub const SURFACE_RULES_PATH: &str = "assets/terrain/surface_rules.ron";
+
+#[derive(Clone, Debug, Deserialize)]
+pub enum SurfaceCondition {
+    Always,
+    /// Column height is at least this value.
+    HeightAtLeast(i32),
+    /// Column height is at most this value.
+    HeightAtMost(i32),
+    /// Steepest height difference to a horizontal neighbour is at least this value.
+    SlopeAtLeast(i32),
+    SlopeAtMost(i32),
+    /// Column top is within this many blocks of the sea level.
+    NearWater(i32),
+    /// Column is covered by water.
+    Underwater,
+}
+
+/// Blocks used for a column when all of `conditions` match.
+#[derive(Clone, Debug, Deserialize)]
+pub struct SurfaceRule {
+    pub conditions: Vec<SurfaceCondition>,
+    pub top: BlockType,
+    pub filler: BlockType,
+    /// Number of filler blocks below the top block.
+    pub filler_depth: i32,
+}
+
+/// Ordered list of surface rules, the first matching rule wins. Loaded from
+/// [`SURFACE_RULES_PATH`], falling back to the built-in rules.
+#[derive(Resource, Clone, Debug, Deserialize)]
+pub struct SurfaceRules {
+    pub rules: Vec<SurfaceRule>,
+}
+
+impl Default for SurfaceRules {
+    fn default() -> Self {
+        Self {
+            rules: vec![
+                SurfaceRule {
+                    conditions: vec![SurfaceCondition::Underwater],
+                    top: BlockType::Gravel,
+                    filler: BlockType::Sand,
+                    filler_depth: 2,
+                },
+                SurfaceRule {
+                    conditions: vec![SurfaceCondition::HeightAtLeast(85)],
+                    top: BlockType::Snow,
+                    filler: BlockType::Snow,
+                    filler_depth: 1,
+                },
+                SurfaceRule {
+                    conditions: vec![SurfaceCondition::SlopeAtLeast(3)],
+                    top: BlockType::Stone,
+                    filler: BlockType::Stone,
+                    filler_depth: 0,
+                },
+                SurfaceRule {
+                    conditions: vec![
+                        SurfaceCondition::NearWater(2),
+                        SurfaceCondition::SlopeAtMost(1),
+                    ],
+                    top: BlockType::Gravel,
+                    filler: BlockType::Sand,
+                    filler_depth: 2,
+                },
+                SurfaceRule {
+                    conditions: vec![SurfaceCondition::Always],
+                    top: BlockType::Grass,
+                    filler: BlockType::Dirt,
+                    filler_depth: 3,
+                },
+            ],
+        }
+    }
+}
+
+/// What the rules know about a column.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct ColumnInfo {
+    pub height: i32,
+    pub slope: i32,
+    pub underwater: bool,
+}
+
+impl ColumnInfo {
+    /// Describes the column at `x`, `z` using the heightmap `surface`.
+    pub fn at(
+        x: i32,
+        z: i32,
+        surface: &impl Fn(i32, i32) -> i32,
+        water_level: &impl Fn(i32, i32) -> Option<i32>,
+    ) -> Self {
+        let height = surface(x, z);
+        let slope = [(1, 0), (-1, 0), (0, 1), (0, -1)]
+            .iter()
+            .map(|(dx, dz)| (surface(x + dx, z + dz) - height).abs())
+            .max()
+            .unwrap_or(0);
+        Self {
+            height,
+            slope,
+            underwater: water_level(x, z).is_some(),
+        }
+    }
+}
+
+impl SurfaceCondition {
+    pub fn matches(&self, column: &ColumnInfo) -> bool {
+        match *self {
+            SurfaceCondition::Always => true,
+            SurfaceCondition::HeightAtLeast(height) => column.height >= height,
+            SurfaceCondition::HeightAtMost(height) => column.height <= height,
+            SurfaceCondition::SlopeAtLeast(slope) => column.slope >= slope,
+            SurfaceCondition::SlopeAtMost(slope) => column.slope <= slope,
+            SurfaceCondition::NearWater(distance) => (column.height - SEA_LEVEL).abs() <= distance,
+            SurfaceCondition::Underwater => column.underwater,
+        }
+    }
+}
+
+impl SurfaceRules {
+    pub fn select(&self, column: &ColumnInfo) -> Option<&SurfaceRule> {
+        self.rules
+            .iter()
+            .find(|rule| rule.conditions.iter().all(|c| c.matches(column)))
+    }
+}
+
+/// Replaces the top blocks of every column in `grid` with the blocks of the
+/// first matching rule. `surface` returns the terrain height of a column.
+pub fn apply_surface_rules(
+    grid: &mut ChunkGrid,
+    rules: &SurfaceRules,
+    surface: impl Fn(i32, i32) -> i32,
+    water_level: impl Fn(i32, i32) -> Option<i32>,
+) {
+    for x in grid.origin.x..grid.origin.x + grid.size.x {
+        for z in grid.origin.z..grid.origin.z + grid.size.z {
+            let column = ColumnInfo::at(x, z, &surface, &water_level);
+            if let Some(rule) = rules.select(&column) {
+                stamp_column(grid, x, z, column.height, rule);
+            }
+        }
+    }
+}
+
+/// Replaces the top blocks of the column at `x`, `z` that ends at `height`.
+pub fn stamp_column(grid: &mut ChunkGrid, x: i32, z: i32, height: i32, rule: &SurfaceRule) {
+    for depth in 0..=rule.filler_depth {
+        let pos = IVec3::new(x, height - 1 - depth, z);
+        let current = grid.get(pos);
+        if !current.is_solid() || current == BlockType::Bedrock {
+            continue;
+        }
+        grid.set(pos, if depth == 0 { rule.top } else { rule.filler });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::plugins::terrain::terrain::MAX_HEIGHT;
+
+    /// Fills a 4×4 grid with `fill` up to the synthetic heightmap `surface`
+    /// and applies the default rules to it.
+    fn surfaced(
+        fill: BlockType,
+        surface: impl Fn(i32, i32) -> i32,
+        water_level: impl Fn(i32, i32) -> Option<i32>,
+    ) -> ChunkGrid {
+        let mut grid = ChunkGrid::new(IVec3::ZERO, IVec3::new(4, MAX_HEIGHT as i32, 4));
+        for x in 0..4 {
+            for z in 0..4 {
+                for y in 0..surface(x, z) {
+                    grid.set(IVec3::new(x, y, z), fill);
+                }
+            }
+        }
+        apply_surface_rules(&mut grid, &SurfaceRules::default(), surface, water_level);
+        grid
+    }
+
+    fn column(grid: &ChunkGrid, x: i32, z: i32, top: i32, depth: i32) -> Vec<BlockType> {
+        (0..depth)
+            .map(|depth| grid.get(IVec3::new(x, top - 1 - depth, z)))
+            .collect()
+    }
+
+    #[test]
+    fn snow_covers_columns_above_the_snow_line() {
+        let grid = surfaced(BlockType::Stone, |_, _| 90, |_, _| None);
+        assert_eq!(
+            column(&grid, 1, 1, 90, 3),
+            [BlockType::Snow, BlockType::Snow, BlockType::Stone]
+        );
+
+        let grid = surfaced(BlockType::Stone, |_, _| 84, |_, _| None);
+        assert_eq!(grid.get(IVec3::new(1, 83, 1)), BlockType::Grass);
+    }
+
+    #[test]
+    fn steep_slopes_are_stone() {
+        let grid = surfaced(BlockType::Dirt, |x, _| 60 + 3 * x, |_, _| None);
+        for x in 0..4 {
+            assert_eq!(
+                column(&grid, x, 1, 60 + 3 * x, 2),
+                [BlockType::Stone, BlockType::Dirt]
+            );
+        }
+
+        // Just below the gradient threshold the column keeps its grass.
+        let grid = surfaced(BlockType::Stone, |x, _| 60 + 2 * x, |_, _| None);
+        assert_eq!(grid.get(IVec3::new(1, 61, 1)), BlockType::Grass);
+    }
+
+    #[test]
+    fn gravel_lines_the_shore_and_the_sea_floor() {
+        let shore = SEA_LEVEL + 2;
+        let grid = surfaced(BlockType::Stone, |_, _| shore, |_, _| None);
+        assert_eq!(
+            column(&grid, 1, 1, shore, 4),
+            [
+                BlockType::Gravel,
+                BlockType::Sand,
+                BlockType::Sand,
+                BlockType::Stone
+            ]
+        );
+
+        let grid = surfaced(BlockType::Stone, |_, _| 40, |_, _| Some(SEA_LEVEL));
+        assert_eq!(grid.get(IVec3::new(1, 39, 1)), BlockType::Gravel);
+
+        // Farther from the sea level the shore rule no longer applies.
+        let grid = surfaced(BlockType::Stone, |_, _| shore + 1, |_, _| None);
+        assert_eq!(grid.get(IVec3::new(1, shore, 1)), BlockType::Grass);
+    }
+
+    #[test]
+    fn grass_sits_on_three_blocks_of_dirt() {
+        let grid = surfaced(BlockType::Stone, |_, _| 70, |_, _| None);
+        assert_eq!(
+            column(&grid, 2, 2, 70, 5),
+            [
+                BlockType::Grass,
+                BlockType::Dirt,
+                BlockType::Dirt,
+                BlockType::Dirt,
+                BlockType::Stone
+            ]
+        );
+    }
+}
@@ -1,40 +1,150 @@
+use std::sync::Arc;
+
 use bevy::{
+    ecs::system::SystemParam,
     prelude::*,
     render::{mesh::Indices, render_resource::PrimitiveTopology},
-    tasks::{AsyncComputeTaskPool, Task},
-    utils::HashMap,
 };
-use futures_lite::future;
 use noise::{NoiseFn, Perlin};
+use serde::Deserialize;
+
+use crate::utils::{config::load_ron_or_default, random::stage_seed};
 
-use crate::plugins::camera::camera::FlyCamera;
+use super::{
+    anvil::{AnvilSettings, AnvilWorld, ImportedWorld, ANVIL_PATH},
+    autosave::{self, Autosave},
+    block_names::{BlockNames, BLOCK_NAMES_PATH},
+    carver::{self, CarverSettings, CARVER_PATH},
+    continents::{ContinentSettings, Continents, CONTINENTS_PATH},
+    edits::{self, EditHistory, EditSettings, EDITS_PATH},
+    erosion::{ErodedHeightmap, ErosionCache, ErosionSettings, EROSION_PATH},
+    events::{self, BlockChanged, ChunkEntity, ChunkLoaded, ChunkMeshed, ChunkUnloaded},
+    features::{self, FeatureSettings, FEATURES_PATH},
+    heightmap::{HeightmapCache, HeightmapSettings, ImportedHeightmap, HEIGHTMAP_PATH},
+    level::{self, LevelMetadata, TimeOfDay},
+    light::{self, MAX_LIGHT},
+    materials::{self, MaterialSettings, MATERIALS_PATH},
+    mesh_export,
+    pipeline::{self, ChunkStatus, LoadedChunks, StageTaskStats},
+    region::{PersistenceSettings, RegionStorage, WorldStorage, PERSISTENCE_PATH},
+    rivers::{RiverCache, RiverCell, RiverNetwork, RiverSettings, RIVERS_PATH},
+    schematic::{PastedSchematics, SchematicSettings, SCHEMATICS_PATH},
+    structures::{self, StructureCache, StructureLayouts, StructureSettings, STRUCTURES_PATH},
+    surface::{self, SurfaceRules, SURFACE_RULES_PATH},
+    tickets::{self, ChunkTickets},
+    vox,
+    worlds::OpenWorld,
+};
 
-const CHUNK_SIZE: usize = 16;
-const MAX_HEIGHT: usize = 100;
+/// Seed of the worlds created without choosing one.
+pub const WORLD_SEED: u32 = 10;
+pub const CHUNK_SIZE: usize = 16;
+pub const MAX_HEIGHT: usize = 100;
+/// Height of the water surface. Columns below it are filled with water.
+pub const SEA_LEVEL: i32 = 52;
 const TERRAIN_HEIGHT: usize = 40;
-const RENDER_DISTANCE: usize = 30;
+/// Radius in chunks of the area loaded around the camera.
+pub const RENDER_DISTANCE: i32 = 30;
+/// Width in blocks of the area loaded around the camera.
+pub const RENDER_AREA: i32 = (2 * RENDER_DISTANCE + 1) * CHUNK_SIZE as i32;
 
+#[derive(Clone)]
 pub struct Chunk {
     pub position: Vec3,
+    pub grid: ChunkGrid,
+    /// Sky light of every block in `grid`, filled in by the `Light` stage.
+    pub light: Vec<u8>,
     pub blocks: Vec<Block>,
     pub mesh: Mesh,
 }
 
+impl Chunk {
+    pub fn new(x: i32, z: i32) -> Self {
+        Self {
+            position: Vec3::new(x as f32, 0.0, z as f32),
+            grid: ChunkGrid::new(
+                IVec3::new(x, 0, z),
+                IVec3::new(CHUNK_SIZE as i32, MAX_HEIGHT as i32, CHUNK_SIZE as i32),
+            ),
+            light: Vec::new(),
+            blocks: Vec::new(),
+            mesh: Mesh::new(PrimitiveTopology::TriangleList),
+        }
+    }
+
+    pub fn light_at(&self, pos: IVec3) -> Option<u8> {
+        self.grid
+            .index_of(pos)
+            .map(|index| self.light.get(index).copied().unwrap_or(0))
+    }
+}
+
 pub struct TerrainPlugin;
 
 impl Plugin for TerrainPlugin {
     fn build(&self, app: &mut bevy::prelude::App) {
-        app.add_systems(Startup, |mut commands: Commands| {
-            commands.insert_resource(LoadedChunks {
-                chunks: HashMap::new(),
-            });
-        })
-        .add_systems(Update, prepare_chunks)
-        .add_systems(Update, generate_chunk);
+        let block_names = load_ron_or_default::<BlockNames>(BLOCK_NAMES_PATH);
+        let persistence = load_ron_or_default::<PersistenceSettings>(PERSISTENCE_PATH);
+        let world = OpenWorld::open(&persistence);
+        let generator = ChunkGenerator::load(&block_names, &world);
+
+        app.insert_resource(generator.carver)
+            .insert_resource(generator.materials)
+            .insert_resource(generator.features)
+            .insert_resource(generator.surface_rules)
+            .insert_resource(HeightmapCache(generator.heightmap))
+            .insert_resource(generator.continents)
+            .insert_resource(ErosionCache(generator.erosion))
+            .insert_resource(RiverCache(generator.rivers))
+            .insert_resource(StructureCache(generator.structures))
+            .insert_resource(block_names)
+            .insert_resource(generator.schematics)
+            .insert_resource(ImportedWorld(generator.world))
+            .insert_resource(Autosave::new(persistence.autosave_interval))
+            .insert_resource(EditHistory::for_world(
+                &load_ron_or_default::<EditSettings>(EDITS_PATH),
+                &world,
+            ))
+            .insert_resource(world.storage)
+            .init_resource::<LoadedChunks>()
+            .insert_resource(ChunkTickets::with_spawn(Vec3::from_array(
+                world.level.camera.position,
+            )))
+            .add_systems(Update, tickets::update_tickets)
+            .add_systems(
+                Update,
+                pipeline::advance_chunks.after(tickets::update_tickets),
+            )
+            .init_resource::<StageTaskStats>()
+            .add_systems(
+                Update,
+                pipeline::collect_stage_results.after(pipeline::advance_chunks),
+            )
+            .insert_resource(TimeOfDay(world.level.time_of_day))
+            .insert_resource(world.level)
+            .add_systems(Update, level::advance_time)
+            .add_systems(
+                Update,
+                autosave::autosave.after(pipeline::collect_stage_results),
+            )
+            .add_systems(Last, autosave::save_on_exit)
+            .add_systems(Update, edits::undo_redo_on_key)
+            .add_event::<ChunkLoaded>()
+            .add_event::<ChunkMeshed>()
+            .add_event::<ChunkUnloaded>()
+            .add_event::<BlockChanged>()
+            .add_systems(
+                Update,
+                events::send_chunk_events
+                    .after(pipeline::collect_stage_results)
+                    .after(edits::undo_redo_on_key),
+            )
+            .add_systems(Update, vox::export_chunk_on_key)
+            .add_systems(Update, mesh_export::export_meshes_on_key);
     }
 }
 
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 struct Visibility {
     top: bool,
     bottom: bool,
@@ -57,10 +167,150 @@ impl Default for Visibility {
     }
 }
 
-#[derive(Debug)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Deserialize)]
+pub enum BlockType {
+    #[default]
+    Air,
+    Grass,
+    Dirt,
+    Stone,
+    Deepslate,
+    Coal,
+    Iron,
+    Gold,
+    Bedrock,
+    Cobblestone,
+    Log,
+    Leaves,
+    Flower,
+    TallGrass,
+    BirchLog,
+    BirchLeaves,
+    SpruceLog,
+    SpruceLeaves,
+    JungleLog,
+    JungleLeaves,
+    Vines,
+    Snow,
+    Gravel,
+    Sand,
+    Water,
+    Planks,
+}
+
+impl BlockType {
+    /// Every block type, in declaration order.
+    pub const ALL: [BlockType; 26] = [
+        BlockType::Air,
+        BlockType::Grass,
+        BlockType::Dirt,
+        BlockType::Stone,
+        BlockType::Deepslate,
+        BlockType::Coal,
+        BlockType::Iron,
+        BlockType::Gold,
+        BlockType::Bedrock,
+        BlockType::Cobblestone,
+        BlockType::Log,
+        BlockType::Leaves,
+        BlockType::Flower,
+        BlockType::TallGrass,
+        BlockType::BirchLog,
+        BlockType::BirchLeaves,
+        BlockType::SpruceLog,
+        BlockType::SpruceLeaves,
+        BlockType::JungleLog,
+        BlockType::JungleLeaves,
+        BlockType::Vines,
+        BlockType::Snow,
+        BlockType::Gravel,
+        BlockType::Sand,
+        BlockType::Water,
+        BlockType::Planks,
+    ];
+
+    /// Name blocks are saved under. Saved worlds refer to it, so it must not
+    /// change when a variant is renamed.
+    pub fn name(self) -> &'static str {
+        match self {
+            BlockType::Air => "air",
+            BlockType::Grass => "grass",
+            BlockType::Dirt => "dirt",
+            BlockType::Stone => "stone",
+            BlockType::Deepslate => "deepslate",
+            BlockType::Coal => "coal",
+            BlockType::Iron => "iron",
+            BlockType::Gold => "gold",
+            BlockType::Bedrock => "bedrock",
+            BlockType::Cobblestone => "cobblestone",
+            BlockType::Log => "log",
+            BlockType::Leaves => "leaves",
+            BlockType::Flower => "flower",
+            BlockType::TallGrass => "tall_grass",
+            BlockType::BirchLog => "birch_log",
+            BlockType::BirchLeaves => "birch_leaves",
+            BlockType::SpruceLog => "spruce_log",
+            BlockType::SpruceLeaves => "spruce_leaves",
+            BlockType::JungleLog => "jungle_log",
+            BlockType::JungleLeaves => "jungle_leaves",
+            BlockType::Vines => "vines",
+            BlockType::Snow => "snow",
+            BlockType::Gravel => "gravel",
+            BlockType::Sand => "sand",
+            BlockType::Water => "water",
+            BlockType::Planks => "planks",
+        }
+    }
+
+    pub fn from_name(name: &str) -> Option<BlockType> {
+        BlockType::ALL
+            .into_iter()
+            .find(|block| block.name() == name)
+    }
+
+    pub fn is_solid(self) -> bool {
+        self != BlockType::Air
+    }
+
+    /// Tint multiplied with the block texture.
+    pub fn color(self) -> [f32; 4] {
+        match self {
+            BlockType::Air | BlockType::Grass => [1.0, 1.0, 1.0, 1.0],
+            BlockType::Dirt => [0.55, 0.4, 0.25, 1.0],
+            BlockType::Stone => [0.5, 0.5, 0.5, 1.0],
+            BlockType::Deepslate => [0.3, 0.3, 0.33, 1.0],
+            BlockType::Coal => [0.15, 0.15, 0.15, 1.0],
+            BlockType::Iron => [0.8, 0.65, 0.55, 1.0],
+            BlockType::Gold => [1.0, 0.85, 0.2, 1.0],
+            BlockType::Bedrock => [0.1, 0.1, 0.1, 1.0],
+            BlockType::Cobblestone => [0.42, 0.42, 0.4, 1.0],
+            BlockType::Log => [0.4, 0.28, 0.15, 1.0],
+            BlockType::Leaves => [0.3, 0.7, 0.25, 1.0],
+            BlockType::Flower => [0.9, 0.2, 0.3, 1.0],
+            BlockType::TallGrass => [0.5, 0.9, 0.4, 1.0],
+            BlockType::BirchLog => [0.9, 0.9, 0.85, 1.0],
+            BlockType::BirchLeaves => [0.5, 0.75, 0.35, 1.0],
+            BlockType::SpruceLog => [0.3, 0.2, 0.1, 1.0],
+            BlockType::SpruceLeaves => [0.2, 0.4, 0.25, 1.0],
+            BlockType::JungleLog => [0.5, 0.35, 0.2, 1.0],
+            BlockType::JungleLeaves => [0.2, 0.8, 0.15, 1.0],
+            BlockType::Vines => [0.25, 0.55, 0.1, 1.0],
+            BlockType::Snow => [0.95, 0.97, 1.0, 1.0],
+            BlockType::Gravel => [0.55, 0.52, 0.5, 1.0],
+            BlockType::Sand => [0.9, 0.85, 0.6, 1.0],
+            BlockType::Water => [0.2, 0.35, 0.85, 1.0],
+            BlockType::Planks => [0.7, 0.55, 0.35, 1.0],
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
 pub struct Block {
     position: Vec3,
     visibility: Visibility,
+    /// Light falling on each face, in the same order as the faces are meshed.
+    face_light: [u8; 6],
+    pub block_type: BlockType,
 }
 
 impl Block {
@@ -74,88 +324,262 @@ impl Block {
     }
 }
 
-#[derive(Component)]
-struct ComputeChunk(Task<Chunk>);
+/// Dense block storage of a chunk.
+#[derive(Clone)]
+pub struct ChunkGrid {
+    pub origin: IVec3,
+    pub size: IVec3,
+    blocks: Vec<BlockType>,
+}
+
+impl ChunkGrid {
+    pub fn new(origin: IVec3, size: IVec3) -> Self {
+        Self {
+            origin,
+            size,
+            blocks: vec![BlockType::Air; (size.x * size.y * size.z) as usize],
+        }
+    }
 
-#[derive(Resource)]
-struct LoadedChunks {
-    chunks: HashMap<String, Option<Chunk>>,
+    pub fn contains(&self, pos: IVec3) -> bool {
+        let local = pos - self.origin;
+        local.cmpge(IVec3::ZERO).all() && local.cmplt(self.size).all()
+    }
+
+    fn index(&self, pos: IVec3) -> usize {
+        let local = pos - self.origin;
+        ((local.x * self.size.y + local.y) * self.size.z + local.z) as usize
+    }
+
+    /// Index of a world position into data laid out like the grid's blocks.
+    pub fn index_of(&self, pos: IVec3) -> Option<usize> {
+        self.contains(pos).then(|| self.index(pos))
+    }
+
+    pub fn len(&self) -> usize {
+        self.blocks.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.blocks.is_empty()
+    }
+
+    /// Returns the block at a world position, or air outside of the grid.
+    pub fn get(&self, pos: IVec3) -> BlockType {
+        if self.contains(pos) {
+            self.blocks[self.index(pos)]
+        } else {
+            BlockType::Air
+        }
+    }
+
+    /// Positions of all chunks overlapping the grid, extended by `reach` chunks
+    /// in every horizontal direction.
+    pub fn chunks_within(&self, reach: i32) -> impl Iterator<Item = (i32, i32)> {
+        let chunk_size = CHUNK_SIZE as i32;
+        let min_x = self.origin.x.div_euclid(chunk_size) - reach;
+        let max_x = (self.origin.x + self.size.x - 1).div_euclid(chunk_size) + reach;
+        let min_z = self.origin.z.div_euclid(chunk_size) - reach;
+        let max_z = (self.origin.z + self.size.z - 1).div_euclid(chunk_size) + reach;
+        (min_x..=max_x).flat_map(move |x| (min_z..=max_z).map(move |z| (x, z)))
+    }
+
+    /// Sets the block at a world position; positions outside the grid are ignored.
+    pub fn set(&mut self, pos: IVec3, block_type: BlockType) {
+        if self.contains(pos) {
+            let index = self.index(pos);
+            self.blocks[index] = block_type;
+        }
+    }
 }
 
-fn spawn_prepare_chunk_if_needed(
-    x: i32,
-    z: i32,
-    loaded_chunks: &mut LoadedChunks,
-    tasks: &mut Vec<ComputeChunk>,
-    thread_pool: &AsyncComputeTaskPool,
-    perlin: Perlin,
-) {
-    let chunk_id = format!("X{}Z{}", x, z);
-    if !(loaded_chunks.chunks.contains_key(&chunk_id)) {
-        loaded_chunks.chunks.insert(chunk_id, None);
-        tasks.push(ComputeChunk(
-            thread_pool.spawn(async move { prepare_chunk(x, z, perlin) }),
-        ));
+/// Everything a generation task needs. It is cloned into every task so the
+/// settings can't change underneath a chunk that is being generated.
+#[derive(Clone)]
+pub struct ChunkGenerator {
+    pub perlin: Perlin,
+    pub carver: CarverSettings,
+    pub materials: MaterialSettings,
+    pub surface_rules: SurfaceRules,
+    pub features: FeatureSettings,
+    pub continents: Continents,
+    /// Replaces the Perlin height when a heightmap image is configured.
+    pub heightmap: Option<Arc<ImportedHeightmap>>,
+    /// Shared between all tasks so eroded regions are only computed once.
+    pub erosion: Option<Arc<ErodedHeightmap>>,
+    pub rivers: Option<Arc<RiverNetwork>>,
+    pub structures: Option<Arc<StructureLayouts>>,
+    pub schematics: PastedSchematics,
+    /// Existing Minecraft world streamed instead of generating terrain.
+    pub world: Option<Arc<AnvilWorld>>,
+    /// Where chunks are saved, saved chunks are loaded instead of generated.
+    pub storage: Option<Arc<RegionStorage>>,
+}
+
+impl ChunkGenerator {
+    /// Loads every generation setting of a world, from its own copy of the
+    /// settings or else from `assets/terrain`.
+    pub fn load(block_names: &BlockNames, world: &OpenWorld) -> Self {
+        let path = |default| world.settings_path(default);
+        // Every world made from a preset shares its settings files, the
+        // world's own seed makes its terrain different.
+        let seed = |salt| stage_seed(world.level.seed, salt);
+        let mut carver = load_ron_or_default::<CarverSettings>(&path(CARVER_PATH));
+        carver.seed = seed(carver.seed);
+        let mut materials = load_ron_or_default::<MaterialSettings>(&path(MATERIALS_PATH));
+        materials.seed = seed(materials.seed);
+        let mut features = load_ron_or_default::<FeatureSettings>(&path(FEATURES_PATH));
+        features.seed = seed(features.seed);
+        let mut continents = load_ron_or_default::<ContinentSettings>(&path(CONTINENTS_PATH));
+        continents.seed = seed(continents.seed);
+        let mut erosion = load_ron_or_default::<ErosionSettings>(&path(EROSION_PATH));
+        erosion.seed = seed(erosion.seed);
+        let mut rivers = load_ron_or_default::<RiverSettings>(&path(RIVERS_PATH));
+        rivers.seed = seed(rivers.seed);
+        let mut structures = load_ron_or_default::<StructureSettings>(&path(STRUCTURES_PATH));
+        structures.seed = seed(structures.seed);
+        let heightmap = HeightmapCache::new(load_ron_or_default::<HeightmapSettings>(&path(
+            HEIGHTMAP_PATH,
+        )))
+        .0;
+        // Imported heightmaps keep the heights of their image unless they ask
+        // for erosion and rivers.
+        if let Some(heightmap) = &heightmap {
+            erosion.enabled &= heightmap.settings().erosion;
+            rivers.enabled &= heightmap.settings().rivers;
+        }
+
+        Self {
+            perlin: Perlin::new(world.level.seed),
+            carver,
+            materials,
+            surface_rules: load_ron_or_default::<SurfaceRules>(&path(SURFACE_RULES_PATH)),
+            features,
+            continents: Continents::new(continents),
+            heightmap,
+            erosion: ErosionCache::new(erosion).0,
+            rivers: RiverCache::new(rivers).0,
+            structures: StructureCache::new(structures).0,
+            schematics: PastedSchematics::load(
+                load_ron_or_default::<SchematicSettings>(&path(SCHEMATICS_PATH)),
+                block_names,
+            ),
+            storage: world.storage.0.clone(),
+            world: ImportedWorld::new(
+                load_ron_or_default::<AnvilSettings>(&path(ANVIL_PATH)),
+                block_names,
+            )
+            .0,
+        }
+    }
+
+    /// Terrain height before rivers are cut into it.
+    fn base_height(&self, x: i32, z: i32) -> i32 {
+        let base = |x: i32, z: i32| match &self.heightmap {
+            Some(heightmap) => heightmap.height(x, z),
+            None => get_perlin_heigth(x as f32, z as f32, self.perlin, &self.continents) as f32,
+        };
+        match &self.erosion {
+            Some(erosion) => erosion.height(x, z, &base).round() as i32,
+            None => base(x, z) as i32,
+        }
+    }
+
+    pub fn surface_height(&self, x: i32, z: i32) -> i32 {
+        let height = self.base_height(x, z);
+        match self.river_at(x, z) {
+            Some(river) => height.min(river.bed),
+            None => height,
+        }
+    }
+
+    /// Height of the water surface above a column, if it is covered by water.
+    pub fn water_level(&self, x: i32, z: i32) -> Option<i32> {
+        let sea = (self.surface_height(x, z) < SEA_LEVEL).then_some(SEA_LEVEL);
+        self.river_at(x, z).and_then(|river| river.water).max(sea)
+    }
+
+    fn river_at(&self, x: i32, z: i32) -> Option<RiverCell> {
+        self.rivers
+            .as_ref()
+            .and_then(|rivers| rivers.column(x, z, &|x, z| self.base_height(x, z)))
     }
 }
 
-fn prepare_chunks(
-    mut commands: Commands,
-    query: Query<&Transform, &FlyCamera>,
-    mut loaded_chunks: ResMut<LoadedChunks>,
-) {
-    let camera = query.get_single().unwrap();
-    let thread_pool = AsyncComputeTaskPool::get();
-    let perlin = Perlin::new(10);
-
-    let x = ((camera.translation.x / CHUNK_SIZE as f32).ceil() * CHUNK_SIZE as f32) as i32;
-    let z = ((camera.translation.z / CHUNK_SIZE as f32).ceil() * CHUNK_SIZE as f32) as i32;
-
-    for i_x in (x..).step_by(CHUNK_SIZE).take(RENDER_DISTANCE) {
-        for i_z in (z..).step_by(CHUNK_SIZE).take(RENDER_DISTANCE) {
-            let i_x_mirror = x - i_x;
-            let i_z_mirror = z - i_z;
-            let mut tasks = Vec::new();
-
-            spawn_prepare_chunk_if_needed(
-                i_x,
-                i_z,
-                &mut loaded_chunks,
-                &mut tasks,
-                &thread_pool,
-                perlin,
-            );
-            spawn_prepare_chunk_if_needed(
-                i_x_mirror,
-                i_z,
-                &mut loaded_chunks,
-                &mut tasks,
-                &thread_pool,
-                perlin,
-            );
-            spawn_prepare_chunk_if_needed(
-                i_x,
-                i_z_mirror,
-                &mut loaded_chunks,
-                &mut tasks,
-                &thread_pool,
-                perlin,
-            );
-            spawn_prepare_chunk_if_needed(
-                i_x_mirror,
-                i_z_mirror,
-                &mut loaded_chunks,
-                &mut tasks,
-                &thread_pool,
-                perlin,
-            );
+#[derive(SystemParam)]
+pub struct GeneratorSettings<'w> {
+    carver: Res<'w, CarverSettings>,
+    materials: Res<'w, MaterialSettings>,
+    surface_rules: Res<'w, SurfaceRules>,
+    features: Res<'w, FeatureSettings>,
+    continents: Res<'w, Continents>,
+    heightmap: Res<'w, HeightmapCache>,
+    erosion: Res<'w, ErosionCache>,
+    rivers: Res<'w, RiverCache>,
+    structures: Res<'w, StructureCache>,
+    schematics: Res<'w, PastedSchematics>,
+    world: Res<'w, ImportedWorld>,
+    storage: Res<'w, WorldStorage>,
+    level: Res<'w, LevelMetadata>,
+}
 
-            commands.spawn_batch(tasks);
+impl<'w> GeneratorSettings<'w> {
+    pub fn generator(&self) -> ChunkGenerator {
+        ChunkGenerator {
+            perlin: Perlin::new(self.level.seed),
+            carver: self.carver.clone(),
+            materials: self.materials.clone(),
+            surface_rules: self.surface_rules.clone(),
+            features: self.features.clone(),
+            continents: self.continents.clone(),
+            heightmap: self.heightmap.0.clone(),
+            erosion: self.erosion.0.clone(),
+            rivers: self.rivers.0.clone(),
+            structures: self.structures.0.clone(),
+            schematics: self.schematics.clone(),
+            world: self.world.0.clone(),
+            storage: self.storage.0.clone(),
         }
     }
 }
 
-fn get_perlin_value(
+/// Read access to a chunk together with its already generated neighbours.
+pub struct ChunkView<'a> {
+    pub chunk: &'a Chunk,
+    pub neighbours: &'a [Arc<Chunk>],
+}
+
+impl<'a> ChunkView<'a> {
+    fn find(&self, pos: IVec3) -> Option<&Chunk> {
+        if self.chunk.grid.contains(pos) {
+            return Some(self.chunk);
+        }
+        self.neighbours
+            .iter()
+            .map(|n| n.as_ref())
+            .find(|n| n.grid.contains(pos))
+    }
+
+    /// Returns the block at a world position, or air if it isn't available.
+    pub fn block(&self, pos: IVec3) -> BlockType {
+        self.find(pos)
+            .map_or(BlockType::Air, |chunk| chunk.grid.get(pos))
+    }
+
+    /// Returns the light at a world position. Everything above the world is
+    /// fully lit, everything else that isn't available is dark.
+    pub fn light(&self, pos: IVec3) -> u8 {
+        if pos.y >= MAX_HEIGHT as i32 {
+            return MAX_LIGHT;
+        }
+        self.find(pos)
+            .and_then(|chunk| chunk.light_at(pos))
+            .unwrap_or(0)
+    }
+}
+
+#[allow(clippy::too_many_arguments)]
+pub fn get_perlin_value(
     perlin: Perlin,
     x: f32,
     y: f32,
@@ -176,60 +600,44 @@ fn get_perlin_value(
     value
 }
 
-fn is_block_at(position: Vec3, perlin: Perlin) -> bool {
-    get_perlin_heigth(position.x, position.z, perlin) as f32 > position.y
-}
-
-fn get_perlin_heigth(x: f32, z: f32, perlin: Perlin) -> u32 {
+fn get_perlin_heigth(x: f32, z: f32, perlin: Perlin, continents: &Continents) -> u32 {
     let perlin_value = get_perlin_value(perlin, x * 0.01, z * 0.01, 0.4, 1.0, 4, 0.5, 2.0);
     let height_value = perlin_value * 90.0;
-    let height = TERRAIN_HEIGHT as u32
-        + height_value as u32
+    let hills = height_value as u32
         + HEIGTH_MAP
             .iter()
             .find(|h| h[0] > height_value as u32)
             .unwrap_or_else(|| &HEIGTH_MAP[2])[1];
 
-    return height;
+    let height = match continents.shape(x as i32, z as i32) {
+        Some((height, scale)) => (height + hills as f32 * scale).max(1.0) as u32,
+        None => TERRAIN_HEIGHT as u32 + hills,
+    };
+    // Continent peaks can rise above the world, which has no room for them.
+    height.min(MAX_HEIGHT as u32 - 1)
 }
 
-fn assign_visibility(block: &mut Block, perlin: Perlin) {
-    if is_block_at(
-        Vec3::new(block.position.x - 1.0, block.position.y, block.position.z),
-        perlin,
-    ) {
-        block.visibility.left = false;
-    }
-    if is_block_at(
-        Vec3::new(block.position.x + 1.0, block.position.y, block.position.z),
-        perlin,
-    ) {
-        block.visibility.right = false;
-    }
-    if is_block_at(
-        Vec3::new(block.position.x, block.position.y + 1.0, block.position.z),
-        perlin,
-    ) {
-        block.visibility.top = false;
-    }
-    if is_block_at(
-        Vec3::new(block.position.x, block.position.y - 1.0, block.position.z),
-        perlin,
-    ) {
-        block.visibility.bottom = false;
-    }
-    if is_block_at(
-        Vec3::new(block.position.x, block.position.y, block.position.z - 1.0),
-        perlin,
-    ) {
-        block.visibility.front = false;
-    }
-    if is_block_at(
-        Vec3::new(block.position.x, block.position.y, block.position.z + 1.0),
-        perlin,
-    ) {
-        block.visibility.back = false;
-    }
+fn assign_visibility(block: &mut Block, view: &ChunkView) {
+    let position = block.position.as_ivec3();
+    let is_solid = |offset: IVec3| view.block(position + offset).is_solid();
+
+    block.visibility.left = !is_solid(IVec3::NEG_X);
+    block.visibility.right = !is_solid(IVec3::X);
+    block.visibility.top = !is_solid(IVec3::Y);
+    // Nothing is ever visible from below the world.
+    block.visibility.bottom = position.y > 0 && !is_solid(IVec3::NEG_Y);
+    block.visibility.front = !is_solid(IVec3::NEG_Z);
+    block.visibility.back = !is_solid(IVec3::Z);
+
+    block.face_light = [
+        IVec3::Y,
+        IVec3::NEG_Y,
+        IVec3::X,
+        IVec3::NEG_X,
+        IVec3::Z,
+        IVec3::NEG_Z,
+    ]
+    .map(|offset| view.light(position + offset));
 }
 
 fn create_block_vertices(block: &Block) -> Vec<[f32; 3]> {
@@ -387,7 +795,7 @@ fn create_block_indices(block: &Block, mut skip: i32) -> (Vec<u32>, i32) {
     let mut indices = Vec::new();
 
     if block.visibility.top {
-        indices.extend_from_slice(&[0 + skip, 3 + skip, 1 + skip, 1 + skip, 3 + skip, 2 + skip])
+        indices.extend_from_slice(&[skip, 3 + skip, 1 + skip, 1 + skip, 3 + skip, 2 + skip])
     } else {
         skip -= 4;
     }
@@ -534,38 +942,130 @@ fn create_block_normals(block: &Block) -> Vec<[f32; 3]> {
     normals
 }
 
+fn create_block_colors(block: &Block) -> Vec<[f32; 4]> {
+    let faces = [
+        block.visibility.top,
+        block.visibility.bottom,
+        block.visibility.right,
+        block.visibility.left,
+        block.visibility.back,
+        block.visibility.front,
+    ];
+    let color = block.block_type.color();
+
+    faces
+        .iter()
+        .zip(block.face_light)
+        .filter(|(visible, _)| **visible)
+        .flat_map(|(_, light)| {
+            // Keep some ambient light so caves aren't pitch black.
+            let brightness = 0.2 + 0.8 * light as f32 / MAX_LIGHT as f32;
+            let shaded = [
+                color[0] * brightness,
+                color[1] * brightness,
+                color[2] * brightness,
+                color[3],
+            ];
+            [shaded; 4]
+        })
+        .collect()
+}
+
 const HEIGTH_MAP: [[u32; 2]; 3] = [[20, 10], [25, 15], [30, 20]];
-fn prepare_chunk(start_x: i32, start_z: i32, perlin: Perlin) -> Chunk {
-    let mut chunk = Chunk {
-        position: Vec3::new(start_x as f32, 0.0, start_z as f32),
-        blocks: Vec::new(),
-        mesh: Mesh::new(PrimitiveTopology::TriangleList),
-    };
+fn fill_terrain(grid: &mut ChunkGrid, generator: &ChunkGenerator) {
+    for x in grid.origin.x..grid.origin.x + grid.size.x {
+        for z in grid.origin.z..grid.origin.z + grid.size.z {
+            let height = generator.surface_height(x, z);
+            for y in grid.origin.y..height.min(grid.origin.y + grid.size.y) {
+                grid.set(IVec3::new(x, y, z), BlockType::Stone);
+            }
+            if let Some(water_level) = generator.water_level(x, z) {
+                for y in height..water_level.min(grid.origin.y + grid.size.y) {
+                    grid.set(IVec3::new(x, y, z), BlockType::Water);
+                }
+            }
+        }
+    }
+}
+
+/// Runs the stage that brings `chunk` to `status`. `neighbours` holds the
+/// neighbours required by [`ChunkStatus::neighbour_requirement`].
+pub fn run_stage(
+    status: ChunkStatus,
+    mut chunk: Chunk,
+    neighbours: &[Arc<Chunk>],
+    generator: &ChunkGenerator,
+) -> Chunk {
+    let surface = |x, z| generator.surface_height(x, z);
+    let water_level = |x, z| generator.water_level(x, z);
+    match status {
+        ChunkStatus::Empty => {}
+        ChunkStatus::Noise => match &generator.world {
+            Some(world) => world.load_chunk(&mut chunk.grid),
+            None => fill_terrain(&mut chunk.grid, generator),
+        },
+        // Imported chunks are complete, they only still need light and a mesh.
+        ChunkStatus::Surface
+        | ChunkStatus::Carvers
+        | ChunkStatus::Structures
+        | ChunkStatus::Features
+            if generator.world.is_some() => {}
+        ChunkStatus::Surface => {
+            materials::apply_materials(&mut chunk.grid, &generator.materials, surface);
+            surface::apply_surface_rules(
+                &mut chunk.grid,
+                &generator.surface_rules,
+                surface,
+                water_level,
+            );
+            if let Some(heightmap) = &generator.heightmap {
+                heightmap.apply_biomes(&mut chunk.grid, surface);
+            }
+        }
+        ChunkStatus::Carvers => carver::carve(&mut chunk.grid, &generator.carver, surface),
+        ChunkStatus::Structures => {
+            if let Some(layouts) = &generator.structures {
+                structures::place_structures(&mut chunk.grid, layouts, surface);
+            }
+            generator.schematics.paste_into(&mut chunk.grid);
+        }
+        ChunkStatus::Features => {
+            features::place_features(&mut chunk.grid, &generator.features, surface, water_level)
+        }
+        ChunkStatus::Light => chunk.light = light::compute_sky_light(&chunk, neighbours),
+        ChunkStatus::Meshed => build_mesh(&mut chunk, neighbours),
+    }
+    chunk
+}
 
-    for x in (start_x..).step_by(1).take(CHUNK_SIZE) {
-        for y in 0..MAX_HEIGHT {
-            for z in (start_z..).step_by(1).take(CHUNK_SIZE) {
-                if is_block_at(Vec3::new(x as f32, y as f32, z as f32), perlin) {
-                    let block = Block {
+fn build_mesh(chunk: &mut Chunk, neighbours: &[Arc<Chunk>]) {
+    let grid = &chunk.grid;
+    let mut blocks = Vec::new();
+    for x in grid.origin.x..grid.origin.x + grid.size.x {
+        for y in grid.origin.y..grid.origin.y + grid.size.y {
+            for z in grid.origin.z..grid.origin.z + grid.size.z {
+                let block_type = grid.get(IVec3::new(x, y, z));
+                if block_type.is_solid() {
+                    blocks.push(Block {
                         position: Vec3::new(x as f32, y as f32, z as f32),
                         visibility: Visibility::default(),
-                    };
-
-                    chunk.blocks.push(block);
+                        face_light: [MAX_LIGHT; 6],
+                        block_type,
+                    });
                 }
             }
         }
     }
-    chunk
-        .blocks
-        .iter_mut()
-        .for_each(|mut b| assign_visibility(&mut b, perlin));
+
+    let view = ChunkView { chunk, neighbours };
+    blocks.iter_mut().for_each(|b| assign_visibility(b, &view));
+    chunk.blocks = blocks;
+    chunk.mesh = Mesh::new(PrimitiveTopology::TriangleList);
 
     let vertices = chunk
         .blocks
         .iter()
-        .map(|b| create_block_vertices(&b))
-        .flatten()
+        .flat_map(create_block_vertices)
         .collect::<Vec<_>>();
     chunk
         .mesh
@@ -576,12 +1076,11 @@ fn prepare_chunk(start_x: i32, start_z: i32, perlin: Perlin) -> Chunk {
         chunk
             .blocks
             .iter()
-            .map(|b| {
-                let (indices, new_skip) = create_block_indices(&b, skip);
+            .flat_map(|b| {
+                let (indices, new_skip) = create_block_indices(b, skip);
                 skip = new_skip;
                 indices
             })
-            .flatten()
             .collect::<Vec<_>>(),
     );
     chunk.mesh.set_indices(Some(indices));
@@ -589,49 +1088,44 @@ fn prepare_chunk(start_x: i32, start_z: i32, perlin: Perlin) -> Chunk {
     let uvs = chunk
         .blocks
         .iter()
-        .map(|b| create_block_uvs(&b))
-        .flatten()
+        .flat_map(create_block_uvs)
         .collect::<Vec<_>>();
     chunk.mesh.insert_attribute(Mesh::ATTRIBUTE_UV_0, uvs);
 
     let normals = chunk
         .blocks
         .iter()
-        .map(|b| create_block_normals(&b))
-        .flatten()
+        .flat_map(create_block_normals)
         .collect::<Vec<_>>();
     chunk.mesh.insert_attribute(Mesh::ATTRIBUTE_NORMAL, normals);
 
-    chunk
+    let colors = chunk
+        .blocks
+        .iter()
+        .flat_map(create_block_colors)
+        .collect::<Vec<_>>();
+    chunk.mesh.insert_attribute(Mesh::ATTRIBUTE_COLOR, colors);
 }
 
-fn generate_chunk(
-    mut commands: Commands,
-    mut meshes: ResMut<Assets<Mesh>>,
-    mut materials: ResMut<Assets<StandardMaterial>>,
-    server: Res<AssetServer>,
-    mut chunk_tasks: Query<(Entity, &mut ComputeChunk)>,
-    mut loaded_chunks: ResMut<LoadedChunks>,
-) {
+pub fn spawn_chunk_mesh(
+    commands: &mut Commands,
+    meshes: &mut Assets<Mesh>,
+    materials: &mut Assets<StandardMaterial>,
+    server: &AssetServer,
+    chunk: &Chunk,
+) -> Entity {
     let handle: Handle<Image> = server.load("grass.png");
+    let mesh_handle = meshes.add(chunk.mesh.clone());
 
-    for (e, mut task) in &mut chunk_tasks {
-        if let Some(chunk) = future::block_on(future::poll_once(&mut task.0)) {
-            let mesh_handle = meshes.add(chunk.mesh.clone());
-
-            let chunk_data = PbrBundle {
-                mesh: mesh_handle,
-                material: materials.add(StandardMaterial {
-                    base_color_texture: Some(handle.clone()),
-                    ..default()
-                }),
-                ..default()
-            };
-
-            let id = format!("X{}Z{}", chunk.position.x, chunk.position.z);
-            loaded_chunks.chunks.insert(id, Some(chunk));
-            commands.spawn(chunk_data);
-            commands.entity(e).remove::<ComputeChunk>();
-        }
-    }
+    let chunk_data = PbrBundle {
+        mesh: mesh_handle,
+        material: materials.add(StandardMaterial {
+            base_color_texture: Some(handle),
+            ..default()
+        }),
+        ..default()
+    };
+
+    let pos = IVec2::new(chunk.grid.origin.x, chunk.grid.origin.z);
+    commands.spawn((chunk_data, ChunkEntity { pos })).id()
 }
@@ -0,0 +1,239 @@
+use bevy::{prelude::*, utils::HashMap};
+
+use super::{
+    pipeline::{ChunkStatus, LoadedChunks},
+    region::WorldStorage,
+    terrain::CHUNK_SIZE,
+};
+
+/// Priority of the chunks around [`ChunkLoader`]s.
+pub const LOADER_PRIORITY: i32 = 0;
+
+/// Chunks around where the player spawns, loaded before everything else.
+const SPAWN_RADIUS: i32 = 2;
+const SPAWN_PRIORITY: i32 = 10;
+/// Seconds the spawn area stays forced, long enough for the loaders around
+/// the player to take over.
+const SPAWN_TIMEOUT: f32 = 60.0;
+
+/// Chunks around a ticket's area that stay loaded without being requested.
+/// Meshing a chunk needs its neighbours lit, and lighting those needs their
+/// neighbours' features.
+const NEIGHBOUR_MARGIN: i32 = 2;
+
+/// Keeps the chunks within `radius` chunks of the entity loaded and meshed.
+#[derive(Component, Clone, Copy, Debug)]
+pub struct ChunkLoader {
+    pub radius: i32,
+}
+
+/// Keeps an area loaded independently of any entity, for the spawn area,
+/// scripted areas or simulation that runs without players.
+#[derive(Clone, Debug)]
+pub struct ForcedTicket {
+    /// Center in block coordinates.
+    pub center: IVec2,
+    /// Radius in chunks.
+    pub radius: i32,
+    /// Status the chunks are generated up to, `Features` for chunks that
+    /// don't need to be shown.
+    pub status: ChunkStatus,
+    /// Chunks of higher priority tickets are generated first.
+    pub priority: i32,
+    /// Seconds until the ticket is removed, `None` to keep it until
+    /// [`ChunkTickets::remove`].
+    pub timeout: Option<f32>,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub struct TicketId(u64);
+
+/// Circle of chunks a loader or forced ticket keeps loaded, in chunk
+/// coordinates.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+struct TicketArea {
+    center: IVec2,
+    radius: i32,
+    status: ChunkStatus,
+    priority: i32,
+}
+
+impl TicketArea {
+    /// Whether `chunk` is in the area grown by `margin` chunks. The chunk's
+    /// `z` coordinate is `y`.
+    fn contains(&self, chunk: IVec2, margin: i32) -> bool {
+        let radius = self.radius + margin;
+        (chunk - self.center).length_squared() <= radius * radius
+    }
+}
+
+#[derive(Resource, Default)]
+pub struct ChunkTickets {
+    forced: HashMap<TicketId, ForcedTicket>,
+    next_id: u64,
+    /// Areas chunks were last requested and unloaded for, to only do it again
+    /// when they changed.
+    areas: Vec<TicketArea>,
+    /// Chunks outside every area that the last unload pass kept because they
+    /// had unsaved changes. They are checked again until they are unloaded.
+    kept_unsaved: usize,
+}
+
+impl ChunkTickets {
+    /// Tickets with the spawn area around `position` forced for a while.
+    pub fn with_spawn(position: Vec3) -> Self {
+        let mut tickets = Self::default();
+        tickets.add(ForcedTicket {
+            center: IVec2::new(position.x.floor() as i32, position.z.floor() as i32),
+            radius: SPAWN_RADIUS,
+            status: ChunkStatus::Meshed,
+            priority: SPAWN_PRIORITY,
+            timeout: Some(SPAWN_TIMEOUT),
+        });
+        tickets
+    }
+
+    pub fn add(&mut self, ticket: ForcedTicket) -> TicketId {
+        let id = TicketId(self.next_id);
+        self.next_id += 1;
+        self.forced.insert(id, ticket);
+        id
+    }
+
+    pub fn remove(&mut self, id: TicketId) -> Option<ForcedTicket> {
+        self.forced.remove(&id)
+    }
+
+    pub fn get(&self, id: TicketId) -> Option<&ForcedTicket> {
+        self.forced.get(&id)
+    }
+
+    /// Squared distance in chunks from the chunk at `x`, `z` to the center of
+    /// the nearest area, which is where chunks are needed first.
+    pub fn distance_squared(&self, x: i32, z: i32) -> i32 {
+        let chunk_size = CHUNK_SIZE as i32;
+        let chunk = IVec2::new(x.div_euclid(chunk_size), z.div_euclid(chunk_size));
+        self.areas
+            .iter()
+            .map(|area| (chunk - area.center).length_squared())
+            .min()
+            .unwrap_or(i32::MAX)
+    }
+}
+
+fn chunk_of(x: f32, z: f32) -> IVec2 {
+    let chunk_size = CHUNK_SIZE as f32;
+    IVec2::new(
+        (x / chunk_size).floor() as i32,
+        (z / chunk_size).floor() as i32,
+    )
+}
+
+/// Requests the chunks of every loader and forced ticket and unloads the ones
+/// no ticket covers anymore. When the world is saved, chunks with unsaved
+/// changes stay loaded until a save has written them successfully and are
+/// unloaded on the next update after that. Without persistence nothing would ever save
+/// them, so they are unloaded right away.
+pub fn update_tickets(
+    time: Res<Time>,
+    loaders: Query<(&Transform, &ChunkLoader)>,
+    storage: Res<WorldStorage>,
+    mut tickets: ResMut<ChunkTickets>,
+    mut loaded_chunks: ResMut<LoadedChunks>,
+) {
+    tickets
+        .forced
+        .retain(|_, ticket| match &mut ticket.timeout {
+            Some(timeout) => {
+                *timeout -= time.delta_seconds();
+                *timeout > 0.0
+            }
+            None => true,
+        });
+
+    let mut areas = loaders
+        .iter()
+        .map(|(transform, loader)| TicketArea {
+            center: chunk_of(transform.translation.x, transform.translation.z),
+            radius: loader.radius,
+            status: ChunkStatus::Meshed,
+            priority: LOADER_PRIORITY,
+        })
+        .chain(tickets.forced.values().map(|ticket| TicketArea {
+            center: chunk_of(ticket.center.x as f32, ticket.center.y as f32),
+            radius: ticket.radius,
+            status: ticket.status,
+            priority: ticket.priority,
+        }))
+        .collect::<Vec<_>>();
+    // Keeps the comparison independent of the order of entities and tickets.
+    areas.sort_by_key(|area| {
+        (
+            area.center.x,
+            area.center.y,
+            area.radius,
+            area.status,
+            area.priority,
+        )
+    });
+    let areas_changed = areas != tickets.areas;
+    if !areas_changed && tickets.kept_unsaved == 0 {
+        return;
+    }
+    if areas_changed {
+        request_areas(&areas, &mut loaded_chunks);
+    }
+
+    let chunk_size = CHUNK_SIZE as i32;
+    let saving = storage.0.is_some();
+    let unused = loaded_chunks
+        .chunks
+        .values()
+        .filter(|entry| {
+            let chunk = IVec2::new(
+                entry.x.div_euclid(chunk_size),
+                entry.z.div_euclid(chunk_size),
+            );
+            !areas
+                .iter()
+                .any(|area| area.contains(chunk, NEIGHBOUR_MARGIN))
+        })
+        .map(|entry| (entry.x, entry.z, entry.dirty && saving))
+        .collect::<Vec<_>>();
+    tickets.kept_unsaved = 0;
+    for (x, z, unsaved) in unused {
+        if unsaved {
+            tickets.kept_unsaved += 1;
+        } else {
+            loaded_chunks.unload(x, z);
+        }
+    }
+    tickets.areas = areas;
+}
+
+/// Requests every chunk in `areas` with the highest status and priority of
+/// the areas it is in.
+fn request_areas(areas: &[TicketArea], loaded_chunks: &mut LoadedChunks) {
+    let mut wanted = HashMap::<IVec2, (ChunkStatus, i32)>::new();
+    for area in areas {
+        for x in -area.radius..=area.radius {
+            for z in -area.radius..=area.radius {
+                if !area.contains(area.center + IVec2::new(x, z), 0) {
+                    continue;
+                }
+                let (status, priority) = wanted
+                    .entry(area.center + IVec2::new(x, z))
+                    .or_insert((area.status, area.priority));
+                *status = (*status).max(area.status);
+                *priority = (*priority).max(area.priority);
+            }
+        }
+    }
+
+    let chunk_size = CHUNK_SIZE as i32;
+    for (chunk, (status, priority)) in wanted {
+        let (x, z) = (chunk.x * chunk_size, chunk.y * chunk_size);
+        loaded_chunks.request(x, z, status);
+        loaded_chunks.set_priority(x, z, priority);
+    }
+}
@@ -0,0 +1,414 @@
+use bevy::prelude::{IVec3, Quat, Vec3};
+use serde::Deserialize;
+
+use crate::utils::random::ChunkRng;
+
+use super::{features::FeatureBlock, terrain::BlockType};
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Deserialize)]
+pub enum TreeSpecies {
+    Oak,
+    Birch,
+    Spruce,
+    Jungle,
+    Dead,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum CanopyShape {
+    /// Round blob around the top of the trunk.
+    Sphere,
+    /// Layers that widen towards the bottom, like a spruce.
+    Cone,
+    None,
+}
+
+/// L-system describing the branch structure of a tree. Symbols:
+/// `F` grows one log forward, `L` places a leaf blob, `+`/`-` turn around the
+/// vertical axis, `&`/`^` pitch down/up, `[`/`]` push/pop the turtle state.
+/// Every other symbol is only used for rewriting.
+#[derive(Clone, Debug)]
+pub struct LSystem {
+    pub axiom: String,
+    pub rules: Vec<(char, String)>,
+    pub iterations: u32,
+    /// Turn angle in degrees.
+    pub angle: f32,
+}
+
+#[derive(Clone, Debug)]
+pub struct TreeParameters {
+    pub log: BlockType,
+    pub leaves: BlockType,
+    pub trunk_height: (i32, i32),
+    /// Trunks are `trunk_width × trunk_width` logs.
+    pub trunk_width: i32,
+    /// Chance of a branch growing out of each log in the upper half of the trunk.
+    pub branch_probability: f32,
+    pub branch_length: (i32, i32),
+    pub leaf_radius: i32,
+    pub canopy: CanopyShape,
+    /// Chance of a vine hanging from each leaf on the edge of the canopy.
+    pub vine_probability: f32,
+    /// Replaces the random branches when set. It is grown from the top of the trunk.
+    pub grammar: Option<LSystem>,
+}
+
+impl TreeSpecies {
+    pub fn parameters(self) -> TreeParameters {
+        match self {
+            TreeSpecies::Oak => TreeParameters {
+                log: BlockType::Log,
+                leaves: BlockType::Leaves,
+                trunk_height: (4, 6),
+                trunk_width: 1,
+                branch_probability: 0.15,
+                branch_length: (1, 2),
+                leaf_radius: 2,
+                canopy: CanopyShape::Sphere,
+                vine_probability: 0.0,
+                grammar: None,
+            },
+            TreeSpecies::Birch => TreeParameters {
+                log: BlockType::BirchLog,
+                leaves: BlockType::BirchLeaves,
+                trunk_height: (5, 7),
+                trunk_width: 1,
+                branch_probability: 0.0,
+                branch_length: (0, 0),
+                leaf_radius: 2,
+                canopy: CanopyShape::Sphere,
+                vine_probability: 0.0,
+                grammar: None,
+            },
+            TreeSpecies::Spruce => TreeParameters {
+                log: BlockType::SpruceLog,
+                leaves: BlockType::SpruceLeaves,
+                trunk_height: (7, 10),
+                trunk_width: 1,
+                branch_probability: 0.0,
+                branch_length: (0, 0),
+                leaf_radius: 3,
+                canopy: CanopyShape::Cone,
+                vine_probability: 0.0,
+                grammar: None,
+            },
+            TreeSpecies::Jungle => TreeParameters {
+                log: BlockType::JungleLog,
+                leaves: BlockType::JungleLeaves,
+                trunk_height: (10, 14),
+                trunk_width: 2,
+                branch_probability: 0.2,
+                branch_length: (2, 4),
+                leaf_radius: 4,
+                canopy: CanopyShape::Sphere,
+                vine_probability: 0.3,
+                grammar: None,
+            },
+            TreeSpecies::Dead => TreeParameters {
+                log: BlockType::Log,
+                leaves: BlockType::Air,
+                trunk_height: (3, 5),
+                trunk_width: 1,
+                branch_probability: 0.0,
+                branch_length: (0, 0),
+                leaf_radius: 0,
+                canopy: CanopyShape::None,
+                vine_probability: 0.0,
+                grammar: Some(LSystem {
+                    axiom: "X".to_string(),
+                    rules: vec![('X', "F[+&FX][-&FX]".to_string())],
+                    iterations: 2,
+                    angle: 60.0,
+                }),
+            },
+        }
+    }
+}
+
+impl LSystem {
+    pub fn expand(&self) -> String {
+        (0..self.iterations).fold(self.axiom.clone(), |current, _| {
+            current
+                .chars()
+                .map(|symbol| {
+                    self.rules
+                        .iter()
+                        .find(|(from, _)| *from == symbol)
+                        .map_or_else(|| symbol.to_string(), |(_, to)| to.clone())
+                })
+                .collect()
+        })
+    }
+}
+
+/// Builds the blocks of a tree standing on the block at the origin. Logs come
+/// first so stamping never lets leaves replace the trunk.
+pub fn generate_tree(parameters: &TreeParameters, rng: &mut ChunkRng) -> Vec<FeatureBlock> {
+    let height = rng.range_i32(parameters.trunk_height.0, parameters.trunk_height.1);
+    let mut logs = Vec::new();
+    let mut leaf_centers = Vec::new();
+
+    for y in 1..=height {
+        for x in 0..parameters.trunk_width {
+            for z in 0..parameters.trunk_width {
+                logs.push(IVec3::new(x, y, z));
+            }
+        }
+    }
+    let top = IVec3::new(0, height, 0);
+
+    match &parameters.grammar {
+        Some(grammar) => interpret(grammar, top, &mut logs, &mut leaf_centers),
+        None => {
+            grow_branches(parameters, height, rng, &mut logs, &mut leaf_centers);
+            leaf_centers.push(top);
+        }
+    }
+
+    let mut blocks = logs
+        .into_iter()
+        .map(|pos| (pos, parameters.log))
+        .collect::<Vec<_>>();
+    if parameters.leaves == BlockType::Air {
+        return blocks;
+    }
+
+    let mut leaves = Vec::new();
+    match parameters.canopy {
+        CanopyShape::Sphere => {
+            for center in &leaf_centers {
+                // Branch tips get smaller blobs than the main crown.
+                let radius = if *center == top {
+                    parameters.leaf_radius
+                } else {
+                    (parameters.leaf_radius - 1).max(1)
+                };
+                leaf_sphere(*center, radius, &mut leaves);
+            }
+        }
+        CanopyShape::Cone => leaf_cone(parameters, height, &mut leaves),
+        CanopyShape::None => {}
+    }
+
+    let vines = hang_vines(parameters, &leaves, rng);
+    blocks.extend(leaves.into_iter().map(|pos| (pos, parameters.leaves)));
+    blocks.extend(vines.into_iter().map(|pos| (pos, BlockType::Vines)));
+    blocks
+}
+
+fn grow_branches(
+    parameters: &TreeParameters,
+    height: i32,
+    rng: &mut ChunkRng,
+    logs: &mut Vec<IVec3>,
+    leaf_centers: &mut Vec<IVec3>,
+) {
+    const DIRECTIONS: [IVec3; 4] = [IVec3::X, IVec3::NEG_X, IVec3::Z, IVec3::NEG_Z];
+
+    for y in (height / 2).max(1)..height {
+        if !rng.chance(parameters.branch_probability) {
+            continue;
+        }
+        let direction = DIRECTIONS[rng.range_i32(0, 3) as usize];
+        let length = rng.range_i32(parameters.branch_length.0, parameters.branch_length.1);
+        let mut pos = IVec3::new(0, y, 0);
+        for step in 1..=length {
+            pos += direction;
+            // Branches rise every other block.
+            if step % 2 == 0 {
+                pos += IVec3::Y;
+            }
+            logs.push(pos);
+        }
+        leaf_centers.push(pos);
+    }
+}
+
+fn interpret(
+    grammar: &LSystem,
+    start: IVec3,
+    logs: &mut Vec<IVec3>,
+    leaf_centers: &mut Vec<IVec3>,
+) {
+    let angle = grammar.angle.to_radians();
+    let mut position = start.as_vec3();
+    let mut rotation = Quat::IDENTITY;
+    let mut stack = Vec::new();
+
+    for symbol in grammar.expand().chars() {
+        match symbol {
+            'F' => {
+                position += rotation * Vec3::Y;
+                logs.push(position.round().as_ivec3());
+            }
+            'L' => leaf_centers.push(position.round().as_ivec3()),
+            '+' => rotation = Quat::from_rotation_y(angle) * rotation,
+            '-' => rotation = Quat::from_rotation_y(-angle) * rotation,
+            '&' => rotation *= Quat::from_rotation_x(angle),
+            '^' => rotation *= Quat::from_rotation_x(-angle),
+            '[' => stack.push((position, rotation)),
+            ']' => {
+                if let Some((saved_position, saved_rotation)) = stack.pop() {
+                    position = saved_position;
+                    rotation = saved_rotation;
+                }
+            }
+            _ => {}
+        }
+    }
+}
+
+fn leaf_sphere(center: IVec3, radius: i32, leaves: &mut Vec<IVec3>) {
+    for x in -radius..=radius {
+        for y in -1..=radius {
+            for z in -radius..=radius {
+                if x * x + y * y + z * z <= radius * radius + 1 {
+                    leaves.push(center + IVec3::new(x, y, z));
+                }
+            }
+        }
+    }
+}
+
+fn leaf_cone(parameters: &TreeParameters, height: i32, leaves: &mut Vec<IVec3>) {
+    let canopy_height = (height * 3 / 4).max(1);
+    for layer in 0..=canopy_height {
+        let y = height + 1 - layer;
+        let mut radius = layer * parameters.leaf_radius / canopy_height;
+        // Every other layer is pulled in to give the spruce its jagged outline.
+        if layer % 2 == 1 {
+            radius = (radius - 1).max(0);
+        }
+        for x in -radius..=radius {
+            for z in -radius..=radius {
+                if x.abs() + z.abs() <= radius + 1 {
+                    leaves.push(IVec3::new(x, y, z));
+                }
+            }
+        }
+    }
+}
+
+fn hang_vines(parameters: &TreeParameters, leaves: &[IVec3], rng: &mut ChunkRng) -> Vec<IVec3> {
+    let mut vines = Vec::new();
+    if parameters.vine_probability <= 0.0 {
+        return vines;
+    }
+
+    for leaf in leaves {
+        let outward = if leaf.x.abs() >= parameters.leaf_radius {
+            IVec3::new(leaf.x.signum(), 0, 0)
+        } else if leaf.z.abs() >= parameters.leaf_radius {
+            IVec3::new(0, 0, leaf.z.signum())
+        } else {
+            continue;
+        };
+        if !rng.chance(parameters.vine_probability) {
+            continue;
+        }
+        let length = rng.range_i32(1, 4);
+        for y in 0..length {
+            vines.push(*leaf + outward - IVec3::new(0, y, 0));
+        }
+    }
+    vines
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SEEDS: std::ops::Range<u64> = 0..32;
+
+    fn generate(species: TreeSpecies, seed: u64) -> Vec<FeatureBlock> {
+        generate_tree(&species.parameters(), &mut ChunkRng::new(seed))
+    }
+
+    /// Logs stacked on the origin column, counted from the ground up.
+    fn trunk_height(blocks: &[FeatureBlock], log: BlockType) -> i32 {
+        (1..)
+            .take_while(|&y| blocks.contains(&(IVec3::new(0, y, 0), log)))
+            .count() as i32
+    }
+
+    #[test]
+    fn trunk_height_is_within_range() {
+        for species in [
+            TreeSpecies::Oak,
+            TreeSpecies::Birch,
+            TreeSpecies::Spruce,
+            TreeSpecies::Jungle,
+        ] {
+            let parameters = species.parameters();
+            let (min, max) = parameters.trunk_height;
+            for seed in SEEDS {
+                let height = trunk_height(&generate(species, seed), parameters.log);
+                assert!(
+                    (min..=max).contains(&height),
+                    "{:?} with seed {} has a trunk of {}",
+                    species,
+                    seed,
+                    height
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn spruce_canopy_narrows_towards_the_top() {
+        let leaves = TreeSpecies::Spruce.parameters().leaves;
+        for seed in SEEDS {
+            let blocks = generate(TreeSpecies::Spruce, seed);
+            let layers = blocks
+                .iter()
+                .filter(|(_, block)| *block == leaves)
+                .map(|(pos, _)| pos.y)
+                .collect::<std::collections::BTreeSet<_>>();
+            let extent = |y: i32| {
+                blocks
+                    .iter()
+                    .filter(|(pos, block)| *block == leaves && pos.y == y)
+                    .map(|(pos, _)| pos.x.abs() + pos.z.abs())
+                    .max()
+                    .unwrap_or(-1)
+            };
+            let bottom = *layers.first().unwrap();
+            let top = *layers.last().unwrap();
+            assert!(extent(bottom) > extent(top));
+            assert!(extent(top) <= 1);
+            // Alternating layers are pulled in, so compare every other one.
+            for y in bottom..=top - 2 {
+                assert!(extent(y) >= extent(y + 2), "seed {} layer {}", seed, y);
+            }
+        }
+    }
+
+    #[test]
+    fn jungle_trees_have_vines_outside_the_canopy() {
+        let parameters = TreeSpecies::Jungle.parameters();
+        for seed in SEEDS {
+            let vines = generate(TreeSpecies::Jungle, seed)
+                .into_iter()
+                .filter(|(_, block)| *block == BlockType::Vines)
+                .map(|(pos, _)| pos)
+                .collect::<Vec<_>>();
+            assert!(!vines.is_empty(), "seed {} grew no vines", seed);
+            for pos in vines {
+                assert!(
+                    pos.x.abs() > parameters.leaf_radius || pos.z.abs() > parameters.leaf_radius
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn dead_trees_have_no_leaves() {
+        let parameters = TreeSpecies::Dead.parameters();
+        for seed in SEEDS {
+            let blocks = generate(TreeSpecies::Dead, seed);
+            assert!(trunk_height(&blocks, parameters.log) > parameters.trunk_height.0);
+            assert!(blocks.iter().all(|(_, block)| *block == parameters.log));
+        }
+    }
+}
@@ -0,0 +1,347 @@
+use std::{fs, io, path::Path, sync::OnceLock};
+
+use bevy::prelude::*;
+use image::RgbImage;
+
+use crate::{plugins::camera::camera::FlyCamera, utils::nbt::invalid_data};
+
+use super::{
+    mesh_export::TEXTURE_PATH,
+    pipeline::LoadedChunks,
+    schematic::Schematic,
+    terrain::{BlockType, CHUNK_SIZE, MAX_HEIGHT},
+};
+
+/// Folder exported models are written to.
+pub const EXPORT_DIR: &str = "exports";
+
+const VERSION: i32 = 150;
+
+/// Largest model MagicaVoxel accepts along every axis.
+const MAX_SIZE: i32 = 256;
+
+/// Cells of the block texture's 3 by 4 grid the faces are drawn from, as
+/// column and row. Matches the UVs of the chunk meshes.
+const FACE_CELLS: [(u32, u32); 6] = [(1, 1), (1, 3), (1, 2), (1, 0), (0, 1), (2, 1)];
+
+/// Reads the first model of a MagicaVoxel file. Colors are mapped to the block
+/// with the closest color and empty voxels leave the world untouched.
+///
+/// MagicaVoxel's Z axis points up, it becomes our Y axis.
+pub fn read_vox(bytes: &[u8]) -> io::Result<Schematic> {
+    let mut reader = ChunkReader { bytes, pos: 0 };
+    if reader.take(4)? != b"VOX " {
+        return Err(invalid_data("not a MagicaVoxel file"));
+    }
+    reader.i32()?;
+    let (id, content) = reader.chunk()?;
+    if id != *b"MAIN" || !content.is_empty() {
+        return Err(invalid_data("missing MAIN chunk"));
+    }
+
+    let mut size = None;
+    let mut voxels: Option<&[u8]> = None;
+    let mut palette = None;
+    while reader.pos < bytes.len() {
+        let (id, content) = reader.chunk()?;
+        match &id {
+            b"SIZE" if size.is_none() => {
+                let mut content = ChunkReader {
+                    bytes: content,
+                    pos: 0,
+                };
+                let (x, y, z) = (content.i32()?, content.i32()?, content.i32()?);
+                size = Some(IVec3::new(x, z, y));
+            }
+            b"XYZI" if voxels.is_none() => {
+                let mut content = ChunkReader {
+                    bytes: content,
+                    pos: 0,
+                };
+                let count = content.i32()?.max(0) as usize;
+                voxels = Some(content.take(count.saturating_mul(4))?);
+            }
+            b"RGBA" if content.len() >= 256 * 4 => palette = Some(content),
+            _ => {}
+        }
+    }
+
+    let size = size.ok_or_else(|| invalid_data("missing SIZE chunk"))?;
+    let voxels = voxels.ok_or_else(|| invalid_data("missing XYZI chunk"))?;
+    if size.cmplt(IVec3::ONE).any() || size.cmpgt(IVec3::splat(MAX_SIZE)).any() {
+        return Err(invalid_data(format!("invalid model size {}", size)));
+    }
+
+    let mut schematic = Schematic {
+        size,
+        blocks: vec![None; (size.x * size.y * size.z) as usize],
+    };
+    for voxel in voxels.chunks_exact(4) {
+        let pos = IVec3::new(voxel[0] as i32, voxel[2] as i32, voxel[1] as i32);
+        if pos.cmpge(size).any() {
+            return Err(invalid_data(format!(
+                "voxel at {} is outside the model",
+                pos
+            )));
+        }
+        let block = match palette {
+            // Palette entry `i` holds the color of color index `i + 1`.
+            Some(palette) => {
+                let entry = (voxel[3] as usize + 255) % 256 * 4;
+                closest_block([palette[entry], palette[entry + 1], palette[entry + 2]])
+            }
+            None => BlockType::Stone,
+        };
+        let index = (pos.x + pos.z * size.x + pos.y * size.x * size.z) as usize;
+        schematic.blocks[index] = Some(block);
+    }
+    Ok(schematic)
+}
+
+/// Writes `schematic` as a MagicaVoxel model. Air and untouched positions are
+/// left empty. The palette holds the colors of the block types used, in
+/// declaration order, so writing a model read from our own file gives the
+/// same bytes again.
+pub fn write_vox(schematic: &Schematic) -> io::Result<Vec<u8>> {
+    let size = schematic.size;
+    if size.cmplt(IVec3::ONE).any() || size.cmpgt(IVec3::splat(MAX_SIZE)).any() {
+        return Err(invalid_data(format!(
+            "models must be 1 to {} voxels wide, not {}",
+            MAX_SIZE, size
+        )));
+    }
+
+    let used = BlockType::ALL
+        .into_iter()
+        .filter(|block| *block != BlockType::Air)
+        .filter(|block| schematic.blocks.contains(&Some(*block)))
+        .collect::<Vec<_>>();
+
+    let mut voxels = Vec::new();
+    for y in 0..size.y {
+        for z in 0..size.z {
+            for x in 0..size.x {
+                let Some(block) = schematic.get(IVec3::new(x, y, z)) else {
+                    continue;
+                };
+                let Some(index) = used.iter().position(|b| *b == block) else {
+                    continue;
+                };
+                voxels.extend([x as u8, z as u8, y as u8, index as u8 + 1]);
+            }
+        }
+    }
+
+    let mut size_chunk = Vec::new();
+    for value in [size.x, size.z, size.y] {
+        size_chunk.extend(value.to_le_bytes());
+    }
+    let mut xyzi = ((voxels.len() / 4) as i32).to_le_bytes().to_vec();
+    xyzi.extend(voxels);
+    let mut rgba = vec![0u8; 256 * 4];
+    for (i, block) in used.iter().enumerate() {
+        rgba[i * 4..i * 4 + 4].copy_from_slice(&color_bytes(*block));
+    }
+
+    let mut children = Vec::new();
+    write_chunk(&mut children, b"SIZE", &size_chunk, &[]);
+    write_chunk(&mut children, b"XYZI", &xyzi, &[]);
+    write_chunk(&mut children, b"RGBA", &rgba, &[]);
+
+    let mut bytes = b"VOX ".to_vec();
+    bytes.extend(VERSION.to_le_bytes());
+    write_chunk(&mut bytes, b"MAIN", &[], &children);
+    Ok(bytes)
+}
+
+fn write_chunk(out: &mut Vec<u8>, id: &[u8; 4], content: &[u8], children: &[u8]) {
+    out.extend(id);
+    out.extend((content.len() as i32).to_le_bytes());
+    out.extend((children.len() as i32).to_le_bytes());
+    out.extend(content);
+    out.extend(children);
+}
+
+/// Color of `block` as it is drawn: the average of the texture's faces
+/// multiplied with the block's tint.
+fn color_bytes(block: BlockType) -> [u8; 4] {
+    static COLORS: OnceLock<[[u8; 4]; BlockType::ALL.len()]> = OnceLock::new();
+    let colors = COLORS.get_or_init(|| {
+        let texture = match image::open(TEXTURE_PATH) {
+            Ok(image) => average_faces(&image.into_rgb8()),
+            Err(e) => {
+                warn!(
+                    "Failed to read {}, exporting the block tints only: {}",
+                    TEXTURE_PATH, e
+                );
+                [1.0; 3]
+            }
+        };
+        BlockType::ALL.map(|block| {
+            let tint = block.color();
+            let [r, g, b] = [0, 1, 2].map(|i| (texture[i] * tint[i] * 255.0).round() as u8);
+            [r, g, b, 255]
+        })
+    });
+    colors[block as usize]
+}
+
+/// Average color of the texture's [`FACE_CELLS`], from 0 to 1.
+fn average_faces(texture: &RgbImage) -> [f32; 3] {
+    let (cell_width, cell_height) = (texture.width() / 3, texture.height() / 4);
+    let mut sum = [0u64; 3];
+    for (column, row) in FACE_CELLS {
+        for y in row * cell_height..(row + 1) * cell_height {
+            for x in column * cell_width..(column + 1) * cell_width {
+                let pixel = texture.get_pixel(x, y);
+                for i in 0..3 {
+                    sum[i] += pixel[i] as u64;
+                }
+            }
+        }
+    }
+    let count = (FACE_CELLS.len() as u64 * (cell_width * cell_height) as u64).max(1);
+    sum.map(|sum| sum as f32 / count as f32 / 255.0)
+}
+
+fn closest_block(color: [u8; 3]) -> BlockType {
+    BlockType::ALL
+        .into_iter()
+        .filter(|block| *block != BlockType::Air)
+        .min_by_key(|block| {
+            let block_color = color_bytes(*block);
+            (0..3)
+                .map(|i| (block_color[i] as i32 - color[i] as i32).pow(2))
+                .sum::<i32>()
+        })
+        .unwrap_or(BlockType::Stone)
+}
+
+struct ChunkReader<'a> {
+    bytes: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> ChunkReader<'a> {
+    fn take(&mut self, len: usize) -> io::Result<&'a [u8]> {
+        let end = self
+            .pos
+            .checked_add(len)
+            .filter(|end| *end <= self.bytes.len())
+            .ok_or_else(|| invalid_data("unexpected end of MagicaVoxel data"))?;
+        let slice = &self.bytes[self.pos..end];
+        self.pos = end;
+        Ok(slice)
+    }
+
+    fn i32(&mut self) -> io::Result<i32> {
+        Ok(i32::from_le_bytes(self.take(4)?.try_into().unwrap()))
+    }
+
+    /// Reads a chunk header and its content. Children follow in the stream
+    /// and are read as chunks of their own.
+    fn chunk(&mut self) -> io::Result<([u8; 4], &'a [u8])> {
+        let id: [u8; 4] = self.take(4)?.try_into().unwrap();
+        let content = self.i32()?.max(0) as usize;
+        self.i32()?;
+        Ok((id, self.take(content)?))
+    }
+}
+
+/// Writes `schematic` to a `.vox` file.
+pub fn export_vox(schematic: &Schematic, path: impl AsRef<Path>) -> io::Result<()> {
+    let path = path.as_ref();
+    if let Some(parent) = path.parent() {
+        fs::create_dir_all(parent)?;
+    }
+    fs::write(path, write_vox(schematic)?)
+}
+
+/// Exports the chunk under the camera to [`EXPORT_DIR`] when F6 is pressed.
+pub fn export_chunk_on_key(
+    keys: Res<Input<KeyCode>>,
+    camera: Query<&Transform, With<FlyCamera>>,
+    loaded_chunks: Res<LoadedChunks>,
+) {
+    if !keys.just_pressed(KeyCode::F6) {
+        return;
+    }
+    let Ok(transform) = camera.get_single() else {
+        return;
+    };
+    let chunk_size = CHUNK_SIZE as i32;
+    let min = IVec3::new(
+        (transform.translation.x.floor() as i32).div_euclid(chunk_size) * chunk_size,
+        0,
+        (transform.translation.z.floor() as i32).div_euclid(chunk_size) * chunk_size,
+    );
+    let size = IVec3::new(chunk_size, MAX_HEIGHT as i32, chunk_size);
+    let path = Path::new(EXPORT_DIR).join(format!("X{}Z{}.vox", min.x, min.z));
+
+    match Schematic::capture(&loaded_chunks, min, size)
+        .and_then(|schematic| export_vox(&schematic, &path))
+    {
+        Ok(()) => info!("Exported chunk to {}", path.display()),
+        Err(e) => warn!("Failed to export {}: {}", path.display(), e),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn models_round_trip_byte_for_byte() {
+        let size = IVec3::new(4, 3, 2);
+        let palette = [
+            None,
+            Some(BlockType::Stone),
+            Some(BlockType::Grass),
+            Some(BlockType::Water),
+            Some(BlockType::Log),
+            Some(BlockType::Leaves),
+        ];
+        let schematic = Schematic {
+            size,
+            blocks: (0..size.x * size.y * size.z)
+                .map(|i| palette[(i * 7 % 6) as usize])
+                .collect(),
+        };
+
+        let bytes = write_vox(&schematic).unwrap();
+        let model = read_vox(&bytes).unwrap();
+        assert_eq!(model.size, size);
+        assert_eq!(model.blocks, schematic.blocks);
+        assert_eq!(write_vox(&model).unwrap(), bytes);
+    }
+
+    #[test]
+    fn rejects_models_that_are_too_large() {
+        let schematic = Schematic {
+            size: IVec3::new(MAX_SIZE + 1, 1, 1),
+            blocks: vec![Some(BlockType::Stone); MAX_SIZE as usize + 1],
+        };
+        assert!(write_vox(&schematic).is_err());
+    }
+
+    #[test]
+    fn block_colors_come_from_the_texture() {
+        // Grass isn't tinted, so its color is the texture's own.
+        assert_ne!(color_bytes(BlockType::Grass), [255; 4]);
+        for block in BlockType::ALL.into_iter().skip(1) {
+            assert_eq!(
+                closest_block(color_bytes(block)[..3].try_into().unwrap()),
+                block
+            );
+        }
+    }
+
+    #[test]
+    fn averages_the_face_cells() {
+        let mut texture = RgbImage::new(3, 4);
+        for (column, row) in FACE_CELLS {
+            texture.put_pixel(column, row, image::Rgb([255, 51, 0]));
+        }
+        assert_eq!(average_faces(&texture), [1.0, 0.2, 0.0]);
+    }
+}
@@ -0,0 +1,311 @@
+use std::{
+    fs, io,
+    path::{Path, PathBuf},
+};
+
+use bevy::prelude::warn;
+
+use super::{
+    edits::EDITS_PATH,
+    level::{LevelMetadata, LEVEL_FILE},
+    region::{self, PersistenceSettings, WorldStorage, PERSISTENCE_PATH},
+    terrain::WORLD_SEED,
+};
+
+/// Preset new worlds are created with, the settings under `assets/terrain`.
+pub const DEFAULT_PRESET: &str = "default";
+
+/// Folder of the other presets, each a folder of settings files overriding
+/// the default ones.
+const PRESETS_DIR: &str = "assets/presets";
+
+/// Folder in a world's folder holding the generator settings it was created
+/// with.
+pub const GENERATOR_DIR: &str = "generator";
+
+/// A world found in the saves folder.
+#[derive(Clone, Debug)]
+pub struct WorldSummary {
+    /// Name of the world's folder, which identifies it.
+    pub dir: String,
+    pub level: LevelMetadata,
+}
+
+/// The worlds in a saves folder, each in its own folder with a [`LEVEL_FILE`].
+pub struct Worlds {
+    saves_dir: PathBuf,
+}
+
+impl Worlds {
+    pub fn new(saves_dir: impl Into<PathBuf>) -> Self {
+        Self {
+            saves_dir: saves_dir.into(),
+        }
+    }
+
+    pub fn path(&self, dir: &str) -> PathBuf {
+        self.saves_dir.join(dir)
+    }
+
+    /// Every world in the folder, the most recently played first. Folders
+    /// with unreadable metadata are skipped.
+    pub fn list(&self) -> io::Result<Vec<WorldSummary>> {
+        let entries = match fs::read_dir(&self.saves_dir) {
+            Ok(entries) => entries,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(Vec::new()),
+            Err(e) => return Err(e),
+        };
+        let mut worlds = Vec::new();
+        for entry in entries {
+            let entry = entry?;
+            if !entry.file_type()?.is_dir() || !entry.path().join(LEVEL_FILE).exists() {
+                continue;
+            }
+            match LevelMetadata::read(&entry.path()) {
+                Ok(level) => worlds.push(WorldSummary {
+                    dir: entry.file_name().to_string_lossy().into_owned(),
+                    level,
+                }),
+                Err(e) => warn!("Skipping world {}: {}", entry.path().display(), e),
+            }
+        }
+        worlds.sort_by(|a, b| {
+            b.level
+                .last_played
+                .cmp(&a.level.last_played)
+                .then_with(|| a.dir.cmp(&b.dir))
+        });
+        Ok(worlds)
+    }
+
+    pub fn open(&self, dir: &str) -> io::Result<WorldSummary> {
+        Ok(WorldSummary {
+            dir: dir.to_string(),
+            level: LevelMetadata::read(&self.path(dir))?,
+        })
+    }
+
+    /// Creates a world in a new folder named after it, copying the generator
+    /// settings of `preset` into it so later changes to the preset don't
+    /// change its terrain.
+    pub fn create(&self, name: &str, seed: u32, preset: &str) -> io::Result<WorldSummary> {
+        let dir = self.unused_dir(name);
+        self.create_in(&dir, name, seed, preset)
+    }
+
+    /// Creates a world in the folder `dir`. The folder may already hold
+    /// chunks saved before worlds had metadata, but not another world.
+    pub fn create_in(
+        &self,
+        dir: &str,
+        name: &str,
+        seed: u32,
+        preset: &str,
+    ) -> io::Result<WorldSummary> {
+        let path = self.path(dir);
+        if path.join(LEVEL_FILE).exists() {
+            return Err(io::Error::new(
+                io::ErrorKind::AlreadyExists,
+                format!("world {} already exists", path.display()),
+            ));
+        }
+        let new_dir = !path.exists();
+        fs::create_dir_all(&path)?;
+        let created = copy_preset(preset, &path.join(GENERATOR_DIR)).and_then(|_| {
+            let level = LevelMetadata::new(name, seed, preset);
+            region::write_atomic(&path.join(LEVEL_FILE), level.to_ron()?.as_bytes())?;
+            Ok(level)
+        });
+        match created {
+            Ok(level) => Ok(WorldSummary {
+                dir: dir.to_string(),
+                level,
+            }),
+            Err(e) => {
+                if new_dir {
+                    let _ = fs::remove_dir_all(&path);
+                }
+                Err(e)
+            }
+        }
+    }
+
+    /// Changes the name shown for a world, its folder keeps its name.
+    pub fn rename(&self, dir: &str, name: &str) -> io::Result<()> {
+        let path = self.path(dir);
+        let mut level = LevelMetadata::read(&path)?;
+        level.name = name.to_string();
+        region::write_atomic(&path.join(LEVEL_FILE), level.to_ron()?.as_bytes())
+    }
+
+    /// Copies a world, with its saved chunks, into a new folder under a new
+    /// name.
+    pub fn duplicate(&self, dir: &str, name: &str) -> io::Result<WorldSummary> {
+        let source = self.path(dir);
+        let mut level = LevelMetadata::read(&source)?;
+        let copy = self.unused_dir(name);
+        let path = self.path(&copy);
+        let copied = copy_dir(&source, &path).and_then(|_| {
+            level.name = name.to_string();
+            region::write_atomic(&path.join(LEVEL_FILE), level.to_ron()?.as_bytes())
+        });
+        if let Err(e) = copied {
+            let _ = fs::remove_dir_all(&path);
+            return Err(e);
+        }
+        Ok(WorldSummary { dir: copy, level })
+    }
+
+    pub fn delete(&self, dir: &str) -> io::Result<()> {
+        let path = self.path(dir);
+        if !path.join(LEVEL_FILE).exists() {
+            return Err(io::Error::new(
+                io::ErrorKind::NotFound,
+                format!("{} is not a world", path.display()),
+            ));
+        }
+        fs::remove_dir_all(path)
+    }
+
+    /// Folder name for a world called `name`: its letters and digits, with a
+    /// number appended when the folder is taken.
+    fn unused_dir(&self, name: &str) -> String {
+        let base = name
+            .trim()
+            .chars()
+            .map(|c| {
+                if c.is_alphanumeric() || c == '-' || c == '_' {
+                    c
+                } else {
+                    '_'
+                }
+            })
+            .collect::<String>();
+        let base = if base.is_empty() {
+            "world".to_string()
+        } else {
+            base
+        };
+        let mut dir = base.clone();
+        let mut number = 2;
+        while self.path(&dir).exists() {
+            dir = format!("{}-{}", base, number);
+            number += 1;
+        }
+        dir
+    }
+}
+
+/// Folder of a preset's settings files.
+fn preset_dir(preset: &str) -> PathBuf {
+    if preset == DEFAULT_PRESET {
+        Path::new(PERSISTENCE_PATH).parent().unwrap().to_path_buf()
+    } else {
+        Path::new(PRESETS_DIR).join(preset)
+    }
+}
+
+/// Settings files under `assets/terrain` that aren't part of a world.
+const APP_SETTINGS: [&str; 2] = [PERSISTENCE_PATH, EDITS_PATH];
+
+/// Copies the default settings files and then the preset's over them, leaving
+/// out the [`APP_SETTINGS`].
+fn copy_preset(preset: &str, target: &Path) -> io::Result<()> {
+    let dir = preset_dir(preset);
+    if !dir.is_dir() {
+        return Err(io::Error::new(
+            io::ErrorKind::NotFound,
+            format!("unknown generator preset {}", preset),
+        ));
+    }
+    fs::create_dir_all(target)?;
+    for source in [preset_dir(DEFAULT_PRESET), dir] {
+        for entry in fs::read_dir(source)? {
+            let path = entry?.path();
+            let app_setting = APP_SETTINGS
+                .iter()
+                .any(|setting| path.file_name() == Path::new(setting).file_name());
+            if path.extension().is_some_and(|ext| ext == "ron") && !app_setting {
+                fs::copy(&path, target.join(path.file_name().unwrap()))?;
+            }
+        }
+    }
+    Ok(())
+}
+
+fn copy_dir(source: &Path, target: &Path) -> io::Result<()> {
+    fs::create_dir(target)?;
+    for entry in fs::read_dir(source)? {
+        let entry = entry?;
+        let path = target.join(entry.file_name());
+        if entry.file_type()?.is_dir() {
+            copy_dir(&entry.path(), &path)?;
+        } else {
+            fs::copy(entry.path(), path)?;
+        }
+    }
+    Ok(())
+}
+
+/// The world the app plays in.
+pub struct OpenWorld {
+    pub level: LevelMetadata,
+    /// Folder of the world, `None` when it isn't saved and uses the settings
+    /// under `assets/terrain`.
+    pub dir: Option<PathBuf>,
+    pub storage: WorldStorage,
+}
+
+impl OpenWorld {
+    /// Opens the world configured in the persistence settings, creating it
+    /// when it doesn't exist yet. Falls back to an unsaved world with the
+    /// default settings when saving is disabled or the world can't be opened.
+    pub fn open(settings: &PersistenceSettings) -> Self {
+        let seed = settings.seed.unwrap_or(WORLD_SEED);
+        let unsaved = || Self {
+            level: LevelMetadata::new(&settings.world, seed, DEFAULT_PRESET),
+            dir: None,
+            storage: WorldStorage(None),
+        };
+        if !settings.enabled {
+            return unsaved();
+        }
+
+        let worlds = Worlds::new(&settings.saves);
+        let path = worlds.path(&settings.world);
+        let world = if path.join(LEVEL_FILE).exists() {
+            worlds.open(&settings.world)
+        } else {
+            worlds.create_in(&settings.world, &settings.world, seed, DEFAULT_PRESET)
+        };
+        match world {
+            Ok(world) => Self::saved(path, world.level, settings),
+            Err(e) => {
+                warn!("Failed to open world {}: {}", path.display(), e);
+                unsaved()
+            }
+        }
+    }
+
+    /// Opens the world in `dir` whose metadata was already read.
+    pub fn saved(dir: PathBuf, level: LevelMetadata, settings: &PersistenceSettings) -> Self {
+        Self {
+            level,
+            storage: WorldStorage::open(dir.clone(), settings),
+            dir: Some(dir),
+        }
+    }
+
+    /// Path of a settings file, the world's own copy if it has one.
+    pub fn settings_path(&self, default: &str) -> String {
+        let own = self.dir.as_ref().and_then(|dir| {
+            let path = dir
+                .join(GENERATOR_DIR)
+                .join(Path::new(default).file_name()?);
+            path.exists().then_some(path)
+        });
+        own.map_or(default.to_string(), |path| {
+            path.to_string_lossy().into_owned()
+        })
+    }
+}
@@ -0,0 +1,16 @@
+use std::fs;
+
+use bevy::prelude::warn;
+use serde::de::DeserializeOwned;
+
+/// Reads a RON settings file, falling back to the defaults when it is missing
+/// or can't be parsed.
+pub fn load_ron_or_default<T: DeserializeOwned + Default>(path: &str) -> T {
+    match fs::read_to_string(path) {
+        Ok(content) => ron::from_str(&content).unwrap_or_else(|e| {
+            warn!("Invalid settings in {}: {}", path, e);
+            T::default()
+        }),
+        Err(_) => T::default(),
+    }
+}
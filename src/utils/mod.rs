@@ -1 +1,5 @@
+pub mod config;
+pub mod nbt;
 pub mod noise;
+pub mod random;
+pub mod region_cache;
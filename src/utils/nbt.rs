@@ -0,0 +1,362 @@
+use std::io::{self, Read};
+
+use bevy::utils::HashMap;
+use flate2::read::{GzDecoder, ZlibDecoder};
+
+/// A value of Minecraft's Named Binary Tag format.
+#[derive(Clone, Debug, PartialEq)]
+pub enum Tag {
+    Byte(i8),
+    Short(i16),
+    Int(i32),
+    Long(i64),
+    Float(f32),
+    Double(f64),
+    ByteArray(Vec<i8>),
+    String(String),
+    List(Vec<Tag>),
+    Compound(HashMap<String, Tag>),
+    IntArray(Vec<i32>),
+    LongArray(Vec<i64>),
+}
+
+impl Tag {
+    /// Child of a compound tag.
+    pub fn get(&self, key: &str) -> Option<&Tag> {
+        match self {
+            Tag::Compound(children) => children.get(key),
+            _ => None,
+        }
+    }
+
+    /// Any integer tag widened to an `i64`.
+    pub fn as_i64(&self) -> Option<i64> {
+        match *self {
+            Tag::Byte(value) => Some(value as i64),
+            Tag::Short(value) => Some(value as i64),
+            Tag::Int(value) => Some(value as i64),
+            Tag::Long(value) => Some(value),
+            _ => None,
+        }
+    }
+
+    pub fn as_i32(&self) -> Option<i32> {
+        self.as_i64().map(|value| value as i32)
+    }
+
+    pub fn as_str(&self) -> Option<&str> {
+        match self {
+            Tag::String(value) => Some(value),
+            _ => None,
+        }
+    }
+
+    pub fn as_list(&self) -> Option<&[Tag]> {
+        match self {
+            Tag::List(values) => Some(values),
+            _ => None,
+        }
+    }
+
+    pub fn as_compound(&self) -> Option<&HashMap<String, Tag>> {
+        match self {
+            Tag::Compound(children) => Some(children),
+            _ => None,
+        }
+    }
+
+    pub fn as_byte_array(&self) -> Option<&[i8]> {
+        match self {
+            Tag::ByteArray(values) => Some(values),
+            _ => None,
+        }
+    }
+
+    pub fn as_int_array(&self) -> Option<&[i32]> {
+        match self {
+            Tag::IntArray(values) => Some(values),
+            _ => None,
+        }
+    }
+
+    pub fn as_long_array(&self) -> Option<&[i64]> {
+        match self {
+            Tag::LongArray(values) => Some(values),
+            _ => None,
+        }
+    }
+}
+
+pub fn invalid_data(message: impl Into<String>) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message.into())
+}
+
+/// Reads the root tag of an NBT file, which may be gzip or zlib compressed
+/// or not compressed at all. Returns the root's name and value.
+pub fn read(bytes: &[u8]) -> io::Result<(String, Tag)> {
+    let mut data = Vec::new();
+    match bytes {
+        [0x1f, 0x8b, ..] => GzDecoder::new(bytes).read_to_end(&mut data)?,
+        [0x78, ..] => ZlibDecoder::new(bytes).read_to_end(&mut data)?,
+        _ => return read_uncompressed(bytes),
+    };
+    read_uncompressed(&data)
+}
+
+pub fn read_uncompressed(bytes: &[u8]) -> io::Result<(String, Tag)> {
+    let mut reader = Reader { bytes, pos: 0 };
+    let id = reader.u8()?;
+    if id != 10 {
+        return Err(invalid_data(format!("root tag has type {}", id)));
+    }
+    let name = reader.string()?;
+    let tag = reader.payload(id, 0)?;
+    Ok((name, tag))
+}
+
+/// Compounds and lists nested deeper than this are rejected.
+const MAX_DEPTH: usize = 512;
+
+struct Reader<'a> {
+    bytes: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> Reader<'a> {
+    fn take(&mut self, len: usize) -> io::Result<&'a [u8]> {
+        let end = self
+            .pos
+            .checked_add(len)
+            .filter(|end| *end <= self.bytes.len())
+            .ok_or_else(|| invalid_data("unexpected end of NBT data"))?;
+        let slice = &self.bytes[self.pos..end];
+        self.pos = end;
+        Ok(slice)
+    }
+
+    fn array<const N: usize>(&mut self) -> io::Result<[u8; N]> {
+        Ok(self.take(N)?.try_into().unwrap())
+    }
+
+    fn u8(&mut self) -> io::Result<u8> {
+        Ok(self.array::<1>()?[0])
+    }
+
+    fn i16(&mut self) -> io::Result<i16> {
+        Ok(i16::from_be_bytes(self.array()?))
+    }
+
+    fn i32(&mut self) -> io::Result<i32> {
+        Ok(i32::from_be_bytes(self.array()?))
+    }
+
+    fn i64(&mut self) -> io::Result<i64> {
+        Ok(i64::from_be_bytes(self.array()?))
+    }
+
+    fn length(&mut self) -> io::Result<usize> {
+        let len = self.i32()?;
+        usize::try_from(len).map_err(|_| invalid_data(format!("negative length {}", len)))
+    }
+
+    fn string(&mut self) -> io::Result<String> {
+        let len = u16::from_be_bytes(self.array()?) as usize;
+        // Java's modified UTF-8 only differs for unusual characters.
+        Ok(String::from_utf8_lossy(self.take(len)?).into_owned())
+    }
+
+    fn payload(&mut self, id: u8, depth: usize) -> io::Result<Tag> {
+        if depth > MAX_DEPTH {
+            return Err(invalid_data("NBT data is nested too deeply"));
+        }
+        Ok(match id {
+            1 => Tag::Byte(self.u8()? as i8),
+            2 => Tag::Short(self.i16()?),
+            3 => Tag::Int(self.i32()?),
+            4 => Tag::Long(self.i64()?),
+            5 => Tag::Float(f32::from_bits(self.i32()? as u32)),
+            6 => Tag::Double(f64::from_bits(self.i64()? as u64)),
+            7 => {
+                let len = self.length()?;
+                Tag::ByteArray(self.take(len)?.iter().map(|b| *b as i8).collect())
+            }
+            8 => Tag::String(self.string()?),
+            9 => {
+                let element = self.u8()?;
+                let len = self.length()?;
+                if element == 0 {
+                    Tag::List(Vec::new())
+                } else {
+                    let mut values = Vec::with_capacity(len.min(self.bytes.len()));
+                    for _ in 0..len {
+                        values.push(self.payload(element, depth + 1)?);
+                    }
+                    Tag::List(values)
+                }
+            }
+            10 => {
+                let mut children = HashMap::new();
+                loop {
+                    let child = self.u8()?;
+                    if child == 0 {
+                        break;
+                    }
+                    let name = self.string()?;
+                    children.insert(name, self.payload(child, depth + 1)?);
+                }
+                Tag::Compound(children)
+            }
+            11 => {
+                let len = self.length()?;
+                let values = self.take(len.saturating_mul(4))?;
+                Tag::IntArray(
+                    values
+                        .chunks_exact(4)
+                        .map(|b| i32::from_be_bytes(b.try_into().unwrap()))
+                        .collect(),
+                )
+            }
+            12 => {
+                let len = self.length()?;
+                let values = self.take(len.saturating_mul(8))?;
+                Tag::LongArray(
+                    values
+                        .chunks_exact(8)
+                        .map(|b| i64::from_be_bytes(b.try_into().unwrap()))
+                        .collect(),
+                )
+            }
+            _ => return Err(invalid_data(format!("unknown tag type {}", id))),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Write;
+
+    use flate2::{
+        write::{GzEncoder, ZlibEncoder},
+        Compression,
+    };
+
+    use super::*;
+
+    fn string(value: &str) -> Vec<u8> {
+        let mut bytes = (value.len() as u16).to_be_bytes().to_vec();
+        bytes.extend(value.as_bytes());
+        bytes
+    }
+
+    fn named(id: u8, name: &str, payload: &[u8]) -> Vec<u8> {
+        let mut bytes = vec![id];
+        bytes.extend(string(name));
+        bytes.extend(payload);
+        bytes
+    }
+
+    /// `level` compound holding one of every tag type, with a nested compound
+    /// inside a list.
+    fn sample() -> Vec<u8> {
+        let mut list = vec![10];
+        list.extend(1i32.to_be_bytes());
+        list.extend(named(8, "Name", &string("minecraft:stone")));
+        list.push(0);
+
+        let mut children = Vec::new();
+        children.extend(named(1, "byte", &[0xff]));
+        children.extend(named(2, "short", &(-2i16).to_be_bytes()));
+        children.extend(named(3, "int", &70000i32.to_be_bytes()));
+        children.extend(named(4, "long", &(-5i64).to_be_bytes()));
+        children.extend(named(5, "float", &1.5f32.to_be_bytes()));
+        children.extend(named(6, "double", &0.25f64.to_be_bytes()));
+        children.extend(named(7, "bytes", &[0, 0, 0, 2, 1, 0x80]));
+        children.extend(named(8, "string", &string("hello")));
+        children.extend(named(9, "list", &list));
+        children.extend(named(11, "ints", &[0, 0, 0, 1, 0, 0, 1, 0]));
+        children.extend(named(12, "longs", &[0, 0, 0, 1, 0, 0, 0, 0, 0, 0, 0, 9]));
+        children.push(0);
+        named(10, "level", &children)
+    }
+
+    #[test]
+    fn reads_every_tag_type() {
+        let (name, root) = read(&sample()).unwrap();
+        assert_eq!(name, "level");
+        assert_eq!(root.get("byte"), Some(&Tag::Byte(-1)));
+        assert_eq!(root.get("short").and_then(Tag::as_i64), Some(-2));
+        assert_eq!(root.get("int").and_then(Tag::as_i32), Some(70000));
+        assert_eq!(root.get("long").and_then(Tag::as_i64), Some(-5));
+        assert_eq!(root.get("float"), Some(&Tag::Float(1.5)));
+        assert_eq!(root.get("double"), Some(&Tag::Double(0.25)));
+        assert_eq!(
+            root.get("bytes").and_then(Tag::as_byte_array),
+            Some(&[1, -128][..])
+        );
+        assert_eq!(root.get("string").and_then(Tag::as_str), Some("hello"));
+        let list = root.get("list").and_then(Tag::as_list).unwrap();
+        assert_eq!(
+            list[0].get("Name").and_then(Tag::as_str),
+            Some("minecraft:stone")
+        );
+        assert_eq!(
+            root.get("ints").and_then(Tag::as_int_array),
+            Some(&[256][..])
+        );
+        assert_eq!(
+            root.get("longs").and_then(Tag::as_long_array),
+            Some(&[9][..])
+        );
+    }
+
+    #[test]
+    fn reads_compressed_data() {
+        let mut gzip = GzEncoder::new(Vec::new(), Compression::default());
+        gzip.write_all(&sample()).unwrap();
+        let mut zlib = ZlibEncoder::new(Vec::new(), Compression::default());
+        zlib.write_all(&sample()).unwrap();
+
+        let expected = read(&sample()).unwrap();
+        assert_eq!(read(&gzip.finish().unwrap()).unwrap(), expected);
+        assert_eq!(read(&zlib.finish().unwrap()).unwrap(), expected);
+    }
+
+    #[test]
+    fn reads_structure_fixture() {
+        let path = concat!(env!("CARGO_MANIFEST_DIR"), "/tests/fixtures/small.nbt");
+        let (_, root) = read(&std::fs::read(path).unwrap()).unwrap();
+        assert_eq!(root.get("DataVersion").and_then(Tag::as_i32), Some(3465));
+        assert_eq!(root.get("blocks").and_then(Tag::as_list).unwrap().len(), 6);
+    }
+
+    #[test]
+    fn rejects_malformed_data() {
+        let sample = sample();
+        for len in [0, 1, 5, sample.len() / 2, sample.len() - 1] {
+            let error = read(&sample[..len]).unwrap_err();
+            assert_eq!(error.kind(), io::ErrorKind::InvalidData, "{} bytes", len);
+        }
+
+        let error = read(&named(8, "", &string("not a compound"))).unwrap_err();
+        assert_eq!(error.to_string(), "root tag has type 8");
+
+        let mut negative = named(10, "", &named(7, "bytes", &(-1i32).to_be_bytes()));
+        negative.push(0);
+        assert_eq!(
+            read(&negative).unwrap_err().to_string(),
+            "negative length -1"
+        );
+
+        // Lists of lists nested deeper than the limit.
+        let mut nested = named(10, "", &[]);
+        nested.extend(named(9, "list", &[]));
+        for _ in 0..=MAX_DEPTH {
+            nested.push(9);
+            nested.extend(1i32.to_be_bytes());
+        }
+        assert_eq!(
+            read(&nested).unwrap_err().to_string(),
+            "NBT data is nested too deeply"
+        );
+    }
+}
@@ -0,0 +1,60 @@
+/// Mixes a world seed, a salt and a chunk position into a single seed, so every
+/// generation step gets its own independent stream per chunk.
+pub fn chunk_seed(seed: u32, salt: u32, chunk_x: i32, chunk_z: i32) -> u64 {
+    let mut value = ((seed as u64) << 32) | salt as u64;
+    value = mix(value ^ (chunk_x as u32 as u64).wrapping_mul(0x9E37_79B9_7F4A_7C15));
+    mix(value ^ (chunk_z as u32 as u64).wrapping_mul(0xC2B2_AE3D_27D4_EB4F))
+}
+
+/// Seed of one generation stage in a world, from the world's seed and the
+/// `seed` of the stage's settings. Settings files are shared by every world
+/// created from a preset, so their seeds only tell the stages apart.
+pub fn stage_seed(world_seed: u32, salt: u32) -> u32 {
+    (chunk_seed(world_seed, salt, 0, 0) >> 32) as u32
+}
+
+fn mix(mut value: u64) -> u64 {
+    value = (value ^ (value >> 30)).wrapping_mul(0xBF58_476D_1CE4_E5B9);
+    value = (value ^ (value >> 27)).wrapping_mul(0x94D0_49BB_1331_11EB);
+    value ^ (value >> 31)
+}
+
+/// Small deterministic random generator (SplitMix64). Generation must not depend
+/// on thread scheduling or load order, so every stage seeds its own instance.
+#[derive(Clone, Debug)]
+pub struct ChunkRng {
+    state: u64,
+}
+
+impl ChunkRng {
+    pub fn new(seed: u64) -> Self {
+        Self { state: seed }
+    }
+
+    pub fn next_u64(&mut self) -> u64 {
+        self.state = self.state.wrapping_add(0x9E37_79B9_7F4A_7C15);
+        mix(self.state)
+    }
+
+    /// Uniform value in `0.0..1.0`.
+    pub fn next_f32(&mut self) -> f32 {
+        (self.next_u64() >> 40) as f32 / (1u64 << 24) as f32
+    }
+
+    /// Uniform value in `min..max`.
+    pub fn range_f32(&mut self, min: f32, max: f32) -> f32 {
+        min + (max - min) * self.next_f32()
+    }
+
+    /// Uniform integer in `min..=max`.
+    pub fn range_i32(&mut self, min: i32, max: i32) -> i32 {
+        if max <= min {
+            return min;
+        }
+        min + (self.next_u64() % (max - min + 1) as u64) as i32
+    }
+
+    pub fn chance(&mut self, probability: f32) -> bool {
+        self.next_f32() < probability
+    }
+}
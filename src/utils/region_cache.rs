@@ -0,0 +1,97 @@
+use std::sync::{Arc, Mutex, OnceLock};
+
+use bevy::utils::HashMap;
+
+struct Entry<T> {
+    cell: Arc<OnceLock<Arc<T>>>,
+    last_used: u64,
+}
+
+struct Entries<T> {
+    map: HashMap<(i32, i32), Entry<T>>,
+    clock: u64,
+}
+
+/// Values computed once per region and shared between generation tasks. Tasks
+/// asking for a region that is still being computed wait for it instead of
+/// computing it again.
+pub struct RegionCache<T> {
+    entries: Mutex<Entries<T>>,
+    /// The least recently used region is dropped once more than this are cached.
+    capacity: usize,
+}
+
+impl<T> RegionCache<T> {
+    pub fn new(capacity: usize) -> Self {
+        Self {
+            entries: Mutex::new(Entries {
+                map: HashMap::new(),
+                clock: 0,
+            }),
+            capacity: capacity.max(1),
+        }
+    }
+
+    pub fn get_or_compute(
+        &self,
+        region_x: i32,
+        region_z: i32,
+        compute: impl FnOnce() -> T,
+    ) -> Arc<T> {
+        let cell = {
+            let mut entries = self.entries.lock().unwrap();
+            entries.clock += 1;
+            let clock = entries.clock;
+            let key = (region_x, region_z);
+            if entries.map.len() >= self.capacity && !entries.map.contains_key(&key) {
+                let oldest = entries
+                    .map
+                    .iter()
+                    .min_by_key(|(_, entry)| entry.last_used)
+                    .map(|(key, _)| *key);
+                if let Some(oldest) = oldest {
+                    entries.map.remove(&oldest);
+                }
+            }
+            let entry = entries.map.entry(key).or_insert_with(|| Entry {
+                cell: Arc::default(),
+                last_used: clock,
+            });
+            entry.last_used = clock;
+            entry.cell.clone()
+        };
+        cell.get_or_init(|| Arc::new(compute())).clone()
+    }
+}
+
+/// Capacity that keeps every region overlapping a square `area` blocks wide
+/// cached, plus `margin` regions on every side for lookups into neighbours.
+pub fn regions_covering(area: i32, region_size: i32, margin: i32) -> usize {
+    let region_size = region_size.max(1);
+    // A square that isn't aligned to the regions touches one more of them.
+    let across = (area + region_size - 1) / region_size + 1 + 2 * margin;
+    (across * across) as usize
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn evicts_the_least_recently_used_region() {
+        let cache = RegionCache::new(2);
+        cache.get_or_compute(0, 0, || 0);
+        cache.get_or_compute(1, 0, || 1);
+        cache.get_or_compute(0, 0, || unreachable!());
+        cache.get_or_compute(2, 0, || 2);
+
+        assert_eq!(*cache.get_or_compute(0, 0, || unreachable!()), 0);
+        assert_eq!(*cache.get_or_compute(1, 0, || 10), 10);
+    }
+
+    #[test]
+    fn covers_the_render_area() {
+        assert_eq!(regions_covering(976, 128, 1), 11 * 11);
+        assert_eq!(regions_covering(256, 256, 0), 4);
+    }
+}